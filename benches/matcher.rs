@@ -0,0 +1,88 @@
+use break_check::config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction};
+use break_check::policy::{Pattern, PolicyMatcher};
+use criterion::{Criterion, criterion_group, criterion_main};
+use std::hint::black_box;
+
+const CUSTOMERS: u32 = 5_000;
+
+fn rules() -> Vec<PolicyRule> {
+    let policy = PolicyDefinition {
+        max_tokens: 100,
+        window_secs: 60,
+        ..Default::default()
+    };
+
+    let mut rules: Vec<_> = (0..CUSTOMERS)
+        .map(|customer| PolicyRule {
+            name: format!("customer-{}", customer),
+            pattern: format!("customer:{}:", customer),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy,
+            priority: customer % 10,
+            action: RuleAction::Limit,
+        })
+        .collect();
+
+    rules.extend((0..CUSTOMERS / 10).map(|customer| PolicyRule {
+        name: format!("customer-{}-export", customer * 10),
+        pattern: format!("customer:{}:api:export", customer * 10),
+        pattern_type: PatternType::Exact,
+        bucket: None,
+        policy,
+        priority: 100,
+        action: RuleAction::Limit,
+    }));
+
+    rules
+}
+
+/// Per-request scan the matcher replaced, for comparison.
+fn resolve_linear<'a>(rules: &'a [(Pattern, PolicyRule)], key: &str) -> Option<&'a PolicyRule> {
+    rules
+        .iter()
+        .filter(|(pattern, _)| pattern.matches(key))
+        .max_by_key(|(_, rule)| rule.priority)
+        .map(|(_, rule)| rule)
+}
+
+fn bench_resolve(c: &mut Criterion) {
+    let rules = rules();
+    let default_policy = DefaultPolicy {
+        name: "default".to_string(),
+        policy: PolicyDefinition {
+            max_tokens: 10,
+            window_secs: 60,
+            ..Default::default()
+        },
+    };
+
+    let matcher = PolicyMatcher::new(&default_policy, &rules).unwrap();
+    let linear: Vec<_> = rules
+        .iter()
+        .map(|rule| {
+            let pattern = Pattern::compile(&rule.pattern, rule.pattern_type).unwrap();
+            (pattern, rule.clone())
+        })
+        .collect();
+
+    let keys = [
+        "customer:4200:api:export",
+        "customer:4217:api:list",
+        "anonymous:api:list",
+    ];
+
+    let mut group = c.benchmark_group("resolve");
+    for key in keys {
+        group.bench_function(format!("matcher/{}", key), |b| {
+            b.iter(|| matcher.resolve(black_box(key)).policy.max_tokens)
+        });
+        group.bench_function(format!("linear/{}", key), |b| {
+            b.iter(|| resolve_linear(&linear, black_box(key)).map(|rule| rule.priority))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_resolve);
+criterion_main!(benches);
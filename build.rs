@@ -1,4 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_prost_build::compile_protos("proto/ratelimiter.proto")?;
+    // Messages are serializable so that admin requests can be written to the
+    // audit log as they were received.
+    tonic_prost_build::configure()
+        .message_attribute(".ratelimiter", "#[derive(serde::Serialize)]")
+        .compile_protos(&["proto/ratelimiter.proto"], &["proto"])?;
     Ok(())
 }
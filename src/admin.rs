@@ -0,0 +1,867 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[cfg(feature = "test-support")]
+use crate::common::VirtualClock;
+use crate::common::{from_unix_millis, to_unix_millis};
+use crate::config::{
+    DefaultPolicy, LeaseConfig, PenaltyConfig, PolicyDefinition, PolicyEvents, PolicyRule,
+    RuleAction,
+};
+use crate::db::{
+    AcquireErr, AuditEvent, AuditLog, DecisionFeed, DecisionFilter, KeyOverride, KeyPolicy,
+    Outcome, OverrideStore, OverrideStoreErr, PolicyStore, PolicyStoreErr, RateLimitStore, TopKeys,
+    TopKeysErr, Traffic, reload_policies,
+};
+use crate::policy::Policies;
+use crate::proto::admin_server::Admin;
+use crate::proto::{
+    self, AdvanceClockRequest, AdvanceClockResponse, CreatePolicyRequest, DeleteOverrideRequest,
+    DeleteOverrideResponse, DeletePolicyRequest, Events, ExplainKeyRequest, ExplainKeyResponse,
+    GetOverrideRequest, KeyTraffic, Lease, ListOverridesRequest, ListOverridesResponse,
+    ListPoliciesRequest, ListPoliciesResponse, Penalty, Policy, PolicyChangeResponse,
+    PolicyTraffic, ResetKeyRequest, ResetKeyResponse, SetOverrideRequest, TopKeysRequest,
+    TopKeysResponse, UpdatePolicyRequest, WatchDecisionsRequest, WindowCounters,
+    watch_decisions_request,
+};
+use log::{error, info, warn};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+#[derive(Debug, Clone)]
+pub struct AdminImpl<R: RateLimitStore, P: PolicyStore, O: OverrideStore> {
+    rate_limit: R,
+    policies: Arc<Policies>,
+    policy_store: P,
+    override_store: O,
+    top_keys: Option<TopKeys>,
+    decisions: Option<DecisionFeed>,
+    audit: AuditLog,
+    #[cfg(feature = "test-support")]
+    clock: Option<VirtualClock>,
+}
+
+impl<R: RateLimitStore, P: PolicyStore, O: OverrideStore> AdminImpl<R, P, O> {
+    pub fn new(rate_limit: R, policies: Arc<Policies>, policy_store: P, override_store: O) -> Self {
+        AdminImpl {
+            rate_limit,
+            policies,
+            policy_store,
+            override_store,
+            top_keys: None,
+            decisions: None,
+            audit: AuditLog::disabled(),
+            #[cfg(feature = "test-support")]
+            clock: None,
+        }
+    }
+
+    /// Writes every admin change to `audit`.
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Lets WatchDecisions stream the decisions recorded in `decisions`.
+    pub fn with_decisions(mut self, decisions: DecisionFeed) -> Self {
+        self.decisions = Some(decisions);
+        self
+    }
+
+    /// Lets TopKeys list the heavy hitters counted by `top_keys`.
+    pub fn with_top_keys(mut self, top_keys: TopKeys) -> Self {
+        self.top_keys = Some(top_keys);
+        self
+    }
+
+    /// Lets AdvanceClock move `clock`.
+    #[cfg(feature = "test-support")]
+    pub fn with_clock(mut self, clock: VirtualClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+}
+
+impl From<&PolicyRule> for Policy {
+    fn from(rule: &PolicyRule) -> Self {
+        Policy {
+            name: rule.name.clone(),
+            pattern: rule.pattern.clone(),
+            r#type: rule.pattern_type.to_string(),
+            max_tokens: rule.policy.max_tokens as i32,
+            window_secs: rule.policy.window_secs as i64,
+            priority: rule.priority as i32,
+            bucket: rule.bucket.clone().unwrap_or_default(),
+            source: "config".to_string(),
+            version: 0,
+            action: rule.action.to_string(),
+            penalty: rule.policy.penalty.as_ref().map(Into::into),
+            lease: rule.policy.lease.as_ref().map(Into::into),
+            failure_mode: rule
+                .policy
+                .failure_mode
+                .map(|mode| mode.to_string())
+                .unwrap_or_default(),
+            coalesce_ms: rule.policy.coalesce_ms.unwrap_or_default() as i64,
+            audit: rule
+                .policy
+                .audit
+                .map(|level| level.to_string())
+                .unwrap_or_default(),
+            events: rule.policy.events.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl From<&DefaultPolicy> for Policy {
+    fn from(default_policy: &DefaultPolicy) -> Self {
+        Policy {
+            name: default_policy.name.clone(),
+            max_tokens: default_policy.policy.max_tokens as i32,
+            window_secs: default_policy.policy.window_secs as i64,
+            source: "config".to_string(),
+            action: RuleAction::Limit.to_string(),
+            penalty: default_policy.policy.penalty.as_ref().map(Into::into),
+            lease: default_policy.policy.lease.as_ref().map(Into::into),
+            failure_mode: default_policy
+                .policy
+                .failure_mode
+                .map(|mode| mode.to_string())
+                .unwrap_or_default(),
+            coalesce_ms: default_policy.policy.coalesce_ms.unwrap_or_default() as i64,
+            audit: default_policy
+                .policy
+                .audit
+                .map(|level| level.to_string())
+                .unwrap_or_default(),
+            events: default_policy.policy.events.as_ref().map(Into::into),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&PenaltyConfig> for Penalty {
+    fn from(penalty: &PenaltyConfig) -> Self {
+        Penalty {
+            denials: penalty.denials as i32,
+            within_secs: penalty.within_secs as i64,
+            ban_secs: penalty.ban_secs as i64,
+            multiplier: penalty.multiplier as i32,
+            max_ban_secs: penalty.max_ban_secs as i64,
+        }
+    }
+}
+
+/// Range TopKeys covers when no start is given.
+const DEFAULT_TOP_KEYS_RANGE: Duration = Duration::from_secs(5 * 60);
+
+/// Keys TopKeys lists when no limit is given.
+const DEFAULT_TOP_KEYS_LIMIT: usize = 10;
+
+/// Decisions waiting to be sent to a watcher before it starts missing some.
+const WATCH_STREAM_BUFFER: usize = 64;
+
+fn non_negative(value: i64, field: &str) -> Result<u64, Status> {
+    u64::try_from(value)
+        .map_err(|_| Status::invalid_argument(format!("{} must not be negative", field)))
+}
+
+impl TryFrom<&Penalty> for PenaltyConfig {
+    type Error = Status;
+
+    fn try_from(penalty: &Penalty) -> Result<Self, Self::Error> {
+        Ok(PenaltyConfig {
+            denials: non_negative(penalty.denials as i64, "penalty.denials")? as u32,
+            within_secs: non_negative(penalty.within_secs, "penalty.within_secs")?,
+            ban_secs: non_negative(penalty.ban_secs, "penalty.ban_secs")?,
+            multiplier: match penalty.multiplier {
+                0 => PenaltyConfig::DEFAULT_MULTIPLIER,
+                multiplier => non_negative(multiplier as i64, "penalty.multiplier")? as u32,
+            },
+            max_ban_secs: match penalty.max_ban_secs {
+                0 => PenaltyConfig::DEFAULT_MAX_BAN_SECS,
+                max_ban_secs => non_negative(max_ban_secs, "penalty.max_ban_secs")?,
+            },
+        })
+    }
+}
+
+impl From<&PolicyEvents> for Events {
+    fn from(events: &PolicyEvents) -> Self {
+        Events {
+            denials: events.denials,
+            allow_sample: events.allow_sample,
+        }
+    }
+}
+
+impl From<&Events> for PolicyEvents {
+    fn from(events: &Events) -> Self {
+        PolicyEvents {
+            denials: events.denials,
+            allow_sample: events.allow_sample,
+        }
+    }
+}
+
+impl From<&LeaseConfig> for Lease {
+    fn from(lease: &LeaseConfig) -> Self {
+        Lease {
+            batch: lease.batch as i32,
+            ttl_ms: lease.ttl_ms as i64,
+        }
+    }
+}
+
+impl TryFrom<&Lease> for LeaseConfig {
+    type Error = Status;
+
+    fn try_from(lease: &Lease) -> Result<Self, Self::Error> {
+        Ok(LeaseConfig {
+            batch: non_negative(lease.batch as i64, "lease.batch")? as u32,
+            ttl_ms: non_negative(lease.ttl_ms, "lease.ttl_ms")?,
+        })
+    }
+}
+
+impl TryFrom<&Policy> for PolicyRule {
+    type Error = Status;
+
+    fn try_from(policy: &Policy) -> Result<Self, Self::Error> {
+        let rule = PolicyRule {
+            name: policy.name.clone(),
+            pattern: policy.pattern.clone(),
+            pattern_type: policy.r#type.parse().map_err(Status::invalid_argument)?,
+            bucket: Some(policy.bucket.clone()).filter(|bucket| !bucket.is_empty()),
+            policy: PolicyDefinition {
+                max_tokens: non_negative(policy.max_tokens as i64, "max_tokens")? as u32,
+                window_secs: non_negative(policy.window_secs, "window_secs")?,
+                penalty: policy
+                    .penalty
+                    .as_ref()
+                    .map(PenaltyConfig::try_from)
+                    .transpose()?,
+                lease: policy
+                    .lease
+                    .as_ref()
+                    .map(LeaseConfig::try_from)
+                    .transpose()?,
+                failure_mode: match policy.failure_mode.as_str() {
+                    "" => None,
+                    mode => Some(mode.parse().map_err(Status::invalid_argument)?),
+                },
+                coalesce_ms: match non_negative(policy.coalesce_ms, "coalesce_ms")? {
+                    0 => None,
+                    coalesce_ms => Some(coalesce_ms),
+                },
+                audit: match policy.audit.as_str() {
+                    "" => None,
+                    level => Some(level.parse().map_err(Status::invalid_argument)?),
+                },
+                events: policy.events.as_ref().map(Into::into),
+            },
+            priority: non_negative(policy.priority as i64, "priority")? as u32,
+            action: match policy.action.as_str() {
+                "" => RuleAction::Limit,
+                action => action.parse().map_err(Status::invalid_argument)?,
+            },
+        };
+
+        rule.validate()
+            .map_err(|problems| Status::invalid_argument(problems.join("; ")))?;
+
+        Ok(rule)
+    }
+}
+
+impl From<&KeyOverride> for proto::KeyOverride {
+    fn from(key_override: &KeyOverride) -> Self {
+        proto::KeyOverride {
+            key: key_override.key.clone(),
+            max_tokens: key_override.max_tokens as i32,
+            expires_at: to_unix_millis(key_override.expires_at) as i64,
+        }
+    }
+}
+
+impl TryFrom<&proto::KeyOverride> for KeyOverride {
+    type Error = Status;
+
+    fn try_from(key_override: &proto::KeyOverride) -> Result<Self, Self::Error> {
+        if key_override.key.is_empty() {
+            return Err(Status::invalid_argument("Key must not be empty"));
+        }
+
+        if key_override.max_tokens <= 0 {
+            return Err(Status::invalid_argument(
+                "max_tokens must be greater than zero",
+            ));
+        }
+
+        let expires_at = from_unix_millis(key_override.expires_at.max(0) as u64);
+        if expires_at <= SystemTime::now() {
+            return Err(Status::invalid_argument("expires_at must be in the future"));
+        }
+
+        Ok(KeyOverride {
+            key: key_override.key.clone(),
+            max_tokens: key_override.max_tokens as u32,
+            expires_at,
+        })
+    }
+}
+
+fn store_error(e: AcquireErr) -> Status {
+    match e {
+        AcquireErr::Timeout => Status::deadline_exceeded("Reading counters timed out"),
+        e => {
+            error!("Failed to read counters: {:?}", e);
+            Status::unavailable("Failed to read counters")
+        }
+    }
+}
+
+fn policy_store_error(e: PolicyStoreErr) -> Status {
+    match e {
+        PolicyStoreErr::Conflict {
+            name,
+            current_version: 0,
+        } => Status::not_found(format!("Policy {:?} does not exist", name)),
+        PolicyStoreErr::Conflict {
+            name,
+            current_version,
+        } => Status::aborted(format!(
+            "Policy {:?} was changed concurrently and is now at version {}",
+            name, current_version
+        )),
+        PolicyStoreErr::Timeout => Status::deadline_exceeded("Policy store timed out"),
+        e => {
+            error!("Policy store error: {:?}", e);
+            Status::unavailable("Failed to access policy store")
+        }
+    }
+}
+
+fn key_traffic((key, traffic): (KeyPolicy, Traffic)) -> KeyTraffic {
+    KeyTraffic {
+        key: key.key,
+        policy: key.policy,
+        requests: traffic.requests as i64,
+        denials: traffic.denials as i64,
+    }
+}
+
+fn override_store_error(e: OverrideStoreErr) -> Status {
+    match e {
+        OverrideStoreErr::Timeout => Status::deadline_exceeded("Override store timed out"),
+        e => {
+            error!("Override store error: {:?}", e);
+            Status::unavailable("Failed to access override store")
+        }
+    }
+}
+
+impl<R: RateLimitStore, P: PolicyStore + Send + Clone, O: OverrideStore> AdminImpl<R, P, O> {
+    fn to_proto(&self, rule: &PolicyRule) -> Policy {
+        let mut policy = Policy::from(rule);
+        if let Some(version) = self.policies.dynamic_version(&rule.name) {
+            policy.source = "redis".to_string();
+            policy.version = version as i64;
+        }
+        policy
+    }
+
+    /// Runs an admin change and writes it to the audit log, with the request
+    /// as received and the status it ended with.
+    async fn audited<T: serde::Serialize, U>(
+        &self,
+        method: &str,
+        request: &Request<T>,
+        change: impl Future<Output = Result<Response<U>, Status>>,
+    ) -> Result<Response<U>, Status> {
+        let result = change.await;
+
+        if self.audit.is_enabled() {
+            self.audit
+                .record_admin(AuditEvent::Admin {
+                    method: method.to_string(),
+                    peer: request.remote_addr().map(|addr| addr.to_string()),
+                    request: serde_json::to_value(request.get_ref()).unwrap_or_default(),
+                    status: match &result {
+                        Ok(_) => format!("{:?}", tonic::Code::Ok),
+                        Err(status) => format!("{:?}", status.code()),
+                    },
+                })
+                .await;
+        }
+
+        result
+    }
+
+    fn parse_policy(&self, policy: Option<&Policy>) -> Result<PolicyRule, Status> {
+        let policy = policy.ok_or_else(|| Status::invalid_argument("Policy must be set"))?;
+        let rule = PolicyRule::try_from(policy)?;
+
+        if rule.name == self.policies.matcher().default_policy().name {
+            return Err(Status::invalid_argument(
+                "Name is already used by the default policy",
+            ));
+        }
+
+        Ok(rule)
+    }
+
+    /// Applies a change right away on this instance; other instances pick it
+    /// up from the change notification.
+    async fn reload(&self) {
+        let mut policy_store = self.policy_store.clone();
+        if let Err(e) = reload_policies(&mut policy_store, &self.policies).await {
+            warn!("Failed to reload policies after a change: {}", e);
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<R, P, O> Admin for AdminImpl<R, P, O>
+where
+    R: RateLimitStore + 'static + Send + Sync + Clone,
+    P: PolicyStore + 'static + Send + Sync + Clone,
+    O: OverrideStore + 'static + Send + Sync + Clone,
+{
+    async fn list_policies(
+        &self,
+        _request: Request<ListPoliciesRequest>,
+    ) -> Result<Response<ListPoliciesResponse>, Status> {
+        let matcher = self.policies.matcher();
+
+        Ok(Response::new(ListPoliciesResponse {
+            default_policy: Some(matcher.default_policy().into()),
+            policies: matcher.rules().map(|rule| self.to_proto(rule)).collect(),
+        }))
+    }
+
+    async fn explain_key(
+        &self,
+        request: Request<ExplainKeyRequest>,
+    ) -> Result<Response<ExplainKeyResponse>, Status> {
+        let request = request.get_ref();
+        if request.key.is_empty() {
+            return Err(Status::invalid_argument("Key must not be empty"));
+        }
+
+        let matcher = self.policies.matcher();
+        let explanation = matcher.explain(&request.key);
+        let selected = match explanation.selected {
+            Some(rule) => self.to_proto(rule),
+            None => matcher.default_policy().into(),
+        };
+
+        // Keys decided by allow or deny rules are never counted.
+        let counters = if explanation.resolved.action == RuleAction::Limit {
+            let mut rate_limit = self.rate_limit.clone();
+            let counters = rate_limit
+                .counters(
+                    &explanation.resolved.counter_key,
+                    explanation.resolved.name,
+                    explanation.resolved.policy.window_secs,
+                )
+                .await
+                .map_err(store_error)?;
+            WindowCounters {
+                window_start: to_unix_millis(counters.window_start) as i64,
+                current: counters.current as i32,
+                previous: counters.previous as i32,
+            }
+        } else {
+            WindowCounters::default()
+        };
+
+        let mut override_store = self.override_store.clone();
+        let active_override = override_store
+            .get(&request.key)
+            .await
+            .map_err(override_store_error)?;
+
+        Ok(Response::new(ExplainKeyResponse {
+            selected: Some(selected),
+            candidates: explanation
+                .candidates
+                .into_iter()
+                .map(|rule| self.to_proto(rule))
+                .collect(),
+            reason: explanation.reason,
+            counter_key: explanation.resolved.counter_key.to_string(),
+            counters: Some(counters),
+            active_override: active_override.as_ref().map(Into::into),
+        }))
+    }
+
+    async fn reset_key(
+        &self,
+        request: Request<ResetKeyRequest>,
+    ) -> Result<Response<ResetKeyResponse>, Status> {
+        self.audited("ResetKey", &request, async {
+            let key = &request.get_ref().key;
+            if key.is_empty() {
+                return Err(Status::invalid_argument("Key must not be empty"));
+            }
+
+            let matcher = self.policies.matcher();
+            let resolved = matcher.resolve(key);
+            if resolved.action != RuleAction::Limit {
+                return Err(Status::failed_precondition(format!(
+                    "Key {:?} is decided by {} rule '{}' and has no counters",
+                    key, resolved.action, resolved.name
+                )));
+            }
+
+            let mut rate_limit = self.rate_limit.clone();
+            rate_limit
+                .reset(
+                    &resolved.counter_key,
+                    resolved.name,
+                    resolved.policy.window_secs,
+                )
+                .await
+                .map_err(|e| match e {
+                    AcquireErr::Timeout => {
+                        Status::deadline_exceeded("Resetting counters timed out")
+                    }
+                    e => {
+                        error!("Failed to reset counters: {:?}", e);
+                        Status::unavailable("Failed to reset counters")
+                    }
+                })?;
+
+            info!(
+                "Reset counters of key '{}' under policy '{}'",
+                key, resolved.name
+            );
+            Ok(Response::new(ResetKeyResponse {
+                policy: resolved.name.to_string(),
+                counter_key: resolved.counter_key.to_string(),
+            }))
+        })
+        .await
+    }
+
+    async fn top_keys(
+        &self,
+        request: Request<TopKeysRequest>,
+    ) -> Result<Response<TopKeysResponse>, Status> {
+        let Some(top_keys) = &self.top_keys else {
+            return Err(Status::failed_precondition(
+                "Heavy hitter tracking is not enabled",
+            ));
+        };
+
+        let request = request.get_ref();
+        let to = match non_negative(request.to, "to")? {
+            0 => SystemTime::now(),
+            to => from_unix_millis(to),
+        };
+        let from = match non_negative(request.from, "from")? {
+            0 => to - DEFAULT_TOP_KEYS_RANGE,
+            from => from_unix_millis(from),
+        };
+        if from > to {
+            return Err(Status::invalid_argument("from must not be after to"));
+        }
+        let limit = match non_negative(request.limit as i64, "limit")? {
+            0 => DEFAULT_TOP_KEYS_LIMIT,
+            limit => limit as usize,
+        };
+
+        let mut summary = top_keys.range(from, to).await.map_err(|e| match e {
+            TopKeysErr::Timeout => Status::deadline_exceeded("Top keys history timed out"),
+            e => {
+                error!("Top keys history error: {:?}", e);
+                Status::unavailable("Failed to read top keys history")
+            }
+        })?;
+        if !request.policy.is_empty() {
+            summary.keys.retain(|key, _| key.policy == request.policy);
+            summary
+                .policies
+                .retain(|policy, _| *policy == request.policy);
+        }
+
+        let (by_requests, by_denials) = summary.top(limit);
+        let mut policies: Vec<_> = summary.policies.into_iter().collect();
+        policies.sort_by(|(a_name, a), (b_name, b)| {
+            b.requests.cmp(&a.requests).then_with(|| a_name.cmp(b_name))
+        });
+
+        Ok(Response::new(TopKeysResponse {
+            by_requests: by_requests.into_iter().map(key_traffic).collect(),
+            by_denials: by_denials.into_iter().map(key_traffic).collect(),
+            policies: policies
+                .into_iter()
+                .map(|(policy, traffic)| PolicyTraffic {
+                    policy,
+                    requests: traffic.requests as i64,
+                    denials: traffic.denials as i64,
+                })
+                .collect(),
+        }))
+    }
+
+    type WatchDecisionsStream = ReceiverStream<Result<proto::Decision, Status>>;
+
+    async fn watch_decisions(
+        &self,
+        request: Request<WatchDecisionsRequest>,
+    ) -> Result<Response<Self::WatchDecisionsStream>, Status> {
+        let Some(feed) = &self.decisions else {
+            return Err(Status::failed_precondition(
+                "Watching decisions is not enabled",
+            ));
+        };
+
+        let request = request.get_ref();
+        let filter = DecisionFilter {
+            key_prefix: request.key_prefix.clone(),
+            outcome: match request.outcome() {
+                watch_decisions_request::Outcome::All => Outcome::All,
+                watch_decisions_request::Outcome::Allowed => Outcome::Allowed,
+                watch_decisions_request::Outcome::Denied => Outcome::Denied,
+            },
+        };
+
+        // Each watcher gets its own queue, so a slow one only falls behind
+        // itself: while its queue is full, its decisions are skipped and
+        // counted in `missed`.
+        let mut decisions = feed.subscribe();
+        let (tx, rx) = mpsc::channel(WATCH_STREAM_BUFFER);
+        info!("Watching decisions matching {:?}", filter);
+        tokio::spawn(async move {
+            let mut missed = 0;
+            loop {
+                let decision = tokio::select! {
+                    _ = tx.closed() => break,
+                    decision = decisions.recv() => decision,
+                };
+
+                match decision {
+                    Ok(decision) if filter.matches(&decision) => {
+                        let event = proto::Decision {
+                            key: decision.key.clone(),
+                            policy: decision.policy.clone(),
+                            allowed: decision.allowed,
+                            remaining: decision.remaining as i32,
+                            timestamp: decision.time as i64,
+                            missed,
+                        };
+                        missed = 0;
+                        if tx.send(Ok(event)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => missed += skipped as i64,
+                    Err(RecvError::Closed) => break,
+                }
+            }
+            info!("Stopped watching decisions matching {:?}", filter);
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn create_policy(
+        &self,
+        request: Request<CreatePolicyRequest>,
+    ) -> Result<Response<PolicyChangeResponse>, Status> {
+        self.audited("CreatePolicy", &request, async {
+            let rule = self.parse_policy(request.get_ref().policy.as_ref())?;
+
+            let mut policy_store = self.policy_store.clone();
+            let version = policy_store.put(&rule, 0).await.map_err(|e| match e {
+                PolicyStoreErr::Conflict {
+                    name,
+                    current_version,
+                } => Status::already_exists(format!(
+                    "Policy {:?} already exists at version {}",
+                    name, current_version
+                )),
+                e => policy_store_error(e),
+            })?;
+
+            info!("Created policy '{}' at version {}", rule.name, version);
+            self.reload().await;
+
+            Ok(Response::new(PolicyChangeResponse {
+                version: version as i64,
+            }))
+        })
+        .await
+    }
+
+    async fn update_policy(
+        &self,
+        request: Request<UpdatePolicyRequest>,
+    ) -> Result<Response<PolicyChangeResponse>, Status> {
+        self.audited("UpdatePolicy", &request, async {
+            let request = request.get_ref();
+            let rule = self.parse_policy(request.policy.as_ref())?;
+            if request.expected_version <= 0 {
+                return Err(Status::invalid_argument(
+                    "Expected version must be greater than zero",
+                ));
+            }
+
+            let mut policy_store = self.policy_store.clone();
+            let version = policy_store
+                .put(&rule, request.expected_version as u64)
+                .await
+                .map_err(policy_store_error)?;
+
+            info!("Updated policy '{}' to version {}", rule.name, version);
+            self.reload().await;
+
+            Ok(Response::new(PolicyChangeResponse {
+                version: version as i64,
+            }))
+        })
+        .await
+    }
+
+    async fn delete_policy(
+        &self,
+        request: Request<DeletePolicyRequest>,
+    ) -> Result<Response<PolicyChangeResponse>, Status> {
+        self.audited("DeletePolicy", &request, async {
+            let request = request.get_ref();
+            if request.expected_version <= 0 {
+                return Err(Status::invalid_argument(
+                    "Expected version must be greater than zero",
+                ));
+            }
+
+            let mut policy_store = self.policy_store.clone();
+            policy_store
+                .delete(&request.name, request.expected_version as u64)
+                .await
+                .map_err(policy_store_error)?;
+
+            info!("Deleted policy '{}'", request.name);
+            self.reload().await;
+
+            Ok(Response::new(PolicyChangeResponse { version: 0 }))
+        })
+        .await
+    }
+
+    async fn set_override(
+        &self,
+        request: Request<SetOverrideRequest>,
+    ) -> Result<Response<proto::KeyOverride>, Status> {
+        self.audited("SetOverride", &request, async {
+            let key_override = request
+                .get_ref()
+                .key_override
+                .as_ref()
+                .ok_or_else(|| Status::invalid_argument("Override must be set"))?;
+            let key_override = KeyOverride::try_from(key_override)?;
+
+            let mut override_store = self.override_store.clone();
+            override_store
+                .set(&key_override)
+                .await
+                .map_err(override_store_error)?;
+
+            info!(
+                "Set override for key '{}': max_tokens={} until {:?}",
+                key_override.key, key_override.max_tokens, key_override.expires_at
+            );
+
+            Ok(Response::new((&key_override).into()))
+        })
+        .await
+    }
+
+    async fn get_override(
+        &self,
+        request: Request<GetOverrideRequest>,
+    ) -> Result<Response<proto::KeyOverride>, Status> {
+        let key = &request.get_ref().key;
+
+        let mut override_store = self.override_store.clone();
+        match override_store
+            .get(key)
+            .await
+            .map_err(override_store_error)?
+        {
+            Some(key_override) => Ok(Response::new((&key_override).into())),
+            None => Err(Status::not_found(format!("No override for key {:?}", key))),
+        }
+    }
+
+    async fn list_overrides(
+        &self,
+        _request: Request<ListOverridesRequest>,
+    ) -> Result<Response<ListOverridesResponse>, Status> {
+        let mut override_store = self.override_store.clone();
+        let overrides = override_store.list().await.map_err(override_store_error)?;
+
+        Ok(Response::new(ListOverridesResponse {
+            overrides: overrides.iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn delete_override(
+        &self,
+        request: Request<DeleteOverrideRequest>,
+    ) -> Result<Response<DeleteOverrideResponse>, Status> {
+        self.audited("DeleteOverride", &request, async {
+            let key = &request.get_ref().key;
+
+            let mut override_store = self.override_store.clone();
+            if !override_store
+                .delete(key)
+                .await
+                .map_err(override_store_error)?
+            {
+                return Err(Status::not_found(format!("No override for key {:?}", key)));
+            }
+
+            info!("Deleted override for key '{}'", key);
+            Ok(Response::new(DeleteOverrideResponse {}))
+        })
+        .await
+    }
+
+    #[cfg(feature = "test-support")]
+    async fn advance_clock(
+        &self,
+        request: Request<AdvanceClockRequest>,
+    ) -> Result<Response<AdvanceClockResponse>, Status> {
+        self.audited("AdvanceClock", &request, async {
+            let Some(clock) = &self.clock else {
+                return Err(Status::failed_precondition(
+                    "The server does not run on a virtual clock",
+                ));
+            };
+
+            let millis = non_negative(request.get_ref().millis, "millis")?;
+
+            let now = clock.advance(Duration::from_millis(millis));
+            warn!("Advanced the virtual clock by {}ms to {:?}", millis, now);
+            Ok(Response::new(AdvanceClockResponse {
+                now: to_unix_millis(now) as i64,
+            }))
+        })
+        .await
+    }
+
+    #[cfg(not(feature = "test-support"))]
+    async fn advance_clock(
+        &self,
+        _request: Request<AdvanceClockRequest>,
+    ) -> Result<Response<AdvanceClockResponse>, Status> {
+        Err(Status::unimplemented(
+            "AdvanceClock needs a build with the test-support feature",
+        ))
+    }
+}
@@ -0,0 +1,503 @@
+use std::time::SystemTime;
+
+use break_check::proto::{
+    AcquireRequest, ExplainKeyRequest, ExplainKeyResponse, HealthCheckRequest, KeyTraffic, Policy,
+    ResetKeyRequest, TopKeysRequest, WatchDecisionsRequest, WindowCounters,
+    admin_client::AdminClient, health_check_response::ServingStatus, health_client::HealthClient,
+    rate_limiter_client::RateLimiterClient, watch_decisions_request::Outcome,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use comfy_table::Table;
+use serde_json::{Map, Value, json};
+use tokio_stream::StreamExt;
+
+/// Acquire, inspect and reset the limits of a break-check server.
+#[derive(Debug, Parser)]
+#[command(name = "break-check-cli", version)]
+struct Cli {
+    /// Address of the server
+    #[arg(long, default_value = "http://[::1]:50051")]
+    addr: String,
+
+    /// Address of the server's admin service
+    #[arg(long, default_value = "http://127.0.0.1:50052")]
+    admin_addr: String,
+
+    /// Print JSON instead of tables
+    #[arg(long, global = true)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Acquire tokens for a key, counting them like any other request
+    Acquire {
+        key: String,
+
+        #[arg(long, default_value_t = 1)]
+        tokens: i32,
+    },
+
+    /// Show how many tokens a key has left, without acquiring any
+    Quota { key: String },
+
+    /// Show which policy applies to a key and why
+    Explain { key: String },
+
+    /// Forget the counters and penalties of a key
+    Reset { key: String },
+
+    /// Show the keys with the most requests and denials
+    HotKeys {
+        /// How far back to look
+        #[arg(long, default_value_t = 5)]
+        minutes: i64,
+
+        /// How many keys to show
+        #[arg(long, default_value_t = 10)]
+        limit: i32,
+
+        /// Only show keys of this policy
+        #[arg(long)]
+        policy: Option<String>,
+    },
+
+    /// Print decisions of every instance as they are made, until interrupted
+    Watch {
+        /// Only print decisions for keys starting with this
+        #[arg(long, default_value = "")]
+        prefix: String,
+
+        /// Only print decisions with this outcome
+        #[arg(long, value_enum, default_value_t = OutcomeArg::All)]
+        outcome: OutcomeArg,
+    },
+
+    /// Show whether the server can reach its store
+    Health {
+        /// Keep printing the status until interrupted
+        #[arg(long)]
+        watch: bool,
+    },
+}
+
+#[derive(Debug, Copy, Clone, ValueEnum)]
+enum OutcomeArg {
+    All,
+    Allowed,
+    Denied,
+}
+
+impl From<OutcomeArg> for Outcome {
+    fn from(outcome: OutcomeArg) -> Self {
+        match outcome {
+            OutcomeArg::All => Outcome::All,
+            OutcomeArg::Allowed => Outcome::Allowed,
+            OutcomeArg::Denied => Outcome::Denied,
+        }
+    }
+}
+
+type Record = Vec<(&'static str, Value)>;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Acquire { key, tokens } => {
+            let mut client = RateLimiterClient::connect(cli.addr).await?;
+            let response = client
+                .acquire(AcquireRequest {
+                    key: key.clone(),
+                    tokens,
+                })
+                .await?
+                .into_inner();
+
+            print_record(
+                cli.json,
+                vec![
+                    ("key", json!(key)),
+                    ("allowed", json!(response.allowed)),
+                    ("remaining", json!(response.remaining)),
+                    ("reset_after", json!(response.reset_after)),
+                    ("policy", json!(response.policy)),
+                    ("source", json!(response.source().as_str_name())),
+                    ("degraded", json!(response.degraded)),
+                ],
+            );
+        }
+        Command::Quota { key } => {
+            let explanation = explain(cli.admin_addr, &key).await?;
+            let policy = explanation.selected.unwrap_or_default();
+            let counters = explanation.counters.unwrap_or_default();
+
+            let max_tokens = explanation
+                .active_override
+                .as_ref()
+                .map_or(policy.max_tokens, |key_override| key_override.max_tokens);
+            let remaining = match policy.action.as_str() {
+                "allow" => json!("unlimited"),
+                "deny" => json!(0),
+                _ => json!(estimate_remaining(
+                    max_tokens,
+                    policy.window_secs,
+                    &counters,
+                    unix_now_millis()
+                )),
+            };
+
+            print_record(
+                cli.json,
+                vec![
+                    ("key", json!(key)),
+                    ("policy", json!(policy.name)),
+                    ("max_tokens", json!(max_tokens)),
+                    ("window_secs", json!(policy.window_secs)),
+                    ("current", json!(counters.current)),
+                    ("previous", json!(counters.previous)),
+                    ("remaining", remaining),
+                    (
+                        "reset_after",
+                        json!(counters.window_start + policy.window_secs * 1000),
+                    ),
+                ],
+            );
+        }
+        Command::Explain { key } => {
+            let explanation = explain(cli.admin_addr, &key).await?;
+            let counters = explanation.counters.unwrap_or_default();
+            let key_override = explanation.active_override.as_ref();
+
+            let record = vec![
+                ("key", json!(key)),
+                (
+                    "policy",
+                    json!(explanation.selected.as_ref().map(|policy| &policy.name)),
+                ),
+                ("reason", json!(explanation.reason)),
+                ("counter_key", json!(explanation.counter_key)),
+                ("current", json!(counters.current)),
+                ("previous", json!(counters.previous)),
+                (
+                    "override_max_tokens",
+                    json!(key_override.map(|key_override| key_override.max_tokens)),
+                ),
+                (
+                    "override_expires_at",
+                    json!(key_override.map(|key_override| key_override.expires_at)),
+                ),
+            ];
+            let candidates = explanation.candidates.iter().map(policy_row).collect();
+
+            if cli.json {
+                let mut object = to_object(record);
+                object.insert(
+                    "candidates".to_string(),
+                    Value::Array(rows_to_json(POLICY_COLUMNS, candidates)),
+                );
+                println!("{}", Value::Object(object));
+            } else {
+                print_record(false, record);
+                print_rows(false, POLICY_COLUMNS, candidates);
+            }
+        }
+        Command::Reset { key } => {
+            let mut client = AdminClient::connect(cli.admin_addr).await?;
+            let response = client
+                .reset_key(ResetKeyRequest { key: key.clone() })
+                .await?
+                .into_inner();
+
+            print_record(
+                cli.json,
+                vec![
+                    ("key", json!(key)),
+                    ("policy", json!(response.policy)),
+                    ("counter_key", json!(response.counter_key)),
+                ],
+            );
+        }
+        Command::HotKeys {
+            minutes,
+            limit,
+            policy,
+        } => {
+            let to = unix_now_millis();
+            let mut client = AdminClient::connect(cli.admin_addr).await?;
+            let response = client
+                .top_keys(TopKeysRequest {
+                    from: to - minutes * 60 * 1000,
+                    to,
+                    limit,
+                    policy: policy.unwrap_or_default(),
+                })
+                .await?
+                .into_inner();
+
+            let key_rows = |keys: &[KeyTraffic]| {
+                keys.iter()
+                    .map(|key| {
+                        vec![
+                            json!(key.key),
+                            json!(key.policy),
+                            json!(key.requests),
+                            json!(key.denials),
+                        ]
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let by_requests = key_rows(&response.by_requests);
+            let by_denials = key_rows(&response.by_denials);
+            let policies = response
+                .policies
+                .iter()
+                .map(|policy| {
+                    vec![
+                        json!(policy.policy),
+                        json!(policy.requests),
+                        json!(policy.denials),
+                    ]
+                })
+                .collect();
+
+            if cli.json {
+                println!(
+                    "{}",
+                    json!({
+                        "by_requests": rows_to_json(KEY_TRAFFIC_COLUMNS, by_requests),
+                        "by_denials": rows_to_json(KEY_TRAFFIC_COLUMNS, by_denials),
+                        "policies": rows_to_json(POLICY_TRAFFIC_COLUMNS, policies),
+                    })
+                );
+            } else {
+                println!("Most requests");
+                print_rows(false, KEY_TRAFFIC_COLUMNS, by_requests);
+                println!("Most denials");
+                print_rows(false, KEY_TRAFFIC_COLUMNS, by_denials);
+                println!("Policies");
+                print_rows(false, POLICY_TRAFFIC_COLUMNS, policies);
+            }
+        }
+        Command::Watch { prefix, outcome } => {
+            let mut client = AdminClient::connect(cli.admin_addr).await?;
+            let mut stream = client
+                .watch_decisions(WatchDecisionsRequest {
+                    key_prefix: prefix,
+                    outcome: Outcome::from(outcome).into(),
+                })
+                .await?
+                .into_inner();
+
+            while let Some(decision) = stream.next().await {
+                let decision = decision?;
+                print_line(
+                    cli.json,
+                    vec![
+                        ("time", json!(decision.timestamp)),
+                        ("key", json!(decision.key)),
+                        ("policy", json!(decision.policy)),
+                        ("allowed", json!(decision.allowed)),
+                        ("remaining", json!(decision.remaining)),
+                        ("missed", json!(decision.missed)),
+                    ],
+                );
+            }
+        }
+        Command::Health { watch: false } => {
+            let mut client = HealthClient::connect(cli.addr).await?;
+            let response = client.check(HealthCheckRequest {}).await?.into_inner();
+            print_record(cli.json, health_record(response.status()));
+        }
+        Command::Health { watch: true } => {
+            let mut client = HealthClient::connect(cli.addr).await?;
+            let mut stream = client.watch(HealthCheckRequest {}).await?.into_inner();
+
+            while let Some(response) = stream.next().await {
+                print_line(cli.json, health_record(response?.status()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn explain(
+    addr: String,
+    key: &str,
+) -> Result<ExplainKeyResponse, Box<dyn std::error::Error>> {
+    let mut client = AdminClient::connect(addr).await?;
+    let response = client
+        .explain_key(ExplainKeyRequest {
+            key: key.to_string(),
+        })
+        .await?;
+    Ok(response.into_inner())
+}
+
+/// Estimates the tokens a key has left the way the sliding window decides:
+/// the current window counts fully, the previous one by how much of it still
+/// overlaps the last `window_secs`.
+fn estimate_remaining(
+    max_tokens: i32,
+    window_secs: i64,
+    counters: &WindowCounters,
+    now_millis: i64,
+) -> i64 {
+    let window_millis = (window_secs * 1000).max(1);
+    let elapsed = (now_millis - counters.window_start).clamp(0, window_millis);
+    let previous_weight = (window_millis - elapsed) as f64 / window_millis as f64;
+
+    let used =
+        counters.current as i64 + (counters.previous as f64 * previous_weight).round() as i64;
+    (max_tokens as i64 - used).max(0)
+}
+
+const POLICY_COLUMNS: &[&str] = &[
+    "name",
+    "type",
+    "pattern",
+    "priority",
+    "action",
+    "max_tokens",
+    "window_secs",
+];
+
+const KEY_TRAFFIC_COLUMNS: &[&str] = &["key", "policy", "requests", "denials"];
+
+const POLICY_TRAFFIC_COLUMNS: &[&str] = &["policy", "requests", "denials"];
+
+fn policy_row(policy: &Policy) -> Vec<Value> {
+    vec![
+        json!(policy.name),
+        json!(policy.r#type),
+        json!(policy.pattern),
+        json!(policy.priority),
+        json!(policy.action),
+        json!(policy.max_tokens),
+        json!(policy.window_secs),
+    ]
+}
+
+fn health_record(status: ServingStatus) -> Record {
+    vec![
+        ("time", json!(unix_now_millis())),
+        ("status", json!(status.as_str_name())),
+    ]
+}
+
+fn unix_now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn display(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        value => value.to_string(),
+    }
+}
+
+fn to_object(record: Record) -> Map<String, Value> {
+    record
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect()
+}
+
+fn rows_to_json(columns: &[&str], rows: Vec<Vec<Value>>) -> Vec<Value> {
+    rows.into_iter()
+        .map(|row| {
+            Value::Object(
+                columns
+                    .iter()
+                    .map(|column| column.to_string())
+                    .zip(row)
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+/// Prints `record` as a JSON object, or as a table of its fields in order.
+fn print_record(json: bool, record: Record) {
+    if json {
+        println!("{}", Value::Object(to_object(record)));
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_header(vec!["field", "value"]);
+    for (name, value) in &record {
+        table.add_row(vec![name.to_string(), display(value)]);
+    }
+    println!("{table}");
+}
+
+/// Prints `record` as a JSON object or as `name=value` pairs, on one line.
+/// Streams use it instead of tables, which would repeat the header for
+/// every update.
+fn print_line(json: bool, record: Record) {
+    if json {
+        println!("{}", Value::Object(to_object(record)));
+        return;
+    }
+
+    let fields: Vec<_> = record
+        .iter()
+        .map(|(name, value)| format!("{}={}", name, display(value)))
+        .collect();
+    println!("{}", fields.join(" "));
+}
+
+/// Prints `rows` as a JSON array of objects, or as a table.
+fn print_rows(json: bool, columns: &[&str], rows: Vec<Vec<Value>>) {
+    if json {
+        println!("{}", Value::Array(rows_to_json(columns, rows)));
+        return;
+    }
+
+    let mut table = Table::new();
+    table.set_header(columns.to_vec());
+    for row in &rows {
+        table.add_row(row.iter().map(display).collect::<Vec<_>>());
+    }
+    println!("{table}");
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(0, 10, 0, 10)] // Start of the window, the previous one counts fully
+    #[case(30_000, 10, 0, 15)] // Half of the previous window still counts
+    #[case(60_000, 10, 0, 20)] // The previous window no longer counts
+    #[case(30_000, 10, 30, 0)] // Never below zero
+    fn test_estimate_remaining(
+        #[case] elapsed: i64,
+        #[case] previous: i32,
+        #[case] current: i32,
+        #[case] expected: i64,
+    ) {
+        let counters = WindowCounters {
+            window_start: 1_000_000,
+            current,
+            previous,
+        };
+
+        assert_eq!(
+            estimate_remaining(20, 60, &counters, 1_000_000 + elapsed),
+            expected
+        );
+    }
+}
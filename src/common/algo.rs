@@ -15,6 +15,7 @@ pub struct AcquireAttempt {
     pub(self) window_duration: Duration,
     pub(self) previous_window_requests: u32,
     pub(self) current_window_requests: u32,
+    pub(self) now: Option<SystemTime>,
 }
 
 impl AcquireAttempt {
@@ -31,8 +32,16 @@ impl AcquireAttempt {
             window_duration,
             previous_window_requests,
             current_window_requests,
+            now: None,
         }
     }
+
+    /// Decides the attempt as of `now` instead of the algorithm's clock, so
+    /// that the decision uses the same time the counters were read at.
+    pub fn at(mut self, now: SystemTime) -> Self {
+        self.now = Some(now);
+        self
+    }
 }
 
 pub trait RateLimitAlgorithm {
@@ -47,6 +56,12 @@ pub struct SlidingWindow<C: Clock> {
     clock: C,
 }
 
+impl Default for SlidingWindow<SystemClock> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SlidingWindow<SystemClock> {
     pub fn new() -> Self {
         Self::with_clock(SystemClock)
@@ -64,7 +79,7 @@ impl<C: Clock> RateLimitAlgorithm for SlidingWindow<C> {
     ) -> Result<(u32, SystemTime), RateLimitAlgorithmErr> {
         let window_ms = attempt.window_duration.as_millis();
 
-        let now = self.clock.now();
+        let now = attempt.now.unwrap_or_else(|| self.clock.now());
         let unix_now = to_unix_millis(now);
 
         let time_in_current_window = unix_now % window_ms;
@@ -135,6 +150,7 @@ mod tests {
             window_duration: Duration::from_secs(window_secs),
             previous_window_requests: previous_requests,
             current_window_requests: current_requests,
+            now: None,
         };
 
         let (remaining, reset_after) = algorithm.try_acquire(&attempt).unwrap();
@@ -171,6 +187,7 @@ mod tests {
             window_duration: Duration::from_secs(window_secs),
             previous_window_requests: previous_requests,
             current_window_requests: current_requests,
+            now: None,
         };
 
         let result = algorithm.try_acquire(&attempt);
@@ -181,6 +198,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_attempt_time_replaces_clock() {
+        let mut clock = MockClock::new();
+        clock.expect_now().never();
+        let algorithm = SlidingWindow::with_clock(clock);
+
+        let now = from_unix_millis(1761948330000); // 2025-11-01 12:05:30 UTC
+        let attempt = AcquireAttempt::new(1, 10, Duration::from_secs(60), 0, 0).at(now);
+
+        let (remaining, reset_after) = algorithm.try_acquire(&attempt).unwrap();
+        assert_eq!(remaining, 9);
+        assert_eq!(reset_after, from_unix_millis(1761948360000));
+    }
+
     proptest! {
         #[test]
         fn test_sliding_window_proptest(
@@ -201,6 +232,7 @@ mod tests {
                 window_duration: Duration::from_secs(window_secs),
                 previous_window_requests: previous_requests,
                 current_window_requests: current_requests,
+                now: None,
             };
 
             let algorithm = SlidingWindow::with_clock(clock);
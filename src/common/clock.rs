@@ -14,13 +14,67 @@ impl Clock for SystemClock {
     }
 }
 
+/// Clock that stands still until it is moved, so that window rollover and
+/// resets can be tested without sleeping. Clones share the same time.
+#[cfg(any(test, feature = "test-support"))]
+#[derive(Debug, Clone)]
+pub struct VirtualClock {
+    now_millis: std::sync::Arc<std::sync::atomic::AtomicU64>,
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl VirtualClock {
+    pub fn new(start: SystemTime) -> Self {
+        VirtualClock {
+            now_millis: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(
+                to_unix_millis(start) as u64,
+            )),
+        }
+    }
+
+    /// Moves the clock forward by `by` and returns the new time.
+    pub fn advance(&self, by: std::time::Duration) -> SystemTime {
+        let millis = by.as_millis() as u64;
+        let previous = self
+            .now_millis
+            .fetch_add(millis, std::sync::atomic::Ordering::SeqCst);
+        from_unix_millis(previous + millis)
+    }
+}
+
+#[cfg(any(test, feature = "test-support"))]
+impl Clock for VirtualClock {
+    fn now(&self) -> SystemTime {
+        from_unix_millis(self.now_millis.load(std::sync::atomic::Ordering::SeqCst))
+    }
+}
+
 pub fn to_unix_millis(time: SystemTime) -> u128 {
     time.duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_millis()
 }
 
-#[cfg(test)]
 pub fn from_unix_millis(millis: u64) -> SystemTime {
     SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(millis)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new(from_unix_millis(1_000));
+        let shared = clock.clone();
+
+        assert_eq!(clock.now(), from_unix_millis(1_000));
+        assert_eq!(
+            shared.advance(Duration::from_secs(60)),
+            from_unix_millis(61_000)
+        );
+        assert_eq!(clock.now(), from_unix_millis(61_000));
+    }
+}
@@ -1,54 +1,599 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use std::{fmt, path::PathBuf, str::FromStr};
 use thiserror::Error;
 
+use crate::config::ConfigIssue;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
-    pub default_policy: PolicyDefinition,
+    pub default_policy: DefaultPolicy,
     pub policies: Vec<PolicyRule>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerConfig {
     pub address: String,
+
+    /// Address of the admin service, which has no authentication of its own.
+    /// Defaults to loopback, so that only local callers can reach it.
+    #[serde(default = "default_admin_address")]
+    pub admin_address: String,
+
+    /// Where counters are kept. Defaults to Redis.
+    #[serde(default)]
+    pub store: StoreKind,
+
+    #[serde(default = "default_redis_url")]
     pub redis_url: String,
 
+    /// Whether `redis_url` is a node of a Redis Cluster, from which the rest
+    /// of the cluster is discovered.
+    #[serde(default)]
+    pub redis_cluster: bool,
+
+    /// Clock deciding which window is current.
+    #[serde(default)]
+    pub time_source: TimeSource,
+
+    /// Start the Redis store on a virtual clock that only moves through the
+    /// AdvanceClock admin endpoint. Needs the test-support feature.
+    #[serde(default)]
+    pub virtual_clock: bool,
+
+    /// Prefix of every Redis key, so that several deployments can share one
+    /// Redis.
+    #[serde(default = "default_key_namespace")]
+    pub key_namespace: String,
+
+    /// Finds the Redis primary through Sentinel instead of `redis_url`.
+    #[serde(default)]
+    pub sentinel: Option<SentinelConfig>,
+
+    /// Credentials for Redis, replacing any in `redis_url`.
+    #[serde(default)]
+    pub redis_auth: Option<RedisAuthConfig>,
+
+    /// Certificates for `rediss://` connections.
+    #[serde(default)]
+    pub redis_tls: Option<RedisTlsConfig>,
+
     #[serde(default = "default_redis_timeout_ms")]
     pub redis_timeout_ms: u64,
+
+    /// How often policy changes stored in Redis are polled for, in case a
+    /// change notification is missed.
+    #[serde(default = "default_policy_poll_secs")]
+    pub policy_poll_secs: u64,
+
+    /// What to do with requests while Redis is unreachable, for policies
+    /// without their own `failure_mode`.
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+
+    /// Number of instances sharing the limits. In `local` failure mode each
+    /// instance allows its share of a policy's limit.
+    #[serde(default = "default_fallback_instances")]
+    pub fallback_instances: u32,
+
+    /// Tracks the keys with the most traffic and denials when set.
+    #[serde(default)]
+    pub top_keys: Option<TopKeysConfig>,
+
+    /// Writes audited decisions and admin changes to files when set.
+    #[serde(default)]
+    pub audit: Option<AuditConfig>,
+
+    /// Publishes decision events to a Redis Stream when set.
+    #[serde(default)]
+    pub events: Option<EventsConfig>,
+}
+
+/// Decision events published to a Redis Stream with `XADD`, trimmed to about
+/// `max_len` entries. Events are queued and written in pipelined batches of
+/// up to `batch_size`; at most `queue_size` events wait, further ones are
+/// dropped. Policies without `events` publish according to `defaults`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EventsConfig {
+    /// Stream key. Defaults to `<key_namespace>:events`.
+    #[serde(default)]
+    pub stream: Option<String>,
+
+    #[serde(default = "default_events_max_len")]
+    pub max_len: u64,
+
+    #[serde(default = "default_events_batch_size")]
+    pub batch_size: usize,
+
+    #[serde(default = "default_events_queue_size")]
+    pub queue_size: usize,
+
+    #[serde(flatten)]
+    pub defaults: PolicyEvents,
+}
+
+impl EventsConfig {
+    pub const DEFAULT_MAX_LEN: u64 = 100_000;
+    pub const DEFAULT_BATCH_SIZE: usize = 500;
+    pub const DEFAULT_QUEUE_SIZE: usize = 10_000;
+}
+
+impl Default for PolicyEvents {
+    fn default() -> Self {
+        PolicyEvents {
+            denials: true,
+            allow_sample: 0.0,
+        }
+    }
+}
+
+/// Audit log. Records are appended as JSON lines to `path`, which is rotated
+/// to `path.1`, `path.2` and so on once it reaches `max_file_bytes`, keeping
+/// `max_files` rotated files. At most `queue_size` records wait to be
+/// written; further decisions are dropped and counted in the log, while admin
+/// changes wait for room. Admin reads are not audited.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+
+    #[serde(default = "default_audit_max_file_bytes")]
+    pub max_file_bytes: u64,
+
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: u32,
+
+    #[serde(default = "default_audit_queue_size")]
+    pub queue_size: usize,
+}
+
+impl AuditConfig {
+    pub const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+    pub const DEFAULT_MAX_FILES: u32 = 10;
+    pub const DEFAULT_QUEUE_SIZE: usize = 10_000;
+}
+
+/// Heavy hitter tracking. Each instance counts the requests and denials of at
+/// most about `capacity` keys between flushes, and adds them to shared
+/// buckets of `bucket_secs` every `flush_secs`. Buckets are kept for
+/// `retention_secs`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TopKeysConfig {
+    #[serde(default = "default_top_keys_capacity")]
+    pub capacity: usize,
+
+    #[serde(default = "default_top_keys_bucket_secs")]
+    pub bucket_secs: u64,
+
+    #[serde(default = "default_top_keys_retention_secs")]
+    pub retention_secs: u64,
+
+    #[serde(default = "default_top_keys_flush_secs")]
+    pub flush_secs: u64,
 }
 
-#[derive(Debug, Copy, Deserialize, Clone)]
+impl TopKeysConfig {
+    pub const DEFAULT_CAPACITY: usize = 1000;
+    pub const DEFAULT_BUCKET_SECS: u64 = 60;
+    pub const DEFAULT_RETENTION_SECS: u64 = 60 * 60;
+    pub const DEFAULT_FLUSH_SECS: u64 = 10;
+}
+
+impl Default for TopKeysConfig {
+    fn default() -> Self {
+        TopKeysConfig {
+            capacity: Self::DEFAULT_CAPACITY,
+            bucket_secs: Self::DEFAULT_BUCKET_SECS,
+            retention_secs: Self::DEFAULT_RETENTION_SECS,
+            flush_secs: Self::DEFAULT_FLUSH_SECS,
+        }
+    }
+}
+
+/// Where the Redis store takes the current time from.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeSource {
+    /// The clock of each instance.
+    #[default]
+    Local,
+
+    /// The clock of the Redis server, read inside the script, so that clock
+    /// skew between instances does not affect decisions.
+    Redis,
+}
+
+/// Redis primary/replica set monitored by Sentinel.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SentinelConfig {
+    /// Sentinel URLs, such as "redis://10.0.0.1:26379". Credentials for the
+    /// sentinels themselves go in the URL.
+    pub addresses: Vec<String>,
+
+    /// Name the sentinels monitor the primary under.
+    pub master_name: String,
+}
+
+/// Redis credentials. The password is read at startup from a file, such as a
+/// mounted secret, or from an environment variable, so that it never has to be
+/// written into the config.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RedisAuthConfig {
+    #[serde(default)]
+    pub username: Option<String>,
+
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub password_env: Option<String>,
+}
+
+/// TLS settings for Redis. Without `ca_file` the system trust store is used,
+/// and `cert_file` and `key_file` enable client certificates.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RedisTlsConfig {
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub cert_file: Option<PathBuf>,
+
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+
+    /// Accepts any server certificate. For development only.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    /// Shared by every instance connected to the same Redis.
+    #[default]
+    Redis,
+
+    /// Local to this process; for single-node deployments and development.
+    Memory,
+}
+
+/// How requests are decided while the store is unreachable.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureMode {
+    /// Fail the request, leaving the decision to the caller.
+    #[default]
+    Unavailable,
+
+    /// Allow every request.
+    Open,
+
+    /// Deny every request.
+    Closed,
+
+    /// Apply the policy to the requests seen by this instance only.
+    Local,
+}
+
+impl fmt::Display for FailureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            FailureMode::Unavailable => "unavailable",
+            FailureMode::Open => "open",
+            FailureMode::Closed => "closed",
+            FailureMode::Local => "local",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for FailureMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unavailable" => Ok(FailureMode::Unavailable),
+            "open" => Ok(FailureMode::Open),
+            "closed" => Ok(FailureMode::Closed),
+            "local" => Ok(FailureMode::Local),
+            _ => Err(format!("unknown failure mode {:?}", s)),
+        }
+    }
+}
+
+/// Limit of a policy. Allow and deny rules have no limit and leave it at zero.
+#[derive(Debug, Copy, Deserialize, Serialize, Clone, Default)]
 pub struct PolicyDefinition {
+    #[serde(default)]
     pub max_tokens: u32,
+
+    #[serde(default)]
     pub window_secs: u64,
+
+    #[serde(default)]
+    pub penalty: Option<PenaltyConfig>,
+
+    #[serde(default)]
+    pub lease: Option<LeaseConfig>,
+
+    /// Overrides `server.failure_mode` for this policy.
+    #[serde(default)]
+    pub failure_mode: Option<FailureMode>,
+
+    /// Merges concurrent acquires for one key that arrive within this many
+    /// milliseconds into a single request to the store.
+    #[serde(default)]
+    pub coalesce_ms: Option<u64>,
+
+    /// Which decisions of this policy are written to the audit log.
+    #[serde(default)]
+    pub audit: Option<AuditLevel>,
+
+    /// Which decisions of this policy are published to the event stream,
+    /// instead of `server.events`' defaults.
+    #[serde(default)]
+    pub events: Option<PolicyEvents>,
+}
+
+/// Decisions of a policy published to the event stream.
+#[derive(Debug, Copy, Deserialize, Serialize, Clone, PartialEq)]
+pub struct PolicyEvents {
+    /// Whether every denial is published.
+    #[serde(default = "default_event_denials")]
+    pub denials: bool,
+
+    /// Share of allowed requests published, from 0 to 1.
+    #[serde(default)]
+    pub allow_sample: f64,
+}
+
+/// Decisions of a policy written to the audit log.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditLevel {
+    /// Denied requests only.
+    Denials,
+
+    /// Every request.
+    All,
+}
+
+impl fmt::Display for AuditLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AuditLevel::Denials => "denials",
+            AuditLevel::All => "all",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for AuditLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "denials" => Ok(AuditLevel::Denials),
+            "all" => Ok(AuditLevel::All),
+            _ => Err(format!("unknown audit level {:?}", s)),
+        }
+    }
+}
+
+/// Local token leasing for hot keys. Each instance takes `batch` tokens for a
+/// key at once and hands them out locally for at most `ttl_ms`, after which
+/// unused tokens are given back. Limits may be exceeded by up to one batch per
+/// instance within a window.
+#[derive(Debug, Copy, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct LeaseConfig {
+    pub batch: u32,
+    pub ttl_ms: u64,
+}
+
+/// Temporary ban for clients that keep sending requests after being denied.
+/// `denials` denials within `within_secs` ban the counter key for `ban_secs`.
+/// Each further ban lasts `multiplier` times longer, up to `max_ban_secs`, and
+/// the escalation is forgotten once `max_ban_secs` pass without a ban.
+#[derive(Debug, Copy, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct PenaltyConfig {
+    pub denials: u32,
+    pub within_secs: u64,
+    pub ban_secs: u64,
+
+    #[serde(default = "default_penalty_multiplier")]
+    pub multiplier: u32,
+
+    #[serde(default = "default_max_ban_secs")]
+    pub max_ban_secs: u64,
+}
+
+impl PenaltyConfig {
+    pub const DEFAULT_MULTIPLIER: u32 = 2;
+    pub const DEFAULT_MAX_BAN_SECS: u64 = 24 * 60 * 60;
 }
 
 #[derive(Debug, Clone, Deserialize)]
+pub struct DefaultPolicy {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub policy: PolicyDefinition,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PolicyRule {
+    pub name: String,
+
     pub pattern: String,
 
     #[serde(rename = "type")]
     pub pattern_type: PatternType,
 
+    /// Counter key template filled from the pattern's `{name}` captures.
+    /// Every key matching the rule with the same captures shares one counter.
+    #[serde(default)]
+    pub bucket: Option<String>,
+
     #[serde(flatten)]
     pub policy: PolicyDefinition,
 
     #[serde(default = "default_priority")]
     pub priority: u32,
+
+    #[serde(default)]
+    pub action: RuleAction,
 }
 
-#[derive(Debug, Deserialize, Clone, Copy)]
+/// What happens to keys matching a rule.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Count the key against the rule's limit.
+    #[default]
+    Limit,
+
+    /// Always allow the key without counting it.
+    Allow,
+
+    /// Always deny the key.
+    Deny,
+}
+
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RuleAction::Limit => "limit",
+            RuleAction::Allow => "allow",
+            RuleAction::Deny => "deny",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for RuleAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "limit" => Ok(RuleAction::Limit),
+            "allow" => Ok(RuleAction::Allow),
+            "deny" => Ok(RuleAction::Deny),
+            _ => Err(format!("unknown action {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum PatternType {
     Exact,
     Prefix,
+    Glob,
+    Regex,
+}
+
+impl fmt::Display for PatternType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PatternType::Exact => "exact",
+            PatternType::Prefix => "prefix",
+            PatternType::Glob => "glob",
+            PatternType::Regex => "regex",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for PatternType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(PatternType::Exact),
+            "prefix" => Ok(PatternType::Prefix),
+            "glob" => Ok(PatternType::Glob),
+            "regex" => Ok(PatternType::Regex),
+            _ => Err(format!("unknown pattern type {:?}", s)),
+        }
+    }
+}
+
+fn default_admin_address() -> String {
+    "127.0.0.1:50052".to_string()
+}
+
+fn default_redis_url() -> String {
+    "redis://127.0.0.1/".to_string()
+}
+
+fn default_key_namespace() -> String {
+    "break_check".to_string()
 }
 
 fn default_redis_timeout_ms() -> u64 {
     100
 }
 
+fn default_policy_poll_secs() -> u64 {
+    30
+}
+
+fn default_fallback_instances() -> u32 {
+    1
+}
+
+fn default_top_keys_capacity() -> usize {
+    TopKeysConfig::DEFAULT_CAPACITY
+}
+
+fn default_top_keys_bucket_secs() -> u64 {
+    TopKeysConfig::DEFAULT_BUCKET_SECS
+}
+
+fn default_top_keys_retention_secs() -> u64 {
+    TopKeysConfig::DEFAULT_RETENTION_SECS
+}
+
+fn default_top_keys_flush_secs() -> u64 {
+    TopKeysConfig::DEFAULT_FLUSH_SECS
+}
+
+fn default_events_max_len() -> u64 {
+    EventsConfig::DEFAULT_MAX_LEN
+}
+
+fn default_events_batch_size() -> usize {
+    EventsConfig::DEFAULT_BATCH_SIZE
+}
+
+fn default_events_queue_size() -> usize {
+    EventsConfig::DEFAULT_QUEUE_SIZE
+}
+
+fn default_event_denials() -> bool {
+    true
+}
+
+fn default_audit_max_file_bytes() -> u64 {
+    AuditConfig::DEFAULT_MAX_FILE_BYTES
+}
+
+fn default_audit_max_files() -> u32 {
+    AuditConfig::DEFAULT_MAX_FILES
+}
+
+fn default_audit_queue_size() -> usize {
+    AuditConfig::DEFAULT_QUEUE_SIZE
+}
+
+fn default_penalty_multiplier() -> u32 {
+    PenaltyConfig::DEFAULT_MULTIPLIER
+}
+
+fn default_max_ban_secs() -> u64 {
+    PenaltyConfig::DEFAULT_MAX_BAN_SECS
+}
+
 fn default_priority() -> u32 {
     0
 }
@@ -60,10 +605,22 @@ pub enum ConfigErr {
 
     #[error("Parse error: {0}")]
     Parse(toml::de::Error),
+
+    #[error("Invalid config:\n{}", format_issues(.0))]
+    Invalid(Vec<ConfigIssue>),
+}
+
+fn format_issues(issues: &[ConfigIssue]) -> String {
+    issues
+        .iter()
+        .map(|issue| format!("  - {}", issue))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
 pub fn load_config(path: impl Into<PathBuf>) -> Result<Config, ConfigErr> {
     let content = std::fs::read_to_string(path.into()).map_err(ConfigErr::Io)?;
     let config: Config = toml::from_str(&content).map_err(ConfigErr::Parse)?;
+    config.validate().map_err(ConfigErr::Invalid)?;
     Ok(config)
 }
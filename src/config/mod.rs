@@ -1,3 +1,6 @@
+#[allow(clippy::module_inception)]
 mod config;
+mod validate;
 
-pub use config::*;
\ No newline at end of file
+pub use config::*;
+pub use validate::*;
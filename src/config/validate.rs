@@ -0,0 +1,955 @@
+use std::{fmt, net::SocketAddr};
+
+use redis::IntoConnectionInfo;
+
+use crate::{
+    config::{Config, PolicyDefinition, PolicyRule, RuleAction, StoreKind},
+    policy::CompiledRule,
+};
+
+/// Longest a request may wait for others to coalesce with.
+const MAX_COALESCE_MS: u64 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigIssue {
+    pub location: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(location: impl Into<String>, message: impl Into<String>) -> Self {
+        ConfigIssue {
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+fn rule_location(index: usize, rule: &PolicyRule) -> String {
+    format!("policies[{}] (pattern {:?})", index, rule.pattern)
+}
+
+fn validate_policy(policy: &PolicyDefinition, problems: &mut Vec<String>) {
+    if policy.window_secs == 0 {
+        problems.push("window_secs must be greater than zero".to_string());
+    }
+
+    if policy.max_tokens == 0 {
+        problems.push("max_tokens must be greater than zero".to_string());
+    }
+
+    if let Some(penalty) = &policy.penalty {
+        if penalty.denials == 0 {
+            problems.push("penalty.denials must be greater than zero".to_string());
+        }
+
+        if penalty.within_secs == 0 {
+            problems.push("penalty.within_secs must be greater than zero".to_string());
+        }
+
+        if penalty.ban_secs == 0 {
+            problems.push("penalty.ban_secs must be greater than zero".to_string());
+        }
+
+        if penalty.multiplier == 0 {
+            problems.push("penalty.multiplier must be greater than zero".to_string());
+        }
+
+        if penalty.max_ban_secs < penalty.ban_secs {
+            problems
+                .push("penalty.max_ban_secs must not be less than penalty.ban_secs".to_string());
+        }
+    }
+
+    if let Some(lease) = &policy.lease {
+        if lease.batch == 0 || lease.batch > policy.max_tokens {
+            problems.push("lease.batch must be between 1 and max_tokens".to_string());
+        }
+
+        if lease.ttl_ms == 0 {
+            problems.push("lease.ttl_ms must be greater than zero".to_string());
+        }
+
+        // A denied batch would count as a denial of the key, even when the
+        // request alone fits.
+        if policy.penalty.is_some() {
+            problems.push("lease cannot be combined with penalty".to_string());
+        }
+    }
+
+    if policy
+        .coalesce_ms
+        .is_some_and(|coalesce_ms| coalesce_ms == 0 || coalesce_ms > MAX_COALESCE_MS)
+    {
+        problems.push(format!(
+            "coalesce_ms must be between 1 and {}",
+            MAX_COALESCE_MS
+        ));
+    }
+
+    // Like a denied lease batch, a denied merged increment would count as a
+    // denial of the key on top of those of its requests.
+    if policy.coalesce_ms.is_some() && policy.penalty.is_some() {
+        problems.push("coalesce_ms cannot be combined with penalty".to_string());
+    }
+
+    validate_events(policy, problems);
+}
+
+fn validate_events(policy: &PolicyDefinition, problems: &mut Vec<String>) {
+    if policy
+        .events
+        .is_some_and(|events| !(0.0..=1.0).contains(&events.allow_sample))
+    {
+        problems.push("events.allow_sample must be between 0 and 1".to_string());
+    }
+}
+
+fn validate_name(name: &str, problems: &mut Vec<String>) {
+    if name.trim().is_empty() {
+        problems.push("name must not be empty".to_string());
+    }
+}
+
+impl PolicyRule {
+    /// Checks a single rule on its own. Conflicts with other rules, such as
+    /// duplicate names, are checked by [`Config::validate`].
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+
+        match self.action {
+            RuleAction::Limit => validate_policy(&self.policy, &mut problems),
+            action if self.bucket.is_some() => {
+                problems.push(format!("{} rules cannot have a bucket", action));
+            }
+            _ => {}
+        }
+        // Allow and deny rules publish events too.
+        if self.action != RuleAction::Limit {
+            validate_events(&self.policy, &mut problems);
+        }
+        validate_name(&self.name, &mut problems);
+
+        if let Err(e) = CompiledRule::compile(self) {
+            problems.push(format!("invalid {} pattern: {}", self.pattern_type, e));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+}
+
+impl Config {
+    /// Checks the config for values that would otherwise fail at request time.
+    /// Every problem found is reported, not just the first one.
+    pub fn validate(&self) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = Vec::new();
+
+        if let Err(e) = self.server.address.parse::<SocketAddr>() {
+            issues.push(ConfigIssue::new(
+                "server.address",
+                format!("invalid socket address {:?}: {}", self.server.address, e),
+            ));
+        }
+
+        match self.server.admin_address.parse::<SocketAddr>() {
+            Err(e) => issues.push(ConfigIssue::new(
+                "server.admin_address",
+                format!(
+                    "invalid socket address {:?}: {}",
+                    self.server.admin_address, e
+                ),
+            )),
+            Ok(admin_address) if self.server.address.parse() == Ok(admin_address) => {
+                issues.push(ConfigIssue::new(
+                    "server.admin_address",
+                    "admin_address must differ from address",
+                ))
+            }
+            Ok(_) => {}
+        }
+
+        if let Err(e) = self.server.redis_url.as_str().into_connection_info() {
+            issues.push(ConfigIssue::new(
+                "server.redis_url",
+                format!("invalid Redis URL {:?}: {}", self.server.redis_url, e),
+            ));
+        }
+
+        if self.server.key_namespace.is_empty() || self.server.key_namespace.contains(['{', '}']) {
+            issues.push(ConfigIssue::new(
+                "server.key_namespace",
+                "key_namespace must be non-empty and must not contain '{' or '}'",
+            ));
+        }
+
+        if let Some(sentinel) = &self.server.sentinel {
+            if self.server.redis_cluster {
+                issues.push(ConfigIssue::new(
+                    "server.sentinel",
+                    "sentinel cannot be combined with redis_cluster",
+                ));
+            }
+
+            if sentinel.addresses.is_empty() {
+                issues.push(ConfigIssue::new(
+                    "server.sentinel.addresses",
+                    "at least one sentinel address is required",
+                ));
+            }
+
+            for (index, address) in sentinel.addresses.iter().enumerate() {
+                if let Err(e) = address.as_str().into_connection_info() {
+                    issues.push(ConfigIssue::new(
+                        format!("server.sentinel.addresses[{}]", index),
+                        format!("invalid sentinel URL {:?}: {}", address, e),
+                    ));
+                }
+            }
+
+            if sentinel.master_name.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    "server.sentinel.master_name",
+                    "master_name must not be empty",
+                ));
+            }
+        }
+
+        if let Some(auth) = &self.server.redis_auth
+            && auth.password_file.is_some()
+            && auth.password_env.is_some()
+        {
+            issues.push(ConfigIssue::new(
+                "server.redis_auth",
+                "only one of password_file and password_env can be set",
+            ));
+        }
+
+        if let Some(tls) = &self.server.redis_tls {
+            if tls.cert_file.is_some() != tls.key_file.is_some() {
+                issues.push(ConfigIssue::new(
+                    "server.redis_tls",
+                    "cert_file and key_file must be set together",
+                ));
+            }
+
+            if self.server.sentinel.is_none() && !self.server.redis_url.starts_with("rediss://") {
+                issues.push(ConfigIssue::new(
+                    "server.redis_tls",
+                    "redis_tls requires a rediss:// redis_url",
+                ));
+            }
+        }
+
+        if self.server.redis_timeout_ms == 0 {
+            issues.push(ConfigIssue::new(
+                "server.redis_timeout_ms",
+                "redis_timeout_ms must be greater than zero",
+            ));
+        }
+
+        if self.server.policy_poll_secs == 0 {
+            issues.push(ConfigIssue::new(
+                "server.policy_poll_secs",
+                "policy_poll_secs must be greater than zero",
+            ));
+        }
+
+        if self.server.virtual_clock && !cfg!(feature = "test-support") {
+            issues.push(ConfigIssue::new(
+                "server.virtual_clock",
+                "virtual_clock requires a build with the test-support feature",
+            ));
+        }
+
+        if let Some(top_keys) = &self.server.top_keys {
+            if top_keys.capacity == 0 || top_keys.bucket_secs == 0 || top_keys.flush_secs == 0 {
+                issues.push(ConfigIssue::new(
+                    "server.top_keys",
+                    "capacity, bucket_secs and flush_secs must be greater than zero",
+                ));
+            }
+
+            if top_keys.retention_secs < top_keys.bucket_secs {
+                issues.push(ConfigIssue::new(
+                    "server.top_keys",
+                    "retention_secs must not be less than bucket_secs",
+                ));
+            }
+        }
+
+        if let Some(audit) = &self.server.audit {
+            if audit.path.as_os_str().is_empty() {
+                issues.push(ConfigIssue::new("server.audit", "path must not be empty"));
+            }
+
+            if audit.max_file_bytes == 0 || audit.queue_size == 0 {
+                issues.push(ConfigIssue::new(
+                    "server.audit",
+                    "max_file_bytes and queue_size must be greater than zero",
+                ));
+            }
+        }
+
+        if let Some(events) = &self.server.events {
+            if self.server.store == StoreKind::Memory {
+                issues.push(ConfigIssue::new(
+                    "server.events",
+                    "events need the redis store",
+                ));
+            }
+
+            if events
+                .stream
+                .as_ref()
+                .is_some_and(|stream| stream.is_empty())
+            {
+                issues.push(ConfigIssue::new(
+                    "server.events.stream",
+                    "stream must not be empty",
+                ));
+            }
+
+            if events.max_len == 0 || events.batch_size == 0 || events.queue_size == 0 {
+                issues.push(ConfigIssue::new(
+                    "server.events",
+                    "max_len, batch_size and queue_size must be greater than zero",
+                ));
+            }
+
+            if !(0.0..=1.0).contains(&events.defaults.allow_sample) {
+                issues.push(ConfigIssue::new(
+                    "server.events",
+                    "allow_sample must be between 0 and 1",
+                ));
+            }
+        }
+
+        if self.server.fallback_instances == 0 {
+            issues.push(ConfigIssue::new(
+                "server.fallback_instances",
+                "fallback_instances must be greater than zero",
+            ));
+        }
+
+        let mut problems = Vec::new();
+        validate_policy(&self.default_policy.policy, &mut problems);
+        validate_name(&self.default_policy.name, &mut problems);
+        issues.extend(
+            problems
+                .into_iter()
+                .map(|problem| ConfigIssue::new("default_policy", problem)),
+        );
+
+        for (index, rule) in self.policies.iter().enumerate() {
+            let location = rule_location(index, rule);
+
+            if let Err(problems) = rule.validate() {
+                issues.extend(
+                    problems
+                        .into_iter()
+                        .map(|problem| ConfigIssue::new(&location, problem)),
+                );
+            }
+
+            if rule.name == self.default_policy.name {
+                issues.push(ConfigIssue::new(
+                    &location,
+                    format!("name {:?} is already used by default_policy", rule.name),
+                ));
+            }
+
+            for (other_index, other) in self.policies[..index].iter().enumerate() {
+                if other.pattern == rule.pattern && other.pattern_type == rule.pattern_type {
+                    issues.push(ConfigIssue::new(
+                        &location,
+                        format!("duplicate of policies[{}]", other_index),
+                    ));
+                }
+
+                if other.name == rule.name {
+                    issues.push(ConfigIssue::new(
+                        &location,
+                        format!(
+                            "name {:?} is already used by policies[{}]",
+                            rule.name, other_index
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const SERVER: &str = r#"
+[server]
+address = "[::]:50051"
+redis_url = "redis://127.0.0.1/"
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+"#;
+
+    fn issues(policies: &str) -> Vec<String> {
+        let config: Config = toml::from_str(&format!("{}{}", SERVER, policies)).unwrap();
+        match config.validate() {
+            Ok(()) => vec![],
+            Err(issues) => issues.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let policies = r#"
+[[policies]]
+name = "login"
+pattern = "user.login"
+type = "exact"
+max_tokens = 5
+window_secs = 60
+priority = 100
+
+[[policies]]
+name = "users"
+pattern = "user."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+priority = 50
+"#;
+        assert_eq!(issues(policies), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_repo_config_is_valid() {
+        let config = crate::config::load_config("./config/config.toml");
+        assert!(config.is_ok(), "{:?}", config.err());
+    }
+
+    #[rstest]
+    #[case(
+        0,
+        60,
+        "policies[0] (pattern \"a\"): max_tokens must be greater than zero"
+    )]
+    #[case(
+        5,
+        0,
+        "policies[0] (pattern \"a\"): window_secs must be greater than zero"
+    )]
+    fn test_zero_values(#[case] max_tokens: u32, #[case] window_secs: u64, #[case] expected: &str) {
+        let policies = format!(
+            "[[policies]]\nname = \"a\"\npattern = \"a\"\ntype = \"exact\"\nmax_tokens = {}\nwindow_secs = {}\n",
+            max_tokens, window_secs
+        );
+        assert_eq!(issues(&policies), vec![expected.to_string()]);
+    }
+
+    #[test]
+    fn test_duplicate_pattern() {
+        let policies = r#"
+[[policies]]
+name = "users"
+pattern = "user."
+type = "prefix"
+max_tokens = 5
+window_secs = 60
+
+[[policies]]
+name = "users-burst"
+pattern = "user."
+type = "prefix"
+max_tokens = 10
+window_secs = 60
+priority = 10
+"#;
+        assert_eq!(
+            issues(policies),
+            vec!["policies[1] (pattern \"user.\"): duplicate of policies[0]".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_names() {
+        let policies = r#"
+[[policies]]
+name = "users"
+pattern = "user."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+
+[[policies]]
+name = "users"
+pattern = "user.login"
+type = "exact"
+max_tokens = 5
+window_secs = 60
+
+[[policies]]
+name = "default"
+pattern = "api."
+type = "prefix"
+max_tokens = 5
+window_secs = 60
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[1] (pattern \"user.login\"): name \"users\" is already used by policies[0]".to_string(),
+                "policies[2] (pattern \"api.\"): name \"default\" is already used by default_policy".to_string(),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("regex", r"tenant:(\d+")]
+    #[case("regex", "[a-")]
+    fn test_invalid_pattern(#[case] pattern_type: &str, #[case] pattern: &str) {
+        let policies = format!(
+            "[[policies]]\nname = \"a\"\npattern = '{}'\ntype = \"{}\"\nmax_tokens = 5\nwindow_secs = 60\n",
+            pattern, pattern_type
+        );
+        let issues = issues(&policies);
+        let expected = format!(
+            "policies[0] (pattern {:?}): invalid regex pattern: ",
+            pattern
+        );
+
+        assert_eq!(issues.len(), 1);
+        assert!(issues[0].starts_with(&expected), "{}", issues[0]);
+    }
+
+    #[test]
+    fn test_bucket_with_unknown_capture() {
+        let policies = r#"
+[[policies]]
+name = "tenants"
+pattern = "tenant:{tenant}:*"
+type = "glob"
+bucket = "tenant:{id}"
+max_tokens = 5
+window_secs = 60
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"tenant:{tenant}:*\"): invalid glob pattern: bucket references capture \"id\" which the pattern does not define".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_penalty() {
+        let policies = r#"
+[[policies]]
+name = "login"
+pattern = "user.login"
+type = "exact"
+max_tokens = 5
+window_secs = 60
+
+[policies.penalty]
+denials = 0
+within_secs = 60
+ban_secs = 600
+max_ban_secs = 60
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"user.login\"): penalty.denials must be greater than zero".to_string(),
+                "policies[0] (pattern \"user.login\"): penalty.max_ban_secs must not be less than penalty.ban_secs".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lease() {
+        let policies = r#"
+[[policies]]
+name = "hot"
+pattern = "hot."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+lease = { batch = 200, ttl_ms = 0 }
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"hot.\"): lease.batch must be between 1 and max_tokens"
+                    .to_string(),
+                "policies[0] (pattern \"hot.\"): lease.ttl_ms must be greater than zero"
+                    .to_string(),
+            ]
+        );
+
+        let policies = r#"
+[[policies]]
+name = "login"
+pattern = "login."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+lease = { batch = 10, ttl_ms = 500 }
+
+[policies.penalty]
+denials = 3
+within_secs = 60
+ban_secs = 600
+max_ban_secs = 3600
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"login.\"): lease cannot be combined with penalty"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let policies = r#"
+[[policies]]
+name = "hot"
+pattern = "hot."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+coalesce_ms = 5000
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"hot.\"): coalesce_ms must be between 1 and 1000"
+                    .to_string(),
+            ]
+        );
+
+        let policies = r#"
+[[policies]]
+name = "login"
+pattern = "login."
+type = "prefix"
+max_tokens = 100
+window_secs = 60
+coalesce_ms = 2
+
+[policies.penalty]
+denials = 3
+within_secs = 60
+ban_secs = 600
+max_ban_secs = 3600
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[0] (pattern \"login.\"): coalesce_ms cannot be combined with penalty"
+                    .to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_list_rules() {
+        let policies = r#"
+[[policies]]
+name = "monitors"
+pattern = "monitor."
+type = "prefix"
+action = "allow"
+
+[[policies]]
+name = "abusers"
+pattern = "ip:10.0.0.*"
+type = "glob"
+bucket = "abusers"
+action = "deny"
+"#;
+        assert_eq!(
+            issues(policies),
+            vec![
+                "policies[1] (pattern \"ip:10.0.0.*\"): deny rules cannot have a bucket"
+                    .to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let config = r#"
+[server]
+address = "not an address"
+admin_address = "not an address either"
+redis_url = "http://127.0.0.1/"
+
+[default_policy]
+name = ""
+max_tokens = 0
+window_secs = 0
+
+[[policies]]
+name = "a"
+pattern = "a"
+type = "exact"
+max_tokens = 0
+window_secs = 60
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues = config.validate().unwrap_err();
+        let locations: Vec<_> = issues.iter().map(|i| i.location.as_str()).collect();
+
+        assert_eq!(
+            locations,
+            vec![
+                "server.address",
+                "server.admin_address",
+                "server.redis_url",
+                "default_policy",
+                "default_policy",
+                "default_policy",
+                "policies[0] (pattern \"a\")",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_admin_address() {
+        let config = r#"
+policies = []
+
+[server]
+address = "0.0.0.0:50051"
+admin_address = "0.0.0.0:50051"
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues = config.validate().unwrap_err();
+
+        assert_eq!(
+            issues.iter().map(|i| i.to_string()).collect::<Vec<_>>(),
+            vec!["server.admin_address: admin_address must differ from address".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_sentinel() {
+        let config = r#"
+policies = []
+
+[server]
+address = "[::]:50051"
+redis_cluster = true
+
+[server.sentinel]
+addresses = ["redis://10.0.0.1:26379", "http://10.0.0.2:26379"]
+master_name = " "
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues = config.validate().unwrap_err();
+        let locations: Vec<_> = issues.iter().map(|i| i.location.as_str()).collect();
+
+        assert_eq!(
+            locations,
+            vec![
+                "server.sentinel",
+                "server.sentinel.addresses[1]",
+                "server.sentinel.master_name",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redis_tls_and_auth() {
+        let config = r#"
+policies = []
+
+[server]
+address = "[::]:50051"
+redis_url = "redis://127.0.0.1/"
+key_namespace = "{tenant}"
+
+[server.redis_auth]
+password_file = "/run/secrets/redis"
+password_env = "REDIS_PASSWORD"
+
+[server.redis_tls]
+cert_file = "client.pem"
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "server.key_namespace: key_namespace must be non-empty and must not contain '{' or '}'",
+                "server.redis_auth: only one of password_file and password_env can be set",
+                "server.redis_tls: cert_file and key_file must be set together",
+                "server.redis_tls: redis_tls requires a rediss:// redis_url",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_top_keys() {
+        let config = r#"
+policies = []
+
+[server]
+address = "[::]:50051"
+
+[server.top_keys]
+bucket_secs = 300
+retention_secs = 60
+flush_secs = 0
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "server.top_keys: capacity, bucket_secs and flush_secs must be greater than zero",
+                "server.top_keys: retention_secs must not be less than bucket_secs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_audit() {
+        let config = r#"
+policies = []
+
+[server]
+address = "[::]:50051"
+
+[server.audit]
+path = ""
+queue_size = 0
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+audit = "denials"
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        assert_eq!(
+            config.default_policy.policy.audit,
+            Some(crate::config::AuditLevel::Denials)
+        );
+
+        let issues: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "server.audit: path must not be empty",
+                "server.audit: max_file_bytes and queue_size must be greater than zero",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let config = r#"
+[server]
+address = "[::]:50051"
+store = "memory"
+
+[server.events]
+stream = ""
+batch_size = 0
+allow_sample = 2.0
+
+[default_policy]
+name = "default"
+max_tokens = 10
+window_secs = 60
+
+[[policies]]
+name = "blocked"
+pattern = "blocked:"
+type = "prefix"
+action = "deny"
+events = { allow_sample = -0.5 }
+"#;
+        let config: Config = toml::from_str(config).unwrap();
+        let issues: Vec<_> = config
+            .validate()
+            .unwrap_err()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+
+        assert_eq!(
+            issues,
+            vec![
+                "server.events: events need the redis store",
+                "server.events.stream: stream must not be empty",
+                "server.events: max_len, batch_size and queue_size must be greater than zero",
+                "server.events: allow_sample must be between 0 and 1",
+                "policies[0] (pattern \"blocked:\"): events.allow_sample must be between 0 and 1",
+            ]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::SystemTime,
+};
+
+use log::error;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{common::to_unix_millis, config::AuditConfig};
+
+/// Most records written between two syncs of the file.
+const WRITE_BATCH: usize = 512;
+
+/// Something worth a line in the audit log.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A decision of a policy with an audit level.
+    Decision {
+        key: String,
+        policy: String,
+        allowed: bool,
+        remaining: u32,
+        source: String,
+    },
+
+    /// A call of an admin RPC that changes something. Reads are not audited.
+    Admin {
+        method: String,
+        peer: Option<String>,
+        request: Value,
+        status: String,
+    },
+
+    /// Records dropped because the queue was full.
+    Dropped { count: u64 },
+}
+
+/// What the writer thread is handed.
+#[derive(Debug)]
+enum Queued {
+    Record(AuditRecord),
+
+    /// Answered once everything queued before is written and synced.
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    /// Unix milliseconds.
+    time: u64,
+
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+impl AuditRecord {
+    fn now(event: AuditEvent) -> Self {
+        AuditRecord {
+            time: to_unix_millis(SystemTime::now()) as u64,
+            event,
+        }
+    }
+}
+
+/// Queues audit records for a writer thread. Recording decisions never waits:
+/// while the queue is full, they are dropped, and the writer logs how many
+/// with the next record it writes. Admin records wait for room instead.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    queue: Option<mpsc::Sender<Queued>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AuditLog {
+    /// A log that records nothing.
+    pub fn disabled() -> Self {
+        AuditLog::default()
+    }
+
+    /// Opens the file of `config` and starts writing records to it.
+    pub fn open(config: &AuditConfig) -> io::Result<Self> {
+        let file = RotatingFile::open(&config.path, config.max_file_bytes, config.max_files)?;
+        let (queue, records) = mpsc::channel(config.queue_size);
+        let log = AuditLog {
+            queue: Some(queue),
+            dropped: Arc::default(),
+        };
+
+        // Writes block, so they are kept off the runtime.
+        let dropped = log.dropped.clone();
+        thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_records(file, records, dropped))?;
+
+        Ok(log)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        if queue
+            .try_send(Queued::Record(AuditRecord::now(event)))
+            .is_err()
+        {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records an admin call, waiting for room in the queue rather than
+    /// dropping it.
+    pub async fn record_admin(&self, event: AuditEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        // Only fails once the writer is gone, when nothing is written anyway.
+        let _ = queue.send(Queued::Record(AuditRecord::now(event))).await;
+    }
+
+    /// Waits until the records recorded so far are written, for shutdown.
+    pub async fn flush(&self) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        let (flushed, written) = oneshot::channel();
+        if queue.send(Queued::Flush(flushed)).await.is_ok() {
+            let _ = written.await;
+        }
+    }
+}
+
+fn write_records(
+    mut file: RotatingFile,
+    mut records: mpsc::Receiver<Queued>,
+    dropped: Arc<AtomicU64>,
+) {
+    let mut batch = Vec::with_capacity(WRITE_BATCH);
+
+    // Ends once every sender is gone, after writing what is left.
+    while records.blocking_recv_many(&mut batch, WRITE_BATCH) > 0 {
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            batch.push(Queued::Record(AuditRecord::now(AuditEvent::Dropped {
+                count,
+            })));
+        }
+
+        let mut flushes = Vec::new();
+        for queued in batch.drain(..) {
+            match queued {
+                Queued::Record(record) => {
+                    let mut line = serde_json::to_vec(&record).unwrap();
+                    line.push(b'\n');
+                    if let Err(e) = file.write(&line) {
+                        error!("Failed to write audit record: {}", e);
+                    }
+                }
+                Queued::Flush(flushed) => flushes.push(flushed),
+            }
+        }
+        if let Err(e) = file.sync() {
+            error!("Failed to sync audit log: {}", e);
+        }
+        for flushed in flushes {
+            let _ = flushed.send(());
+        }
+    }
+}
+
+/// A file that is moved to `<path>.1` once it reaches `max_bytes`, shifting
+/// older files up to `<path>.<max_files>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file: BufWriter::new(file),
+            max_bytes,
+            max_files,
+        })
+    }
+
+    fn rotated(&self, index: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        name.into()
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+
+        // Renaming over the oldest file drops it.
+        for index in (1..self.max_files).rev() {
+            match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated(1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::SlidingWindow,
+        config::{
+            AuditLevel, DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction,
+        },
+        db::{MemoryRateLimit, ObservedRateLimit, RateLimitConfig, RateLimitStore},
+        policy::Policies,
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir()
+            .join(format!("break-check-{}", uuid::Uuid::new_v4()))
+            .join(name)
+    }
+
+    fn read_lines(path: &Path) -> Vec<Value> {
+        fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rotation() {
+        let path = temp_path("audit.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(line.as_bytes()).unwrap();
+        }
+        file.sync().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(file.rotated(1)).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(file.rotated(2)).unwrap(), "second\n");
+        assert!(!file.rotated(3).exists());
+    }
+
+    #[test]
+    fn test_full_queue_drops() {
+        let (queue, mut records) = mpsc::channel(1);
+        let log = AuditLog {
+            queue: Some(queue),
+            dropped: Arc::default(),
+        };
+
+        log.record(AuditEvent::Dropped { count: 0 });
+        log.record(AuditEvent::Dropped { count: 0 });
+
+        assert!(matches!(records.try_recv(), Ok(Queued::Record(_))));
+        assert_eq!(log.dropped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_admin_records_wait_for_room() {
+        let (queue, mut records) = mpsc::channel(1);
+        let log = AuditLog {
+            queue: Some(queue),
+            dropped: Arc::default(),
+        };
+        let admin = AuditEvent::Admin {
+            method: "ResetKey".to_string(),
+            peer: None,
+            request: Value::Null,
+            status: "Ok".to_string(),
+        };
+
+        log.record(AuditEvent::Dropped { count: 0 });
+        let recorded = tokio::spawn({
+            let log = log.clone();
+            async move { log.record_admin(admin).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!recorded.is_finished());
+
+        assert!(matches!(records.recv().await, Some(Queued::Record(_))));
+        recorded.await.unwrap();
+        let Some(Queued::Record(record)) = records.recv().await else {
+            panic!("admin record missing");
+        };
+        assert!(matches!(record.event, AuditEvent::Admin { .. }));
+        assert_eq!(log.dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_records_audited_policies() {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let login = PolicyRule {
+            name: "login".to_string(),
+            pattern: "login:".to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                audit: Some(AuditLevel::Denials),
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        };
+        let policies = Arc::new(Policies::new(default_policy, vec![login]).unwrap());
+
+        let path = temp_path("audit.log");
+        let log = AuditLog::open(&AuditConfig {
+            path: path.clone(),
+            max_file_bytes: AuditConfig::DEFAULT_MAX_FILE_BYTES,
+            max_files: 1,
+            queue_size: 16,
+        })
+        .unwrap();
+        let mut store = ObservedRateLimit::new(
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+            policies,
+        )
+        .with_audit(log.clone());
+
+        for key in ["login:alice", "login:alice", "user:1", "user:1"] {
+            let _ = store
+                .acquire(&RateLimitConfig::new(key.to_string(), 1))
+                .await;
+        }
+
+        log.flush().await;
+        let records = read_lines(&path);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["type"], "decision");
+        assert_eq!(records[0]["key"], "login:alice");
+        assert_eq!(records[0]["allowed"], false);
+        assert_eq!(records[0]["source"], "Policy");
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::{sync::oneshot, time::sleep};
+
+use crate::{
+    config::RuleAction,
+    db::{
+        AcquireErr, AcquireResult, DecisionSource, RateLimitConfig, RateLimitStore,
+        TokensRemaining, UNLIMITED_TOKENS, WindowCounters, sharded::ShardedMap, state_key,
+    },
+    policy::Policies,
+};
+
+/// A request waiting for the batch it joined to be decided.
+#[derive(Debug)]
+struct Waiter {
+    key: String,
+    tokens: u32,
+    reply: oneshot::Sender<AcquireResult>,
+}
+
+/// Merges acquires for one counter of a policy that arrive within its
+/// `coalesce_ms` into a single request to `inner`, and splits the result among
+/// the callers in arrival order. Keys sharing a bucket are merged too. Keys
+/// without coalescing go straight to `inner`.
+#[derive(Debug, Clone)]
+pub struct CoalescingRateLimit<R: RateLimitStore> {
+    inner: R,
+    policies: Arc<Policies>,
+    pending: Arc<ShardedMap<Vec<Waiter>>>,
+}
+
+impl<R: RateLimitStore + Clone + Send + Sync + 'static> CoalescingRateLimit<R> {
+    pub fn new(inner: R, policies: Arc<Policies>) -> Self {
+        CoalescingRateLimit {
+            inner,
+            policies,
+            pending: Arc::new(ShardedMap::with_default_shards()),
+        }
+    }
+
+    /// The coalescing interval of a key and the key its batches are kept
+    /// under.
+    fn coalesce_interval(&self, key: &str) -> Option<(String, Duration)> {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(key);
+        match resolved.action {
+            RuleAction::Limit => resolved.policy.coalesce_ms.map(|coalesce_ms| {
+                (
+                    state_key(&resolved.counter_key, resolved.name),
+                    Duration::from_millis(coalesce_ms),
+                )
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Copies an error for every caller of a batch. Redis errors keep their kind
+/// and message.
+fn share_error(e: &AcquireErr) -> AcquireErr {
+    match e {
+        AcquireErr::RateLimitExceeded {
+            reset_after,
+            policy,
+            decided_by,
+            counted_at,
+        } => AcquireErr::RateLimitExceeded {
+            reset_after: *reset_after,
+            policy: policy.clone(),
+            decided_by: *decided_by,
+            counted_at: *counted_at,
+        },
+        AcquireErr::RedisError(e) => AcquireErr::RedisError(redis::RedisError::from((
+            e.kind(),
+            "Coalesced acquire failed",
+            e.to_string(),
+        ))),
+        AcquireErr::Timeout => AcquireErr::Timeout,
+        AcquireErr::ZeroWindow => AcquireErr::ZeroWindow,
+    }
+}
+
+/// Decides a batch on the key of its first caller, which counts for all of
+/// them.
+async fn decide_batch<R: RateLimitStore>(inner: &mut R, waiters: Vec<Waiter>) {
+    let total = waiters
+        .iter()
+        .fold(0u32, |total, waiter| total.saturating_add(waiter.tokens));
+    let batch_config = RateLimitConfig::new(waiters[0].key.clone(), total);
+
+    debug!(
+        "Coalesced {} acquires of {} tokens for key '{}'",
+        waiters.len(),
+        total,
+        batch_config.resource_key
+    );
+
+    match inner.acquire(&batch_config).await {
+        Ok(granted) if granted.remaining == UNLIMITED_TOKENS => {
+            for waiter in waiters {
+                let _ = waiter.reply.send(Ok(granted.clone()));
+            }
+        }
+        Ok(granted) => {
+            // Each caller sees what would have been left had the requests
+            // been decided one by one.
+            let mut remaining = granted.remaining.saturating_add(total);
+            for waiter in waiters {
+                remaining = remaining.saturating_sub(waiter.tokens);
+                let _ = waiter.reply.send(Ok(TokensRemaining::new(
+                    remaining,
+                    granted.reset_after,
+                    granted.policy.clone(),
+                    granted.decided_by,
+                    granted.counted_at,
+                )));
+            }
+        }
+        Err(AcquireErr::RateLimitExceeded {
+            decided_by:
+                DecisionSource::Policy | DecisionSource::Override | DecisionSource::LocalFallback,
+            counted_at,
+            ..
+        }) if waiters.len() > 1 => {
+            // The earlier requests may still fit. Undo the batch and decide
+            // them one by one until the first denial, which the rest share.
+            if let Err(e) = inner.release(&batch_config, counted_at).await {
+                for waiter in waiters {
+                    let _ = waiter.reply.send(Err(share_error(&e)));
+                }
+                return;
+            }
+
+            let mut denial: Option<AcquireErr> = None;
+            for waiter in waiters {
+                let result = match &denial {
+                    Some(denial) => Err(share_error(denial)),
+                    None => {
+                        let config = RateLimitConfig::new(waiter.key, waiter.tokens);
+                        let result = inner.acquire(&config).await;
+                        if let Err(e @ AcquireErr::RateLimitExceeded { .. }) = &result {
+                            denial = Some(share_error(e));
+                        }
+                        result
+                    }
+                };
+                let _ = waiter.reply.send(result);
+            }
+        }
+        Err(e) => {
+            for waiter in waiters {
+                let _ = waiter.reply.send(Err(share_error(&e)));
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R: RateLimitStore + Clone + Send + Sync + 'static> RateLimitStore for CoalescingRateLimit<R> {
+    async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+        let Some((key, interval)) = self.coalesce_interval(&config.resource_key) else {
+            return self.inner.acquire(config).await;
+        };
+
+        let (reply, result) = oneshot::channel();
+        let waiter = Waiter {
+            key: config.resource_key.clone(),
+            tokens: config.tokens_to_acquire,
+            reply,
+        };
+        let first = self.pending.update(&key, Vec::new, |waiters| {
+            waiters.push(waiter);
+            waiters.len() == 1
+        });
+
+        if first {
+            // The batch is decided by its own task, so that the others are
+            // answered even when the first caller goes away.
+            let pending = self.pending.clone();
+            let mut inner = self.inner.clone();
+            tokio::spawn(async move {
+                sleep(interval).await;
+                if let Some(waiters) = pending.remove(&key) {
+                    decide_batch(&mut inner, waiters).await;
+                }
+            });
+        }
+
+        // The reply is only dropped unsent when the batch task panicked.
+        result.await.unwrap_or(Err(AcquireErr::Timeout))
+    }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        self.inner.counters(counter_key, policy, window_secs).await
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        // Pending batches are only counted once decided, so they count against
+        // the fresh window like any later request.
+        self.inner.reset(counter_key, policy, window_secs).await
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        self.inner.release(config, counted_at).await
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        self.inner.is_healthy().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        common::{SlidingWindow, SystemClock},
+        config::{DefaultPolicy, FailureMode, PatternType, PolicyDefinition, PolicyRule},
+        db::{FallbackRateLimit, MemoryRateLimit, fallback::tests::Unreachable},
+    };
+
+    /// Counts the acquires that reach the store, and keeps the times tokens
+    /// were counted at and given back for.
+    #[derive(Clone)]
+    struct Counting {
+        inner: MemoryRateLimit<SlidingWindow<SystemClock>>,
+        acquires: Arc<AtomicUsize>,
+        counted: Arc<Mutex<Vec<SystemTime>>>,
+        released: Arc<Mutex<Vec<SystemTime>>>,
+    }
+
+    #[async_trait]
+    impl RateLimitStore for Counting {
+        async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+            self.acquires.fetch_add(1, Ordering::SeqCst);
+            let result = self.inner.acquire(config).await;
+            let counted_at = match &result {
+                Ok(granted) => granted.counted_at,
+                Err(AcquireErr::RateLimitExceeded { counted_at, .. }) => *counted_at,
+                Err(_) => return result,
+            };
+            self.counted.lock().unwrap().push(counted_at);
+            result
+        }
+
+        async fn counters(
+            &mut self,
+            counter_key: &str,
+            policy: &str,
+            window_secs: u64,
+        ) -> Result<WindowCounters, AcquireErr> {
+            self.inner.counters(counter_key, policy, window_secs).await
+        }
+
+        async fn reset(
+            &mut self,
+            counter_key: &str,
+            policy: &str,
+            window_secs: u64,
+        ) -> Result<(), AcquireErr> {
+            self.inner.reset(counter_key, policy, window_secs).await
+        }
+
+        async fn release(
+            &mut self,
+            config: &RateLimitConfig,
+            counted_at: SystemTime,
+        ) -> Result<(), AcquireErr> {
+            self.released.lock().unwrap().push(counted_at);
+            self.inner.release(config, counted_at).await
+        }
+
+        async fn is_healthy(&mut self) -> bool {
+            true
+        }
+    }
+
+    fn policies() -> Arc<Policies> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let hot = PolicyRule {
+            name: "hot".to_string(),
+            pattern: "hot.".to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens: 5,
+                window_secs: 60,
+                coalesce_ms: Some(20),
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        };
+        let team = PolicyRule {
+            name: "team".to_string(),
+            pattern: "team.".to_string(),
+            bucket: Some("team".to_string()),
+            ..hot.clone()
+        };
+
+        Arc::new(Policies::new(default_policy, vec![hot, team]).unwrap())
+    }
+
+    fn counting() -> Counting {
+        Counting {
+            inner: MemoryRateLimit::new(policies(), SlidingWindow::new()),
+            acquires: Arc::new(AtomicUsize::new(0)),
+            counted: Arc::new(Mutex::new(Vec::new())),
+            released: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn store() -> (CoalescingRateLimit<Counting>, Arc<AtomicUsize>) {
+        let inner = counting();
+        let acquires = inner.acquires.clone();
+        (CoalescingRateLimit::new(inner, policies()), acquires)
+    }
+
+    async fn acquire<R: RateLimitStore + Clone>(
+        store: &R,
+        key: &str,
+        tokens: u32,
+    ) -> AcquireResult {
+        store
+            .clone()
+            .acquire(&RateLimitConfig::new(key.to_string(), tokens))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_merges_concurrent_acquires() {
+        let (store, acquires) = store();
+
+        let (first, second, third) = tokio::join!(
+            acquire(&store, "hot.key", 1),
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 1),
+        );
+
+        assert_eq!(first.unwrap().remaining, 4);
+        assert_eq!(second.unwrap().remaining, 2);
+        assert_eq!(third.unwrap().remaining, 1);
+        assert_eq!(acquires.load(Ordering::SeqCst), 1);
+
+        let counters = store.clone().counters("hot.key", "hot", 60).await.unwrap();
+        assert_eq!(counters.current, 4);
+    }
+
+    #[tokio::test]
+    async fn test_splits_denied_batch_in_arrival_order() {
+        let (store, _) = store();
+
+        let (first, second, third, fourth) = tokio::join!(
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 1),
+        );
+
+        assert_eq!(first.unwrap().remaining, 3);
+        assert_eq!(second.unwrap().remaining, 1);
+        assert!(matches!(third, Err(AcquireErr::RateLimitExceeded { .. })));
+        // It would fit, but arrived after a denied request.
+        assert!(matches!(fourth, Err(AcquireErr::RateLimitExceeded { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_gives_back_denied_batch_at_store_time() {
+        let inner = counting();
+        let (counted, released) = (inner.counted.clone(), inner.released.clone());
+        let store = CoalescingRateLimit::new(inner, policies());
+
+        let (first, second) =
+            tokio::join!(acquire(&store, "hot.key", 4), acquire(&store, "hot.key", 4),);
+
+        assert!(first.is_ok() && second.is_err());
+        assert_eq!(*released.lock().unwrap(), vec![counted.lock().unwrap()[0]]);
+    }
+
+    #[tokio::test]
+    async fn test_merges_keys_sharing_a_bucket() {
+        let (store, acquires) = store();
+
+        let (first, second) =
+            tokio::join!(acquire(&store, "team.a", 1), acquire(&store, "team.b", 1),);
+
+        assert_eq!(first.unwrap().remaining, 4);
+        assert_eq!(second.unwrap().remaining, 3);
+        assert_eq!(acquires.load(Ordering::SeqCst), 1);
+
+        let counters = store.clone().counters("team", "team", 60).await.unwrap();
+        assert_eq!(counters.current, 2);
+    }
+
+    #[tokio::test]
+    async fn test_splits_batch_denied_locally() {
+        let policies = policies();
+        let store = CoalescingRateLimit::new(
+            FallbackRateLimit::new(Unreachable, policies.clone(), FailureMode::Local, 1),
+            policies,
+        );
+
+        let (first, second, third) = tokio::join!(
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 2),
+            acquire(&store, "hot.key", 2),
+        );
+
+        let first = first.unwrap();
+        assert_eq!(first.remaining, 3);
+        assert_eq!(first.decided_by, DecisionSource::LocalFallback);
+        assert_eq!(second.unwrap().remaining, 1);
+        assert!(matches!(
+            third,
+            Err(AcquireErr::RateLimitExceeded {
+                decided_by: DecisionSource::LocalFallback,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_without_coalesce_policy() {
+        let (store, acquires) = store();
+
+        let (first, second) = tokio::join!(
+            acquire(&store, "cold.key", 1),
+            acquire(&store, "cold.key", 1),
+        );
+
+        assert!(first.is_ok() && second.is_ok());
+        assert_eq!(acquires.load(Ordering::SeqCst), 2);
+    }
+}
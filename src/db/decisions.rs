@@ -0,0 +1,188 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc};
+
+/// Decisions kept for watchers of this instance. A watcher further behind
+/// than this misses the oldest decisions instead of holding up the rest.
+const WATCH_BUFFER: usize = 1024;
+
+/// An acquire decision, as streamed to watchers.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Decision {
+    pub key: String,
+    pub policy: String,
+    pub allowed: bool,
+    pub remaining: u32,
+
+    /// Unix milliseconds.
+    pub time: u64,
+}
+
+/// Which decisions a watcher wants to see.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Outcome {
+    #[default]
+    All,
+    Allowed,
+    Denied,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecisionFilter {
+    pub key_prefix: String,
+    pub outcome: Outcome,
+}
+
+impl DecisionFilter {
+    pub fn matches(&self, decision: &Decision) -> bool {
+        let outcome = match self.outcome {
+            Outcome::All => true,
+            Outcome::Allowed => decision.allowed,
+            Outcome::Denied => !decision.allowed,
+        };
+        outcome && decision.key.starts_with(&self.key_prefix)
+    }
+}
+
+/// Hands decisions to the watchers of every instance. Nothing is recorded
+/// while no one watches. A relay stuck on Redis costs decisions rather than
+/// `Acquire` latency: those that find its queue full are skipped, and the
+/// relay warns how many.
+#[derive(Debug, Clone)]
+pub struct DecisionFeed {
+    watchers: broadcast::Sender<Arc<Decision>>,
+    outbox: Option<mpsc::Sender<Decision>>,
+    watched: Arc<AtomicBool>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Default for DecisionFeed {
+    fn default() -> Self {
+        DecisionFeed::local()
+    }
+}
+
+impl DecisionFeed {
+    /// A feed of the decisions of this instance only.
+    pub fn local() -> Self {
+        DecisionFeed {
+            watchers: broadcast::channel(WATCH_BUFFER).0,
+            outbox: None,
+            watched: Arc::default(),
+            dropped: Arc::default(),
+        }
+    }
+
+    /// A feed that queues decisions for a relay, which passes them on to the
+    /// watchers of every instance with [`DecisionFeed::deliver`].
+    pub fn relayed(capacity: usize) -> (Self, mpsc::Receiver<Decision>) {
+        let (outbox, queued) = mpsc::channel(capacity);
+        let feed = DecisionFeed {
+            outbox: Some(outbox),
+            ..DecisionFeed::local()
+        };
+        (feed, queued)
+    }
+
+    /// Whether anyone watches, on this instance or, as told by the relay
+    /// with [`DecisionFeed::set_watched`], on another one.
+    pub fn is_watched(&self) -> bool {
+        self.has_watchers() || self.watched.load(Ordering::Relaxed)
+    }
+
+    /// Whether anyone watches on this instance.
+    pub fn has_watchers(&self) -> bool {
+        self.watchers.receiver_count() > 0
+    }
+
+    pub fn set_watched(&self, watched: bool) {
+        self.watched.store(watched, Ordering::Relaxed);
+    }
+
+    /// Records a decision, built only when someone watches.
+    pub fn record(&self, decision: impl FnOnce() -> Decision) {
+        if !self.is_watched() {
+            return;
+        }
+
+        match &self.outbox {
+            Some(outbox) => {
+                if outbox.try_send(decision()).is_err() {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            None => self.deliver(decision()),
+        }
+    }
+
+    /// Passes a decision on to the watchers of this instance.
+    pub fn deliver(&self, decision: Decision) {
+        // Fails only when no one watches.
+        let _ = self.watchers.send(Arc::new(decision));
+    }
+
+    /// Decisions dropped since the last call, because the relay fell behind.
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Decision>> {
+        self.watchers.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decision(key: &str, allowed: bool) -> Decision {
+        Decision {
+            key: key.to_string(),
+            policy: "default".to_string(),
+            allowed,
+            remaining: 0,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let filter = DecisionFilter {
+            key_prefix: "tenant:".to_string(),
+            outcome: Outcome::Denied,
+        };
+
+        assert!(filter.matches(&decision("tenant:1", false)));
+        assert!(!filter.matches(&decision("tenant:1", true)));
+        assert!(!filter.matches(&decision("user:1", false)));
+        assert!(DecisionFilter::default().matches(&decision("user:1", true)));
+    }
+
+    #[test]
+    fn test_records_only_while_watched() {
+        let feed = DecisionFeed::local();
+        feed.record(|| panic!("built without watchers"));
+
+        let mut watcher = feed.subscribe();
+        feed.record(|| decision("user:1", true));
+        assert_eq!(*watcher.try_recv().unwrap(), decision("user:1", true));
+    }
+
+    #[test]
+    fn test_full_outbox_drops() {
+        let (feed, mut queued) = DecisionFeed::relayed(1);
+        feed.set_watched(true);
+
+        feed.record(|| decision("user:1", true));
+        feed.record(|| decision("user:2", true));
+
+        assert_eq!(queued.try_recv().unwrap(), decision("user:1", true));
+        assert!(queued.try_recv().is_err());
+        assert_eq!(feed.take_dropped(), 1);
+        assert_eq!(feed.take_dropped(), 0);
+    }
+}
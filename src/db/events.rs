@@ -0,0 +1,205 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use tokio::sync::mpsc;
+
+use crate::config::PolicyEvents;
+
+/// A decision published to the event stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionEvent {
+    pub key: String,
+    pub policy: String,
+    pub allowed: bool,
+    pub remaining: u32,
+
+    /// What decided, as in the audit log.
+    pub source: String,
+
+    /// Unix milliseconds.
+    pub time: u64,
+}
+
+/// Queues decision events for the task writing them to the stream. Events
+/// that find it `capacity` events behind are lost; the writer warns how many
+/// after its next batch.
+#[derive(Debug, Clone, Default)]
+pub struct EventPublisher {
+    queue: Option<mpsc::Sender<DecisionEvent>>,
+    dropped: Arc<AtomicU64>,
+    defaults: PolicyEvents,
+    sampler: Sampler,
+}
+
+impl EventPublisher {
+    /// A publisher that publishes nothing.
+    pub fn disabled() -> Self {
+        EventPublisher::default()
+    }
+
+    /// A publisher that queues up to `capacity` events for a writer, which
+    /// takes them from the returned receiver. Policies without `events`
+    /// publish according to `defaults`.
+    pub fn queued(
+        capacity: usize,
+        defaults: PolicyEvents,
+    ) -> (Self, mpsc::Receiver<DecisionEvent>) {
+        let (queue, queued) = mpsc::channel(capacity);
+        let publisher = EventPublisher {
+            queue: Some(queue),
+            defaults,
+            ..EventPublisher::default()
+        };
+        (publisher, queued)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Whether a decision of a policy with `events` is published.
+    pub(super) fn selects(&self, events: Option<PolicyEvents>, allowed: bool) -> bool {
+        let events = events.unwrap_or(self.defaults);
+        if allowed {
+            self.sampler.sample(events.allow_sample)
+        } else {
+            events.denials
+        }
+    }
+
+    pub fn publish(&self, event: DecisionEvent) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+
+        if queue.try_send(event).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Events dropped since the last call, because the writer fell behind.
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Picks a share of calls at random, from the hash of a call counter.
+#[derive(Debug, Clone, Default)]
+struct Sampler {
+    hasher: RandomState,
+    calls: Arc<AtomicU64>,
+}
+
+impl Sampler {
+    fn sample(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        if rate >= 1.0 {
+            return true;
+        }
+
+        let call = self.calls.fetch_add(1, Ordering::Relaxed);
+        (self.hasher.hash_one(call) as f64) < rate * u64::MAX as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::SlidingWindow,
+        config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction},
+        db::{MemoryRateLimit, ObservedRateLimit, RateLimitConfig, RateLimitStore},
+        policy::Policies,
+    };
+
+    #[test]
+    fn test_sampling() {
+        let sampler = Sampler::default();
+
+        assert!((0..100).all(|_| !sampler.sample(0.0)));
+        assert!((0..100).all(|_| sampler.sample(1.0)));
+
+        let sampled = (0..10_000).filter(|_| sampler.sample(0.1)).count();
+        assert!((800..1200).contains(&sampled), "sampled {}", sampled);
+    }
+
+    #[tokio::test]
+    async fn test_publishes_selected_decisions() {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let quiet = PolicyRule {
+            name: "quiet".to_string(),
+            pattern: "quiet:".to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                events: Some(PolicyEvents {
+                    denials: false,
+                    allow_sample: 0.0,
+                }),
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        };
+        let policies = Arc::new(Policies::new(default_policy, vec![quiet]).unwrap());
+        let defaults = PolicyEvents {
+            denials: true,
+            allow_sample: 1.0,
+        };
+        let (publisher, mut queued) = EventPublisher::queued(16, defaults);
+        let mut store = ObservedRateLimit::new(
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+            policies,
+        )
+        .with_events(publisher);
+
+        for key in ["user:1", "user:1", "quiet:1", "quiet:1"] {
+            let _ = store
+                .acquire(&RateLimitConfig::new(key.to_string(), 1))
+                .await;
+        }
+
+        let first = queued.try_recv().unwrap();
+        assert_eq!((first.key.as_str(), first.allowed), ("user:1", true));
+        let second = queued.try_recv().unwrap();
+        assert_eq!((second.key.as_str(), second.allowed), ("user:1", false));
+        assert_eq!(second.source, "Policy");
+        assert!(queued.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_full_queue_drops() {
+        let (publisher, mut queued) = EventPublisher::queued(1, PolicyEvents::default());
+        let event = DecisionEvent {
+            key: "user:1".to_string(),
+            policy: "default".to_string(),
+            allowed: false,
+            remaining: 0,
+            source: "Policy".to_string(),
+            time: 0,
+        };
+
+        publisher.publish(event.clone());
+        publisher.publish(event.clone());
+
+        assert_eq!(queued.try_recv().unwrap(), event);
+        assert_eq!(publisher.take_dropped(), 1);
+        assert_eq!(publisher.take_dropped(), 0);
+    }
+}
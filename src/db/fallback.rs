@@ -0,0 +1,323 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::warn;
+use tokio::task::JoinHandle;
+
+use crate::{
+    common::{SlidingWindow, SystemClock},
+    config::FailureMode,
+    db::{
+        AcquireErr, AcquireResult, DecisionSource, MemoryOverrideStore, MemoryRateLimit,
+        RateLimitConfig, RateLimitStore, TokensRemaining, WindowCounters,
+    },
+    policy::Policies,
+};
+
+/// Decides requests by the policy's failure mode when `inner` cannot be
+/// reached. Decisions made this way are reported with a degraded
+/// [`DecisionSource`].
+#[derive(Debug, Clone)]
+pub struct FallbackRateLimit<R: RateLimitStore> {
+    inner: R,
+    policies: Arc<Policies>,
+    default_mode: FailureMode,
+    local: MemoryRateLimit<SlidingWindow<SystemClock>>,
+}
+
+impl<R: RateLimitStore> FallbackRateLimit<R> {
+    /// `instances` is the number of instances sharing the limits, each of
+    /// which allows its share of a limit in [`FailureMode::Local`].
+    pub fn new(
+        inner: R,
+        policies: Arc<Policies>,
+        default_mode: FailureMode,
+        instances: u32,
+    ) -> Self {
+        let local =
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()).with_instances(instances);
+
+        FallbackRateLimit {
+            inner,
+            policies,
+            default_mode,
+            local,
+        }
+    }
+
+    /// Applies `overrides` in [`FailureMode::Local`]. Without them, local
+    /// decisions only know the policy limits.
+    pub fn with_overrides(mut self, overrides: MemoryOverrideStore) -> Self {
+        self.local = self.local.with_overrides(overrides);
+        self
+    }
+
+    /// Drops local fallback windows that no longer count, every `interval`.
+    pub fn spawn_expiry(&self, interval: Duration) -> JoinHandle<()> {
+        self.local.spawn_expiry(interval)
+    }
+
+    async fn fall_back(&mut self, config: &RateLimitConfig, err: AcquireErr) -> AcquireResult {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(&config.resource_key);
+        let mode = resolved.policy.failure_mode.unwrap_or(self.default_mode);
+
+        let now = SystemTime::now();
+        match mode {
+            FailureMode::Unavailable => Err(err),
+            FailureMode::Open => {
+                warn!(
+                    "Allowing key '{}' without the store: {}",
+                    config.resource_key, err
+                );
+                Ok(TokensRemaining::new(
+                    resolved.policy.max_tokens,
+                    now,
+                    resolved.name.clone(),
+                    DecisionSource::FailOpen,
+                    now,
+                ))
+            }
+            FailureMode::Closed => {
+                warn!(
+                    "Denying key '{}' without the store: {}",
+                    config.resource_key, err
+                );
+                Err(AcquireErr::RateLimitExceeded {
+                    reset_after: now,
+                    policy: resolved.name.clone(),
+                    decided_by: DecisionSource::FailClosed,
+                    counted_at: now,
+                })
+            }
+            FailureMode::Local => {
+                warn!(
+                    "Limiting key '{}' locally without the store: {}",
+                    config.resource_key, err
+                );
+                match self.local.acquire(config).await {
+                    Ok(result) => Ok(TokensRemaining {
+                        decided_by: DecisionSource::LocalFallback,
+                        ..result
+                    }),
+                    Err(AcquireErr::RateLimitExceeded {
+                        reset_after,
+                        policy,
+                        counted_at,
+                        ..
+                    }) => Err(AcquireErr::RateLimitExceeded {
+                        reset_after,
+                        policy,
+                        decided_by: DecisionSource::LocalFallback,
+                        counted_at,
+                    }),
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<R: RateLimitStore + Send + Sync> RateLimitStore for FallbackRateLimit<R> {
+    async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+        match self.inner.acquire(config).await {
+            Err(e @ (AcquireErr::RedisError(_) | AcquireErr::Timeout)) => {
+                self.fall_back(config, e).await
+            }
+            result => result,
+        }
+    }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        self.inner.counters(counter_key, policy, window_secs).await
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        self.local.reset(counter_key, policy, window_secs).await?;
+        self.inner.reset(counter_key, policy, window_secs).await
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        match self.inner.release(config, counted_at).await {
+            // Tokens counted while the store is unreachable were counted by
+            // the local fallback, if at all.
+            Err(AcquireErr::RedisError(_) | AcquireErr::Timeout) => {
+                self.local.release(config, counted_at).await
+            }
+            result => result,
+        }
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        self.inner.is_healthy().await
+    }
+}
+
+#[cfg(test)]
+pub(in crate::db) mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{
+        config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction},
+        db::{KeyOverride, OverrideStore, reload_overrides},
+    };
+
+    /// A store that is never reachable.
+    #[derive(Debug, Clone)]
+    pub(in crate::db) struct Unreachable;
+
+    #[async_trait]
+    impl RateLimitStore for Unreachable {
+        async fn acquire(&mut self, _config: &RateLimitConfig) -> AcquireResult {
+            Err(AcquireErr::Timeout)
+        }
+
+        async fn counters(
+            &mut self,
+            _counter_key: &str,
+            _policy: &str,
+            _window_secs: u64,
+        ) -> Result<WindowCounters, AcquireErr> {
+            Err(AcquireErr::Timeout)
+        }
+
+        async fn reset(
+            &mut self,
+            _counter_key: &str,
+            _policy: &str,
+            _window_secs: u64,
+        ) -> Result<(), AcquireErr> {
+            Err(AcquireErr::Timeout)
+        }
+
+        async fn release(
+            &mut self,
+            _config: &RateLimitConfig,
+            _counted_at: SystemTime,
+        ) -> Result<(), AcquireErr> {
+            Err(AcquireErr::Timeout)
+        }
+
+        async fn is_healthy(&mut self) -> bool {
+            false
+        }
+    }
+
+    fn store(default_mode: FailureMode, instances: u32) -> FallbackRateLimit<Unreachable> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let login = PolicyRule {
+            name: "login".to_string(),
+            pattern: "login:".to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                failure_mode: Some(FailureMode::Closed),
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        };
+
+        let policies = Arc::new(Policies::new(default_policy, vec![login]).unwrap());
+        FallbackRateLimit::new(Unreachable, policies, default_mode, instances)
+    }
+
+    async fn decide(store: &mut FallbackRateLimit<Unreachable>, key: &str) -> AcquireResult {
+        store
+            .acquire(&RateLimitConfig::new(key.to_string(), 1))
+            .await
+    }
+
+    fn source(result: &AcquireResult) -> Option<DecisionSource> {
+        match result {
+            Ok(result) => Some(result.decided_by),
+            Err(AcquireErr::RateLimitExceeded { decided_by, .. }) => Some(*decided_by),
+            Err(_) => None,
+        }
+    }
+
+    #[rstest]
+    #[case(FailureMode::Unavailable, None, false)]
+    #[case(FailureMode::Open, Some(DecisionSource::FailOpen), true)]
+    #[case(FailureMode::Closed, Some(DecisionSource::FailClosed), false)]
+    #[case(FailureMode::Local, Some(DecisionSource::LocalFallback), true)]
+    #[tokio::test]
+    async fn test_default_mode(
+        #[case] mode: FailureMode,
+        #[case] expected: Option<DecisionSource>,
+        #[case] allowed: bool,
+    ) {
+        let mut store = store(mode, 1);
+
+        let result = decide(&mut store, "user:1").await;
+        assert_eq!(source(&result), expected);
+        assert_eq!(result.is_ok(), allowed);
+    }
+
+    #[tokio::test]
+    async fn test_policy_mode() {
+        let mut store = store(FailureMode::Open, 1);
+
+        let result = decide(&mut store, "login:alice").await;
+        assert_eq!(source(&result), Some(DecisionSource::FailClosed));
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_local_share() {
+        let mut store = store(FailureMode::Local, 4);
+
+        // Each of the 4 instances allows 10 / 4 = 2 tokens.
+        assert!(decide(&mut store, "user:1").await.is_ok());
+        assert!(decide(&mut store, "user:1").await.is_ok());
+        let denied = decide(&mut store, "user:1").await;
+        assert!(denied.is_err());
+        assert!(source(&denied).unwrap().is_degraded());
+    }
+
+    #[tokio::test]
+    async fn test_local_applies_overrides() {
+        let mut source = MemoryOverrideStore::default();
+        source
+            .set(&KeyOverride {
+                key: "user:1".to_string(),
+                max_tokens: 1,
+                expires_at: SystemTime::now() + Duration::from_secs(60),
+            })
+            .await
+            .unwrap();
+        let overrides = MemoryOverrideStore::default();
+        reload_overrides(&mut source, &overrides).await.unwrap();
+        let mut store = store(FailureMode::Local, 1).with_overrides(overrides);
+
+        assert_eq!(decide(&mut store, "user:1").await.unwrap().remaining, 0);
+        assert!(decide(&mut store, "user:1").await.is_err());
+        assert_eq!(decide(&mut store, "user:2").await.unwrap().remaining, 9);
+    }
+}
@@ -0,0 +1,527 @@
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::{
+    config::{LeaseConfig, RuleAction},
+    db::{
+        AcquireErr, AcquireResult, DecisionSource, RateLimitConfig, RateLimitStore,
+        TokensRemaining, WindowCounters, sharded::ShardedMap, state_key,
+    },
+    policy::Policies,
+};
+
+/// Tokens taken from the store in advance and handed out locally.
+#[derive(Debug, Clone)]
+struct Lease {
+    /// Resource key the lease was taken for. Keys sharing its counter are
+    /// served from it too.
+    key: String,
+    tokens: u32,
+
+    /// Tokens the store had left when the lease was taken.
+    shared_remaining: u32,
+    reset_after: SystemTime,
+    policy: Arc<str>,
+    decided_by: DecisionSource,
+    counted_at: SystemTime,
+    expires_at: Instant,
+}
+
+impl Lease {
+    fn is_expired(&self, now: Instant) -> bool {
+        now >= self.expires_at
+    }
+
+    fn take(&mut self, tokens: u32, now: Instant) -> Option<TokensRemaining> {
+        if self.is_expired(now) || tokens > self.tokens {
+            return None;
+        }
+
+        self.tokens -= tokens;
+        Some(TokensRemaining::new(
+            self.shared_remaining.saturating_add(self.tokens),
+            self.reset_after,
+            self.policy.clone(),
+            self.decided_by,
+            self.counted_at,
+        ))
+    }
+}
+
+/// Serves keys whose policy enables leasing from tokens taken from `inner` in
+/// batches, so that most requests for hot keys never reach the store. Leases
+/// belong to the counter of a policy, so keys sharing a bucket share them.
+/// Keys without a lease policy go straight to `inner`.
+#[derive(Debug, Clone)]
+pub struct LeasedRateLimit<R: RateLimitStore> {
+    inner: R,
+    policies: Arc<Policies>,
+    leases: Arc<ShardedMap<Lease>>,
+}
+
+impl<R: RateLimitStore + Clone + Send + Sync + 'static> LeasedRateLimit<R> {
+    pub fn new(inner: R, policies: Arc<Policies>) -> Self {
+        LeasedRateLimit {
+            inner,
+            policies,
+            leases: Arc::new(ShardedMap::with_default_shards()),
+        }
+    }
+
+    /// Gives back the unused tokens of expired leases every `interval`, until
+    /// the rate limiter is dropped.
+    pub fn spawn_expiry(&self, interval: Duration) -> JoinHandle<()> {
+        let leases: Weak<ShardedMap<Lease>> = Arc::downgrade(&self.leases);
+        let mut inner = self.inner.clone();
+
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                let Some(leases) = leases.upgrade() else {
+                    break;
+                };
+                let now = Instant::now();
+                let expired = leases.drain_where(|_, lease| lease.is_expired(now));
+                drop(leases);
+
+                for (_, lease) in expired {
+                    give_back(&mut inner, lease).await;
+                }
+            }
+        })
+    }
+
+    /// The lease policy of a key and the key its lease is kept under.
+    fn lease_config(&self, key: &str) -> Option<(String, LeaseConfig)> {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(key);
+        match resolved.action {
+            RuleAction::Limit => resolved
+                .policy
+                .lease
+                .map(|lease| (state_key(&resolved.counter_key, resolved.name), lease)),
+            _ => None,
+        }
+    }
+}
+
+async fn give_back<R: RateLimitStore>(inner: &mut R, lease: Lease) {
+    if lease.tokens == 0 {
+        return;
+    }
+
+    debug!(
+        "Returning {} unused leased tokens for key '{}'",
+        lease.tokens, lease.key
+    );
+    let config = RateLimitConfig::new(lease.key, lease.tokens);
+    if let Err(e) = inner.release(&config, lease.counted_at).await {
+        warn!(
+            "Failed to return leased tokens for key '{}': {}",
+            config.resource_key, e
+        );
+    }
+}
+
+#[async_trait]
+impl<R: RateLimitStore + Clone + Send + Sync + 'static> RateLimitStore for LeasedRateLimit<R> {
+    async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+        let Some((lease_key, lease_config)) = self.lease_config(&config.resource_key) else {
+            return self.inner.acquire(config).await;
+        };
+
+        let key = &config.resource_key;
+        let tokens = config.tokens_to_acquire;
+        if let Some(Some(result)) = self
+            .leases
+            .modify(&lease_key, |lease| lease.take(tokens, Instant::now()))
+        {
+            return Ok(result);
+        }
+
+        if let Some(lease) = self.leases.remove(&lease_key) {
+            give_back(&mut self.inner, lease).await;
+        }
+
+        let batch = lease_config.batch.max(tokens);
+        let batch_config = RateLimitConfig::new(key.clone(), batch);
+
+        let granted = match self.inner.acquire(&batch_config).await {
+            Ok(granted) => granted,
+            Err(AcquireErr::RateLimitExceeded {
+                decided_by:
+                    DecisionSource::Policy | DecisionSource::Override | DecisionSource::LocalFallback,
+                counted_at,
+                ..
+            }) if batch > tokens => {
+                // Not enough left for a whole batch. Undo the batch and let
+                // the store decide on this request alone.
+                self.inner.release(&batch_config, counted_at).await?;
+                return self.inner.acquire(config).await;
+            }
+            Err(e) => return Err(e),
+        };
+
+        if granted.decided_by.is_degraded() {
+            // Decided without the store, so there is nothing to lease from.
+            // Only the tokens of this request are kept.
+            let surplus = RateLimitConfig::new(key.clone(), batch - tokens);
+            if batch > tokens
+                && let Err(e) = self.inner.release(&surplus, granted.counted_at).await
+            {
+                warn!("Failed to return unleased tokens for key '{}': {}", key, e);
+            }
+            return Ok(TokensRemaining {
+                remaining: granted.remaining.saturating_add(batch - tokens),
+                ..granted
+            });
+        }
+
+        let lease = Lease {
+            key: key.clone(),
+            tokens: batch - tokens,
+            shared_remaining: granted.remaining,
+            reset_after: granted.reset_after,
+            policy: granted.policy,
+            decided_by: granted.decided_by,
+            counted_at: granted.counted_at,
+            expires_at: Instant::now() + Duration::from_millis(lease_config.ttl_ms),
+        };
+        debug!(
+            "Leased {} tokens for key '{}', {} left after this request",
+            batch, key, lease.tokens
+        );
+
+        let result = TokensRemaining::new(
+            lease.shared_remaining.saturating_add(lease.tokens),
+            lease.reset_after,
+            lease.policy.clone(),
+            lease.decided_by,
+            lease.counted_at,
+        );
+
+        if let Some(replaced) = self.leases.insert(lease_key, lease) {
+            // Another request leased concurrently; its leftovers go back.
+            give_back(&mut self.inner, replaced).await;
+        }
+
+        Ok(result)
+    }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        self.inner.counters(counter_key, policy, window_secs).await
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        // The lease was counted in the window that is being forgotten, so its
+        // tokens must not be given back to the fresh one.
+        self.leases.remove(&state_key(counter_key, policy));
+        self.inner.reset(counter_key, policy, window_secs).await
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        self.inner.release(config, counted_at).await
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        self.inner.is_healthy().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        common::{SlidingWindow, SystemClock},
+        config::{DefaultPolicy, FailureMode, PatternType, PolicyDefinition, PolicyRule},
+        db::{DecisionSource, FallbackRateLimit, MemoryRateLimit, fallback::tests::Unreachable},
+    };
+
+    fn policies(ttl_ms: u64) -> Arc<Policies> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let hot = PolicyRule {
+            name: "hot".to_string(),
+            pattern: "hot.".to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens: 25,
+                window_secs: 60,
+                lease: Some(LeaseConfig { batch: 10, ttl_ms }),
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        };
+        let team = PolicyRule {
+            name: "team".to_string(),
+            pattern: "team.".to_string(),
+            bucket: Some("team".to_string()),
+            ..hot.clone()
+        };
+
+        Arc::new(Policies::new(default_policy, vec![hot, team]).unwrap())
+    }
+
+    fn store(ttl_ms: u64) -> LeasedRateLimit<MemoryRateLimit<SlidingWindow<SystemClock>>> {
+        let policies = policies(ttl_ms);
+        LeasedRateLimit::new(
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+            policies,
+        )
+    }
+
+    fn unreachable(mode: FailureMode) -> LeasedRateLimit<FallbackRateLimit<Unreachable>> {
+        let policies = policies(60_000);
+        LeasedRateLimit::new(
+            FallbackRateLimit::new(Unreachable, policies.clone(), mode, 1),
+            policies,
+        )
+    }
+
+    fn source(result: &AcquireResult) -> Option<DecisionSource> {
+        match result {
+            Ok(result) => Some(result.decided_by),
+            Err(AcquireErr::RateLimitExceeded { decided_by, .. }) => Some(*decided_by),
+            Err(_) => None,
+        }
+    }
+
+    async fn acquire<R: RateLimitStore>(store: &mut R, key: &str, tokens: u32) -> AcquireResult {
+        store
+            .acquire(&RateLimitConfig::new(key.to_string(), tokens))
+            .await
+    }
+
+    async fn counted<R: RateLimitStore>(store: &mut R, key: &str, policy: &str) -> u32 {
+        store.counters(key, policy, 60).await.unwrap().current
+    }
+
+    #[tokio::test]
+    async fn test_serves_from_lease() {
+        let mut store = store(60_000);
+
+        let first = acquire(&mut store, "hot.key", 1).await.unwrap();
+        assert_eq!(first.remaining, 24);
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 10);
+
+        for _ in 0..9 {
+            acquire(&mut store, "hot.key", 1).await.unwrap();
+        }
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 10);
+
+        // The lease is used up, so the next request takes a new batch.
+        acquire(&mut store, "hot.key", 1).await.unwrap();
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 20);
+    }
+
+    #[tokio::test]
+    async fn test_exact_near_limit() {
+        let mut store = store(60_000);
+
+        acquire(&mut store, "hot.key", 20).await.unwrap();
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 20);
+
+        // A batch of 10 no longer fits, a single token still does.
+        let result = acquire(&mut store, "hot.key", 5).await.unwrap();
+        assert_eq!(result.remaining, 0);
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 25);
+
+        assert!(acquire(&mut store, "hot.key", 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_returns_expired_lease() {
+        let mut store = store(10);
+
+        acquire(&mut store, "hot.key", 1).await.unwrap();
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 10);
+
+        let handle = store.spawn_expiry(Duration::from_millis(5));
+        sleep(Duration::from_millis(50)).await;
+        handle.abort();
+
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reset_drops_lease() {
+        let mut store = store(10);
+
+        acquire(&mut store, "hot.key", 1).await.unwrap();
+        store.reset("hot.key", "hot", 60).await.unwrap();
+        assert_eq!(store.leases.len(), 0);
+
+        let handle = store.spawn_expiry(Duration::from_millis(5));
+        sleep(Duration::from_millis(50)).await;
+        handle.abort();
+        assert_eq!(counted(&mut store, "hot.key", "hot").await, 0);
+
+        assert_eq!(
+            acquire(&mut store, "hot.key", 1).await.unwrap().remaining,
+            24
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_sharing_a_bucket_share_lease() {
+        let mut store = store(60_000);
+
+        acquire(&mut store, "team.a", 1).await.unwrap();
+        let result = acquire(&mut store, "team.b", 1).await.unwrap();
+        assert_eq!(result.remaining, 23);
+        assert_eq!(counted(&mut store, "team", "team").await, 10);
+        assert_eq!(store.leases.len(), 1);
+    }
+
+    /// A store whose clock is an hour behind, which keeps what is given back.
+    #[derive(Clone)]
+    struct Behind {
+        inner: MemoryRateLimit<SlidingWindow<SystemClock>>,
+        released: Arc<Mutex<Vec<(String, u32, SystemTime)>>>,
+    }
+
+    #[async_trait]
+    impl RateLimitStore for Behind {
+        async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+            let result = self.inner.acquire(config).await?;
+            Ok(TokensRemaining {
+                counted_at: SystemTime::UNIX_EPOCH + Duration::from_secs(3600),
+                ..result
+            })
+        }
+
+        async fn counters(
+            &mut self,
+            counter_key: &str,
+            policy: &str,
+            window_secs: u64,
+        ) -> Result<WindowCounters, AcquireErr> {
+            self.inner.counters(counter_key, policy, window_secs).await
+        }
+
+        async fn reset(
+            &mut self,
+            counter_key: &str,
+            policy: &str,
+            window_secs: u64,
+        ) -> Result<(), AcquireErr> {
+            self.inner.reset(counter_key, policy, window_secs).await
+        }
+
+        async fn release(
+            &mut self,
+            config: &RateLimitConfig,
+            counted_at: SystemTime,
+        ) -> Result<(), AcquireErr> {
+            self.released.lock().unwrap().push((
+                config.resource_key.clone(),
+                config.tokens_to_acquire,
+                counted_at,
+            ));
+            Ok(())
+        }
+
+        async fn is_healthy(&mut self) -> bool {
+            true
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gives_back_at_store_time() {
+        let policies = policies(10);
+        let released = Arc::new(Mutex::new(Vec::new()));
+        let mut store = LeasedRateLimit::new(
+            Behind {
+                inner: MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+                released: released.clone(),
+            },
+            policies,
+        );
+
+        acquire(&mut store, "hot.key", 1).await.unwrap();
+        let handle = store.spawn_expiry(Duration::from_millis(5));
+        sleep(Duration::from_millis(50)).await;
+        handle.abort();
+
+        let counted_at = SystemTime::UNIX_EPOCH + Duration::from_secs(3600);
+        assert_eq!(
+            *released.lock().unwrap(),
+            vec![("hot.key".to_string(), 9, counted_at)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_fallback_without_lease() {
+        let mut store = unreachable(FailureMode::Local);
+
+        let first = acquire(&mut store, "hot.key", 1).await;
+        assert_eq!(source(&first), Some(DecisionSource::LocalFallback));
+        assert_eq!(first.unwrap().remaining, 24);
+        assert_eq!(store.leases.len(), 0);
+
+        // Only the single token was kept, so 20 more fit.
+        assert_eq!(
+            acquire(&mut store, "hot.key", 20).await.unwrap().remaining,
+            4
+        );
+
+        // The batch is denied and undone locally, the request alone fits.
+        let result = acquire(&mut store, "hot.key", 4).await;
+        assert_eq!(source(&result), Some(DecisionSource::LocalFallback));
+        assert_eq!(result.unwrap().remaining, 0);
+
+        let result = acquire(&mut store, "hot.key", 1).await;
+        assert!(matches!(result, Err(AcquireErr::RateLimitExceeded { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_fail_open_and_closed_without_lease() {
+        let mut store = unreachable(FailureMode::Open);
+        let result = acquire(&mut store, "hot.key", 1).await;
+        assert_eq!(source(&result), Some(DecisionSource::FailOpen));
+        assert_eq!(store.leases.len(), 0);
+
+        let mut store = unreachable(FailureMode::Closed);
+        let result = acquire(&mut store, "hot.key", 1).await;
+        assert_eq!(source(&result), Some(DecisionSource::FailClosed));
+    }
+
+    #[tokio::test]
+    async fn test_without_lease_policy() {
+        let mut store = store(60_000);
+
+        acquire(&mut store, "cold.key", 1).await.unwrap();
+        assert_eq!(counted(&mut store, "cold.key", "default").await, 1);
+    }
+}
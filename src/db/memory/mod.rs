@@ -0,0 +1,7 @@
+mod overrides;
+mod policies;
+mod store;
+
+pub use overrides::*;
+pub use policies::*;
+pub use store::*;
@@ -0,0 +1,86 @@
+use std::{collections::HashSet, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+
+use crate::db::{KeyOverride, OverrideStore, OverrideStoreErr};
+
+use crate::db::sharded::ShardedMap;
+
+/// Overrides kept in process memory, shared with the [`MemoryRateLimit`] that
+/// created the store or was given it.
+///
+/// [`MemoryRateLimit`]: crate::db::MemoryRateLimit
+#[derive(Debug, Clone)]
+pub struct MemoryOverrideStore {
+    overrides: Arc<ShardedMap<KeyOverride>>,
+}
+
+impl Default for MemoryOverrideStore {
+    fn default() -> Self {
+        MemoryOverrideStore {
+            overrides: Arc::new(ShardedMap::with_default_shards()),
+        }
+    }
+}
+
+impl MemoryOverrideStore {
+    /// Max tokens of the active override of `key`, if there is one.
+    pub(super) fn active(&self, key: &str, now: SystemTime) -> Option<u32> {
+        self.overrides
+            .get(key, |key_override| {
+                Some(key_override.max_tokens).filter(|_| key_override.is_active(now))
+            })
+            .flatten()
+    }
+
+    /// Replaces every override with `overrides`.
+    pub fn replace(&self, overrides: Vec<KeyOverride>) {
+        let keys: HashSet<String> = overrides.iter().map(|o| o.key.clone()).collect();
+        for key_override in overrides {
+            self.overrides
+                .insert(key_override.key.clone(), key_override);
+        }
+        self.overrides.retain(|key, _| keys.contains(key));
+    }
+
+    pub(super) fn purge(&self, now: SystemTime) {
+        self.overrides
+            .retain(|_, key_override| key_override.is_active(now));
+    }
+}
+
+#[async_trait]
+impl OverrideStore for MemoryOverrideStore {
+    async fn set(&mut self, key_override: &KeyOverride) -> Result<(), OverrideStoreErr> {
+        self.overrides
+            .insert(key_override.key.clone(), key_override.clone());
+        Ok(())
+    }
+
+    async fn get(&mut self, key: &str) -> Result<Option<KeyOverride>, OverrideStoreErr> {
+        let now = SystemTime::now();
+        Ok(self
+            .overrides
+            .get(key, |key_override| {
+                Some(key_override.clone()).filter(|key_override| key_override.is_active(now))
+            })
+            .flatten())
+    }
+
+    async fn list(&mut self) -> Result<Vec<KeyOverride>, OverrideStoreErr> {
+        let now = SystemTime::now();
+        let mut overrides = self.overrides.collect(|_, key_override| {
+            Some(key_override.clone()).filter(|key_override| key_override.is_active(now))
+        });
+        overrides.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(overrides)
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<bool, OverrideStoreErr> {
+        let now = SystemTime::now();
+        Ok(self
+            .overrides
+            .remove(key)
+            .is_some_and(|key_override| key_override.is_active(now)))
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    config::PolicyRule,
+    db::{PolicyStore, PolicyStoreErr},
+    policy::StoredPolicy,
+};
+
+#[derive(Debug, Default)]
+struct State {
+    version: u64,
+    rules: HashMap<String, StoredPolicy>,
+}
+
+/// Runtime policies kept in process memory. They apply to this instance only
+/// and are lost on restart.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryPolicyStore {
+    state: Arc<Mutex<State>>,
+}
+
+impl State {
+    fn check_version(&self, name: &str, expected_version: u64) -> Result<(), PolicyStoreErr> {
+        let current_version = self.rules.get(name).map_or(0, |stored| stored.version);
+        if current_version != expected_version {
+            return Err(PolicyStoreErr::Conflict {
+                name: name.to_string(),
+                current_version,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PolicyStore for MemoryPolicyStore {
+    async fn version(&mut self) -> Result<u64, PolicyStoreErr> {
+        Ok(self.state.lock().unwrap().version)
+    }
+
+    async fn list(&mut self) -> Result<(u64, Vec<StoredPolicy>), PolicyStoreErr> {
+        let state = self.state.lock().unwrap();
+        Ok((state.version, state.rules.values().cloned().collect()))
+    }
+
+    async fn put(
+        &mut self,
+        rule: &PolicyRule,
+        expected_version: u64,
+    ) -> Result<u64, PolicyStoreErr> {
+        let mut state = self.state.lock().unwrap();
+        state.check_version(&rule.name, expected_version)?;
+
+        state.version += 1;
+        let version = state.version;
+        state.rules.insert(
+            rule.name.clone(),
+            StoredPolicy {
+                rule: rule.clone(),
+                version,
+            },
+        );
+        Ok(version)
+    }
+
+    async fn delete(&mut self, name: &str, expected_version: u64) -> Result<(), PolicyStoreErr> {
+        let mut state = self.state.lock().unwrap();
+        state.check_version(name, expected_version)?;
+
+        state.version += 1;
+        state.rules.remove(name);
+        Ok(())
+    }
+}
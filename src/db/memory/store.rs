@@ -0,0 +1,650 @@
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use log::{debug, info};
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::{
+    common::{AcquireAttempt, RateLimitAlgorithm, RateLimitAlgorithmErr},
+    config::{PenaltyConfig, RuleAction},
+    db::{
+        AcquireErr, AcquireResult, DecisionSource, MemoryOverrideStore, RateLimitConfig,
+        RateLimitStore, TokensRemaining, WindowCounters, list_decision, state_key, window_index,
+    },
+    policy::Policies,
+};
+
+use crate::db::sharded::ShardedMap;
+
+/// Counters of the current and previous window of a counter key.
+#[derive(Debug, Clone)]
+struct Window {
+    window_secs: u64,
+    index: u128,
+    current: u32,
+    previous: u32,
+}
+
+impl Window {
+    /// Counters as of window `index`, moving the current count into the
+    /// previous window once a window has passed.
+    fn at(&self, window_secs: u64, index: u128) -> (u32, u32) {
+        if window_secs != self.window_secs || index > self.index + 1 {
+            (0, 0)
+        } else if index == self.index + 1 {
+            (0, self.current)
+        } else {
+            (self.current, self.previous)
+        }
+    }
+
+    fn advance(&mut self, window_secs: u64, index: u128) {
+        let (current, previous) = self.at(window_secs, index);
+        *self = Window {
+            window_secs,
+            index: index.max(self.index),
+            current,
+            previous,
+        };
+    }
+}
+
+/// Penalty state of a counter key, the in-process version of the keys kept by
+/// the Redis store.
+#[derive(Debug, Clone, Default)]
+struct PenaltyState {
+    denials: u32,
+    denials_until: Option<SystemTime>,
+    strikes: u32,
+    strikes_until: Option<SystemTime>,
+    ban_until: Option<SystemTime>,
+}
+
+impl PenaltyState {
+    fn expire(&mut self, now: SystemTime) {
+        if self.denials_until.is_some_and(|until| until <= now) {
+            self.denials = 0;
+            self.denials_until = None;
+        }
+
+        if self.strikes_until.is_some_and(|until| until <= now) {
+            self.strikes = 0;
+            self.strikes_until = None;
+        }
+
+        if self.ban_until.is_some_and(|until| until <= now) {
+            self.ban_until = None;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.denials == 0 && self.strikes == 0 && self.ban_until.is_none()
+    }
+
+    /// Records a denial and returns when the ban it triggers ends, if any.
+    fn deny(&mut self, penalty: &PenaltyConfig, now: SystemTime) -> Option<SystemTime> {
+        self.expire(now);
+
+        self.denials += 1;
+        if self.denials == 1 {
+            self.denials_until = Some(now + Duration::from_secs(penalty.within_secs));
+        }
+        if self.denials < penalty.denials {
+            return None;
+        }
+        self.denials = 0;
+        self.denials_until = None;
+
+        self.strikes += 1;
+        let mut ban = penalty.ban_secs;
+        for _ in 1..self.strikes {
+            ban = ban.saturating_mul(penalty.multiplier as u64);
+            if ban >= penalty.max_ban_secs {
+                break;
+            }
+        }
+        let ban = Duration::from_secs(ban.min(penalty.max_ban_secs));
+
+        let ban_until = now + ban;
+        self.strikes_until = Some(ban_until + Duration::from_secs(penalty.max_ban_secs));
+        self.ban_until = Some(ban_until);
+        Some(ban_until)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    windows: ShardedMap<Window>,
+    penalties: ShardedMap<PenaltyState>,
+}
+
+impl State {
+    fn purge(&self, now: SystemTime) {
+        self.windows.retain(|_, window| {
+            window_index(now, window.window_secs).is_ok_and(|index| index <= window.index + 1)
+        });
+        self.penalties.retain(|_, penalty| {
+            penalty.expire(now);
+            !penalty.is_empty()
+        });
+    }
+}
+
+/// Rate limits kept in process memory, for single-node deployments and
+/// development without Redis. Counts are lost on restart and not shared
+/// between instances.
+#[derive(Debug, Clone)]
+pub struct MemoryRateLimit<A: RateLimitAlgorithm> {
+    state: Arc<State>,
+    overrides: MemoryOverrideStore,
+    policies: Arc<Policies>,
+    algorithm: A,
+    instances: u32,
+}
+
+impl<A: RateLimitAlgorithm> MemoryRateLimit<A> {
+    pub fn new(policies: Arc<Policies>, algorithm: A) -> Self {
+        MemoryRateLimit {
+            state: Arc::new(State {
+                windows: ShardedMap::with_default_shards(),
+                penalties: ShardedMap::with_default_shards(),
+            }),
+            overrides: MemoryOverrideStore::default(),
+            policies,
+            algorithm,
+            instances: 1,
+        }
+    }
+
+    /// Shares every limit between `instances` processes, each allowing its
+    /// share of at least one token.
+    pub fn with_instances(mut self, instances: u32) -> Self {
+        self.instances = instances.max(1);
+        self
+    }
+
+    /// Consults `overrides` instead of a store of its own.
+    pub fn with_overrides(mut self, overrides: MemoryOverrideStore) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Override store consulted by this rate limiter.
+    pub fn override_store(&self) -> MemoryOverrideStore {
+        self.overrides.clone()
+    }
+
+    /// Drops windows, penalties and overrides that no longer affect any
+    /// decision. Runs every `interval` until the rate limiter is dropped.
+    pub fn spawn_expiry(&self, interval: Duration) -> JoinHandle<()> {
+        let state: Weak<State> = Arc::downgrade(&self.state);
+        let overrides = self.overrides.clone();
+
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                let Some(state) = state.upgrade() else {
+                    break;
+                };
+                let now = SystemTime::now();
+                state.purge(now);
+                overrides.purge(now);
+                debug!(
+                    "Expired old in-memory windows, {} keys remain",
+                    state.windows.len()
+                );
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl<A: RateLimitAlgorithm + Send + Sync> RateLimitStore for MemoryRateLimit<A> {
+    async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(&config.resource_key);
+        let policy = resolved.policy;
+
+        let now = SystemTime::now();
+        if let Some(result) = list_decision(&resolved, now) {
+            return result;
+        }
+
+        let key = state_key(&resolved.counter_key, resolved.name);
+        if policy.penalty.is_some() {
+            let ban_until = self
+                .state
+                .penalties
+                .get(&key, |penalty| penalty.ban_until)
+                .flatten()
+                .filter(|ban_until| *ban_until > now);
+            if let Some(ban_until) = ban_until {
+                return Err(AcquireErr::RateLimitExceeded {
+                    reset_after: ban_until,
+                    policy: resolved.name.clone(),
+                    decided_by: DecisionSource::Penalty,
+                    counted_at: now,
+                });
+            }
+        }
+
+        // Like the Redis store, tokens are counted before the decision is made.
+        let index = window_index(now, policy.window_secs)?;
+        let (current, previous) = self.state.windows.update(
+            &key,
+            || Window {
+                window_secs: policy.window_secs,
+                index,
+                current: 0,
+                previous: 0,
+            },
+            |window| {
+                window.advance(policy.window_secs, index);
+                let counts = (window.current, window.previous);
+                window.current = window.current.saturating_add(config.tokens_to_acquire);
+                counts
+            },
+        );
+
+        let (max_tokens, decided_by) = match self.overrides.active(&config.resource_key, now) {
+            Some(max_tokens) => (max_tokens, DecisionSource::Override),
+            None => (policy.max_tokens, DecisionSource::Policy),
+        };
+        let max_tokens = (max_tokens / self.instances).max(1);
+
+        let attempt = AcquireAttempt::new(
+            config.tokens_to_acquire,
+            max_tokens,
+            Duration::from_secs(policy.window_secs),
+            previous,
+            current,
+        );
+
+        match self.algorithm.try_acquire(&attempt) {
+            Ok((remaining, reset_after)) => Ok(TokensRemaining::new(
+                remaining,
+                reset_after,
+                resolved.name.clone(),
+                decided_by,
+                now,
+            )),
+            Err(RateLimitAlgorithmErr::RateLimitExceeded(reset_after)) => {
+                let ban_until = policy.penalty.as_ref().and_then(|penalty| {
+                    self.state
+                        .penalties
+                        .update(&key, PenaltyState::default, |state| {
+                            state.deny(penalty, now)
+                        })
+                });
+
+                let (reset_after, decided_by) = match ban_until {
+                    Some(ban_until) => {
+                        info!(
+                            "Banned key '{}' under policy '{}' until {:?}",
+                            config.resource_key, resolved.name, ban_until
+                        );
+                        (ban_until, DecisionSource::Penalty)
+                    }
+                    None => (reset_after, decided_by),
+                };
+
+                Err(AcquireErr::RateLimitExceeded {
+                    reset_after,
+                    policy: resolved.name.clone(),
+                    decided_by,
+                    counted_at: now,
+                })
+            }
+        }
+    }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        let index = window_index(SystemTime::now(), window_secs)?;
+        let (current, previous) = self
+            .state
+            .windows
+            .get(&state_key(counter_key, policy), |window| {
+                window.at(window_secs, index)
+            })
+            .unwrap_or((0, 0));
+
+        Ok(WindowCounters {
+            window_start: SystemTime::UNIX_EPOCH
+                + Duration::from_millis((index * window_secs as u128 * 1000) as u64),
+            current,
+            previous,
+        })
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        _window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        let key = state_key(counter_key, policy);
+        self.state.windows.remove(&key);
+        self.state.penalties.remove(&key);
+        Ok(())
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(&config.resource_key);
+        if resolved.action != RuleAction::Limit {
+            return Ok(());
+        }
+
+        let index = window_index(counted_at, resolved.policy.window_secs)?;
+        let key = state_key(&resolved.counter_key, resolved.name);
+        self.state.windows.modify(&key, |window| {
+            if window.index == index {
+                window.current = window.current.saturating_sub(config.tokens_to_acquire);
+            } else if window.index == index + 1 {
+                window.previous = window.previous.saturating_sub(config.tokens_to_acquire);
+            }
+        });
+        Ok(())
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    use crate::{
+        common::{SlidingWindow, SystemClock},
+        config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule},
+        db::{KeyOverride, OverrideStore},
+    };
+
+    fn rule(name: &str, pattern: &str, policy: PolicyDefinition, action: RuleAction) -> PolicyRule {
+        PolicyRule {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy,
+            priority: 0,
+            action,
+        }
+    }
+
+    fn store() -> MemoryRateLimit<SlidingWindow<SystemClock>> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let rules = vec![
+            rule(
+                "login",
+                "login:",
+                PolicyDefinition {
+                    max_tokens: 1,
+                    window_secs: 60,
+                    penalty: Some(PenaltyConfig {
+                        denials: 2,
+                        within_secs: 60,
+                        ban_secs: 600,
+                        multiplier: 2,
+                        max_ban_secs: 3600,
+                    }),
+                    ..Default::default()
+                },
+                RuleAction::Limit,
+            ),
+            rule(
+                "monitors",
+                "monitor.",
+                PolicyDefinition::default(),
+                RuleAction::Allow,
+            ),
+        ];
+
+        MemoryRateLimit::new(
+            Arc::new(Policies::new(default_policy, rules).unwrap()),
+            SlidingWindow::new(),
+        )
+    }
+
+    async fn acquire(
+        store: &mut MemoryRateLimit<SlidingWindow<SystemClock>>,
+        key: &str,
+        tokens: u32,
+    ) -> AcquireResult {
+        store
+            .acquire(&RateLimitConfig::new(key.to_string(), tokens))
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_limits_per_key() {
+        let mut store = store();
+
+        assert_eq!(acquire(&mut store, "a", 4).await.unwrap().remaining, 6);
+        assert_eq!(acquire(&mut store, "a", 6).await.unwrap().remaining, 0);
+        assert!(acquire(&mut store, "a", 1).await.is_err());
+        assert!(acquire(&mut store, "b", 10).await.is_ok());
+
+        let counters = store.counters("a", "default", 60).await.unwrap();
+        assert_eq!(counters.current, 11);
+
+        store
+            .release(&RateLimitConfig::new("a".to_string(), 5), SystemTime::now())
+            .await
+            .unwrap();
+        let counters = store.counters("a", "default", 60).await.unwrap();
+        assert_eq!(counters.current, 6);
+    }
+
+    #[tokio::test]
+    async fn test_policies_sharing_a_bucket() {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let shared = |name: &str, pattern: &str| PolicyRule {
+            bucket: Some("shared".to_string()),
+            ..rule(
+                name,
+                pattern,
+                PolicyDefinition {
+                    max_tokens: 2,
+                    window_secs: 60,
+                    ..Default::default()
+                },
+                RuleAction::Limit,
+            )
+        };
+        let rules = vec![shared("reads", "read:"), shared("writes", "write:")];
+        let mut store = MemoryRateLimit::new(
+            Arc::new(Policies::new(default_policy, rules).unwrap()),
+            SlidingWindow::new(),
+        );
+
+        acquire(&mut store, "read:1", 2).await.unwrap();
+        assert!(acquire(&mut store, "read:2", 1).await.is_err());
+        assert_eq!(
+            acquire(&mut store, "write:1", 2).await.unwrap().remaining,
+            0
+        );
+
+        let counters = store.counters("shared", "reads", 60).await.unwrap();
+        assert_eq!(counters.current, 3);
+        let counters = store.counters("shared", "writes", 60).await.unwrap();
+        assert_eq!(counters.current, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reset() {
+        let mut store = store();
+
+        acquire(&mut store, "a", 10).await.unwrap();
+        assert!(acquire(&mut store, "a", 1).await.is_err());
+
+        store.reset("a", "default", 60).await.unwrap();
+        assert_eq!(store.counters("a", "default", 60).await.unwrap().current, 0);
+        assert_eq!(acquire(&mut store, "a", 1).await.unwrap().remaining, 9);
+    }
+
+    #[tokio::test]
+    async fn test_override() {
+        let mut store = store();
+        store
+            .override_store()
+            .set(&KeyOverride {
+                key: "a".to_string(),
+                max_tokens: 100,
+                expires_at: SystemTime::now() + Duration::from_secs(60),
+            })
+            .await
+            .unwrap();
+
+        let result = acquire(&mut store, "a", 50).await.unwrap();
+        assert_eq!(result.remaining, 50);
+        assert_eq!(result.decided_by, DecisionSource::Override);
+    }
+
+    #[tokio::test]
+    async fn test_allow_list() {
+        let mut store = store();
+
+        let result = acquire(&mut store, "monitor.synthetic", 1000)
+            .await
+            .unwrap();
+        assert_eq!(result.decided_by, DecisionSource::AllowList);
+        assert_eq!(store.state.windows.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_penalty() {
+        let mut store = store();
+
+        assert!(acquire(&mut store, "login:alice", 1).await.is_ok());
+        assert!(matches!(
+            acquire(&mut store, "login:alice", 1).await,
+            Err(AcquireErr::RateLimitExceeded {
+                decided_by: DecisionSource::Policy,
+                ..
+            })
+        ));
+
+        let Err(AcquireErr::RateLimitExceeded {
+            reset_after,
+            decided_by: DecisionSource::Penalty,
+            ..
+        }) = acquire(&mut store, "login:alice", 1).await
+        else {
+            panic!("second denial should ban the key");
+        };
+        assert!(reset_after >= SystemTime::now() + Duration::from_secs(599));
+
+        assert!(matches!(
+            acquire(&mut store, "login:alice", 1).await,
+            Err(AcquireErr::RateLimitExceeded {
+                decided_by: DecisionSource::Penalty,
+                ..
+            })
+        ));
+    }
+
+    #[rstest]
+    #[case(0, 2)]
+    #[case(1, 1)]
+    #[case(2, 1)]
+    fn test_penalty_escalation(#[case] strikes: u32, #[case] denials: u32) {
+        let penalty = PenaltyConfig {
+            denials,
+            within_secs: 60,
+            ban_secs: 600,
+            multiplier: 3,
+            max_ban_secs: 3600,
+        };
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut state = PenaltyState {
+            strikes,
+            strikes_until: Some(now + Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        let mut ban_until = None;
+        for _ in 0..denials {
+            ban_until = state.deny(&penalty, now);
+        }
+
+        let expected = [600, 1800, 3600][strikes as usize];
+        assert_eq!(ban_until, Some(now + Duration::from_secs(expected)));
+    }
+
+    #[test]
+    fn test_window_advance() {
+        let mut window = Window {
+            window_secs: 60,
+            index: 10,
+            current: 5,
+            previous: 3,
+        };
+
+        assert_eq!(window.at(60, 10), (5, 3));
+        assert_eq!(window.at(60, 11), (0, 5));
+        assert_eq!(window.at(60, 12), (0, 0));
+        assert_eq!(window.at(30, 10), (0, 0));
+
+        window.advance(60, 11);
+        assert_eq!((window.index, window.current, window.previous), (11, 0, 5));
+    }
+
+    #[test]
+    fn test_purge() {
+        let store = store();
+        let now = SystemTime::now();
+        let index = window_index(now, 60).unwrap();
+
+        for (key, window_index) in [
+            ("current", index),
+            ("previous", index - 1),
+            ("old", index - 2),
+        ] {
+            store.state.windows.insert(
+                key.to_string(),
+                Window {
+                    window_secs: 60,
+                    index: window_index,
+                    current: 1,
+                    previous: 0,
+                },
+            );
+        }
+        store.state.purge(now);
+
+        let mut keys = store.state.windows.collect(|key, _| Some(key.to_string()));
+        keys.sort();
+        assert_eq!(keys, vec!["current", "previous"]);
+    }
+}
@@ -1,5 +1,28 @@
+mod audit;
+mod coalesce;
+mod decisions;
+mod events;
+mod fallback;
+mod lease;
+mod memory;
+mod observer;
+mod overrides;
+mod policies;
 mod rate;
 mod redis;
+mod sharded;
+mod top_keys;
 
+pub use audit::*;
+pub use coalesce::*;
+pub use decisions::*;
+pub use events::*;
+pub use fallback::*;
+pub use lease::*;
+pub use memory::*;
+pub use observer::*;
+pub use overrides::*;
+pub use policies::*;
 pub use rate::*;
 pub use redis::*;
+pub use top_keys::*;
@@ -0,0 +1,245 @@
+use std::{sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+
+use crate::{
+    common::to_unix_millis,
+    config::AuditLevel,
+    db::{
+        AcquireErr, AcquireResult, AuditEvent, AuditLog, Decision, DecisionEvent, DecisionFeed,
+        EventPublisher, RateLimitConfig, RateLimitStore, TopKeys, WindowCounters,
+    },
+    policy::Policies,
+};
+
+/// Tells the decisions of `inner` to whoever observes them: the heavy hitter
+/// tracker, watchers, the audit log and the event stream. Each is optional.
+/// The policy of a key is resolved once per decision, and only when the audit
+/// log or the event stream need its settings. Store errors carry no decision
+/// and are left out.
+#[derive(Debug, Clone)]
+pub struct ObservedRateLimit<R: RateLimitStore> {
+    inner: R,
+    policies: Arc<Policies>,
+    top_keys: Option<TopKeys>,
+    decisions: Option<DecisionFeed>,
+    audit: AuditLog,
+    events: EventPublisher,
+}
+
+impl<R: RateLimitStore> ObservedRateLimit<R> {
+    pub fn new(inner: R, policies: Arc<Policies>) -> Self {
+        ObservedRateLimit {
+            inner,
+            policies,
+            top_keys: None,
+            decisions: None,
+            audit: AuditLog::disabled(),
+            events: EventPublisher::disabled(),
+        }
+    }
+
+    /// Counts every decision in `top_keys`.
+    pub fn with_top_keys(mut self, top_keys: TopKeys) -> Self {
+        self.top_keys = Some(top_keys);
+        self
+    }
+
+    /// Shows every decision to the watchers of `decisions`.
+    pub fn with_decisions(mut self, decisions: DecisionFeed) -> Self {
+        self.decisions = Some(decisions);
+        self
+    }
+
+    /// Writes the decisions of policies with an audit level to `audit`.
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = audit;
+        self
+    }
+
+    /// Publishes the decisions selected by the `events` of their policies.
+    pub fn with_events(mut self, events: EventPublisher) -> Self {
+        self.events = events;
+        self
+    }
+}
+
+#[async_trait]
+impl<R: RateLimitStore + Send + Sync> RateLimitStore for ObservedRateLimit<R> {
+    async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
+        let result = self.inner.acquire(config).await;
+
+        let (policy, allowed, remaining, source) = match &result {
+            Ok(granted) => (
+                granted.policy.as_ref(),
+                true,
+                granted.remaining,
+                granted.decided_by,
+            ),
+            Err(AcquireErr::RateLimitExceeded {
+                policy, decided_by, ..
+            }) => (policy.as_ref(), false, 0, *decided_by),
+            Err(_) => return result,
+        };
+        let key = &config.resource_key;
+        let time = to_unix_millis(SystemTime::now()) as u64;
+
+        if let Some(top_keys) = &self.top_keys {
+            top_keys.record(key, policy, !allowed);
+        }
+
+        if let Some(decisions) = &self.decisions {
+            decisions.record(|| Decision {
+                key: key.clone(),
+                policy: policy.to_string(),
+                allowed,
+                remaining,
+                time,
+            });
+        }
+
+        if !self.audit.is_enabled() && !self.events.is_enabled() {
+            return result;
+        }
+
+        let (audit, events) = {
+            let matcher = self.policies.matcher();
+            let resolved = matcher.resolve(key);
+            (resolved.policy.audit, resolved.policy.events)
+        };
+
+        let audited = match audit {
+            Some(AuditLevel::All) => true,
+            Some(_) => !allowed,
+            None => false,
+        };
+        if audited {
+            self.audit.record(AuditEvent::Decision {
+                key: key.clone(),
+                policy: policy.to_string(),
+                allowed,
+                remaining,
+                source: format!("{:?}", source),
+            });
+        }
+
+        if self.events.selects(events, allowed) {
+            self.events.publish(DecisionEvent {
+                key: key.clone(),
+                policy: policy.to_string(),
+                allowed,
+                remaining,
+                source: format!("{:?}", source),
+                time,
+            });
+        }
+
+        result
+    }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        self.inner.counters(counter_key, policy, window_secs).await
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        self.inner.reset(counter_key, policy, window_secs).await
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        self.inner.release(config, counted_at).await
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        self.inner.is_healthy().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::SlidingWindow,
+        config::{DefaultPolicy, PolicyDefinition, TopKeysConfig},
+        db::{MemoryRateLimit, MemoryTopKeysHistory, fallback::tests::Unreachable},
+    };
+
+    fn policies() -> Arc<Policies> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        Arc::new(Policies::new(default_policy, vec![]).unwrap())
+    }
+
+    fn top_keys() -> TopKeys {
+        TopKeys::new(
+            MemoryTopKeysHistory::new(10, 3600),
+            TopKeysConfig::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_tells_every_observer() {
+        let policies = policies();
+        let (top_keys, decisions) = (top_keys(), DecisionFeed::local());
+        let mut store = ObservedRateLimit::new(
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+            policies,
+        )
+        .with_top_keys(top_keys.clone())
+        .with_decisions(decisions.clone());
+
+        let mut watcher = decisions.subscribe();
+        for _ in 0..2 {
+            let _ = store
+                .acquire(&RateLimitConfig::new("user:1".to_string(), 1))
+                .await;
+        }
+
+        assert!(watcher.try_recv().unwrap().allowed);
+        assert!(!watcher.try_recv().unwrap().allowed);
+
+        top_keys.flush().await.unwrap();
+        let now = SystemTime::now();
+        let summary = top_keys.range(now, now).await.unwrap();
+        assert_eq!(summary.policies["default"].requests, 2);
+        assert_eq!(summary.policies["default"].denials, 1);
+    }
+
+    #[tokio::test]
+    async fn test_leaves_out_store_errors() {
+        let (top_keys, decisions) = (top_keys(), DecisionFeed::local());
+        let mut store = ObservedRateLimit::new(Unreachable, policies())
+            .with_top_keys(top_keys.clone())
+            .with_decisions(decisions.clone());
+
+        let mut watcher = decisions.subscribe();
+        let result = store
+            .acquire(&RateLimitConfig::new("user:1".to_string(), 1))
+            .await;
+
+        assert!(matches!(result, Err(AcquireErr::Timeout)));
+        assert!(watcher.try_recv().is_err());
+        top_keys.flush().await.unwrap();
+        let now = SystemTime::now();
+        assert!(top_keys.range(now, now).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::warn;
+use thiserror::Error;
+use tokio::time::sleep;
+
+use crate::db::MemoryOverrideStore;
+
+#[derive(Error, Debug)]
+pub enum OverrideStoreErr {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+
+    #[error("Timeout error")]
+    Timeout,
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Temporary limit for a single resource key. It replaces the `max_tokens` of
+/// the policy the key resolves to, while the window and counters stay the same.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOverride {
+    pub key: String,
+    pub max_tokens: u32,
+    pub expires_at: SystemTime,
+}
+
+impl KeyOverride {
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.expires_at > now
+    }
+}
+
+/// Storage for per-key overrides. Expired overrides are never returned.
+#[async_trait]
+pub trait OverrideStore {
+    /// Creates or replaces the override of a key.
+    async fn set(&mut self, key_override: &KeyOverride) -> Result<(), OverrideStoreErr>;
+
+    async fn get(&mut self, key: &str) -> Result<Option<KeyOverride>, OverrideStoreErr>;
+
+    async fn list(&mut self) -> Result<Vec<KeyOverride>, OverrideStoreErr>;
+
+    /// Removes the override of a key and returns whether there was one.
+    async fn delete(&mut self, key: &str) -> Result<bool, OverrideStoreErr>;
+}
+
+/// Replaces the overrides in `cache` with the active ones in `store`.
+pub async fn reload_overrides<O: OverrideStore + Send>(
+    store: &mut O,
+    cache: &MemoryOverrideStore,
+) -> Result<(), OverrideStoreErr> {
+    cache.replace(store.list().await?);
+    Ok(())
+}
+
+/// Reloads the overrides of `store` into `cache` every `interval`. While
+/// `store` cannot be reached, `cache` keeps the overrides it last saw.
+pub async fn poll_overrides<O: OverrideStore + Send>(
+    mut store: O,
+    cache: MemoryOverrideStore,
+    interval: Duration,
+) {
+    loop {
+        if let Err(e) = reload_overrides(&mut store, &cache).await {
+            warn!("Failed to reload overrides: {}", e);
+        }
+        sleep(interval).await;
+    }
+}
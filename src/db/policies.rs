@@ -0,0 +1,60 @@
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::{
+    config::PolicyRule,
+    policy::{Policies, StoredPolicy},
+};
+
+#[derive(Error, Debug)]
+pub enum PolicyStoreErr {
+    #[error("Policy {name:?} is at version {current_version}, not the expected version")]
+    Conflict { name: String, current_version: u64 },
+
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+
+    #[error("Timeout error")]
+    Timeout,
+
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+}
+
+/// Storage for policy rules managed at runtime.
+///
+/// Each rule carries a version, and writes only succeed when the caller passes
+/// the version it last read, so concurrent edits cannot silently overwrite each
+/// other. Version 0 means the rule does not exist.
+#[async_trait]
+pub trait PolicyStore {
+    /// Version of the whole rule set, which changes on every write.
+    async fn version(&mut self) -> Result<u64, PolicyStoreErr>;
+
+    /// Reads all rules together with the version of the rule set.
+    async fn list(&mut self) -> Result<(u64, Vec<StoredPolicy>), PolicyStoreErr>;
+
+    /// Creates or replaces a rule and returns its new version.
+    async fn put(
+        &mut self,
+        rule: &PolicyRule,
+        expected_version: u64,
+    ) -> Result<u64, PolicyStoreErr>;
+
+    async fn delete(&mut self, name: &str, expected_version: u64) -> Result<(), PolicyStoreErr>;
+}
+
+/// Applies the stored rules if they changed since they were last applied.
+/// Returns whether anything was reloaded.
+pub async fn reload_policies<P: PolicyStore + Send>(
+    store: &mut P,
+    policies: &Policies,
+) -> Result<bool, PolicyStoreErr> {
+    if store.version().await? == policies.version() {
+        return Ok(false);
+    }
+
+    let (version, stored) = store.list().await?;
+    policies.apply(version, stored);
+    Ok(true)
+}
@@ -1,40 +1,148 @@
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
 use async_trait::async_trait;
 use thiserror::Error;
 
+use crate::{common::to_unix_millis, config::RuleAction, policy::ResolvedPolicy};
+
 #[derive(Error, Debug)]
 pub enum AcquireErr {
-    #[error("Rate limit exceeded. Reset after {0:?}")]
-    RateLimitExceeded(SystemTime),
+    #[error("Rate limit exceeded by policy {policy:?}. Reset after {reset_after:?}")]
+    RateLimitExceeded {
+        reset_after: SystemTime,
+        policy: Arc<str>,
+        decided_by: DecisionSource,
+
+        /// As in [`TokensRemaining`], denied tokens are counted too.
+        counted_at: SystemTime,
+    },
 
     #[error("Redis error: {0}")]
     RedisError(#[from] redis::RedisError),
 
     #[error("Timeout error")]
     Timeout,
+
+    #[error("Window of zero seconds")]
+    ZeroWindow,
+}
+
+/// What the limit of a decision came from.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DecisionSource {
+    /// The limit of the matching policy.
+    #[default]
+    Policy,
+
+    /// A per-key override of the policy limit.
+    Override,
+
+    /// An allow rule, without counting the request.
+    AllowList,
+
+    /// A deny rule, without counting the request.
+    DenyList,
+
+    /// A temporary ban after repeated denials.
+    Penalty,
+
+    /// The store was unreachable and the request was allowed.
+    FailOpen,
+
+    /// The store was unreachable and the request was denied.
+    FailClosed,
+
+    /// The store was unreachable and the policy was applied by this instance
+    /// alone.
+    LocalFallback,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+impl DecisionSource {
+    /// Whether the decision was made without the store.
+    pub fn is_degraded(self) -> bool {
+        matches!(
+            self,
+            DecisionSource::FailOpen | DecisionSource::FailClosed | DecisionSource::LocalFallback
+        )
+    }
+}
+
+/// Remaining tokens reported for keys that are never limited.
+pub const UNLIMITED_TOKENS: u32 = i32::MAX as u32;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct TokensRemaining {
     pub remaining: u32,
     pub reset_after: SystemTime,
+    pub policy: Arc<str>,
+    pub decided_by: DecisionSource,
+
+    /// When the tokens were counted, by the time source of the store. Tokens
+    /// given back with [`RateLimitStore::release`] go to the window of this
+    /// time.
+    pub counted_at: SystemTime,
 }
 
 pub type AcquireResult = Result<TokensRemaining, AcquireErr>;
 
 impl TokensRemaining {
-    pub fn new(remaining: u32, reset_after: SystemTime) -> Self {
+    pub fn new(
+        remaining: u32,
+        reset_after: SystemTime,
+        policy: Arc<str>,
+        decided_by: DecisionSource,
+        counted_at: SystemTime,
+    ) -> Self {
         TokensRemaining {
             remaining,
             reset_after,
+            policy,
+            decided_by,
+            counted_at,
         }
     }
 }
 
+/// Requests counted in the current and previous window of a counter key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct WindowCounters {
+    pub window_start: SystemTime,
+    pub current: u32,
+    pub previous: u32,
+}
+
 #[async_trait]
 pub trait RateLimitStore {
     async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult;
+
+    /// Reads the counters a policy keeps for a counter key without acquiring
+    /// tokens.
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr>;
+
+    /// Forgets the window counters and penalties a policy keeps for a counter
+    /// key.
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr>;
+
+    /// Gives back tokens counted at `counted_at` that were never used, such as
+    /// the rest of an expired lease.
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr>;
+
+    /// Whether the backend can currently serve requests.
+    async fn is_healthy(&mut self) -> bool;
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Default, Hash)]
@@ -51,3 +159,42 @@ impl RateLimitConfig {
         }
     }
 }
+
+/// Index of the window containing `now` for a window of `window_secs`.
+/// Allow and deny rules have no window, so a zero window is refused.
+pub(in crate::db) fn window_index(now: SystemTime, window_secs: u64) -> Result<u128, AcquireErr> {
+    if window_secs == 0 {
+        return Err(AcquireErr::ZeroWindow);
+    }
+    Ok(to_unix_millis(now) / (window_secs as u128 * 1000))
+}
+
+/// Key of the state a policy keeps for a counter key. It includes the policy,
+/// so that policies sharing a counter key count separately.
+pub(in crate::db) fn state_key(counter_key: &str, policy: &str) -> String {
+    format!("{}:{}:{}", policy.len(), policy, counter_key)
+}
+
+/// Decides keys matched by allow or deny rules, which are never counted.
+/// Returns `None` for keys that are limited.
+pub(in crate::db) fn list_decision(
+    resolved: &ResolvedPolicy,
+    now: SystemTime,
+) -> Option<AcquireResult> {
+    match resolved.action {
+        RuleAction::Limit => None,
+        RuleAction::Allow => Some(Ok(TokensRemaining::new(
+            UNLIMITED_TOKENS,
+            now,
+            resolved.name.clone(),
+            DecisionSource::AllowList,
+            now,
+        ))),
+        RuleAction::Deny => Some(Err(AcquireErr::RateLimitExceeded {
+            reset_after: now,
+            policy: resolved.name.clone(),
+            decided_by: DecisionSource::DenyList,
+            counted_at: now,
+        })),
+    }
+}
@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use redis::aio::{ConnectionLike, PubSubStream};
+use tokio::{sync::mpsc, time::sleep};
+use tokio_stream::StreamExt;
+
+use crate::db::{Decision, DecisionFeed};
+
+use super::{PubSubClient, keys::KeySpace};
+
+/// Decisions queued for publishing before new ones are dropped.
+const OUTBOX_CAPACITY: usize = 4096;
+
+/// Most decisions published in one message.
+const PUBLISH_BATCH: usize = 256;
+
+/// How often instances announce their watchers and check for others'.
+const WATCHERS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an announcement lasts, a few intervals so that one slow refresh
+/// does not stop the feed.
+const WATCHERS_TTL: Duration = Duration::from_secs(3);
+
+/// How long to wait before subscribing again after losing the subscription.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Relays decisions between instances through Redis pub/sub. Instances only
+/// publish while some instance announces watchers, so an unwatched feed costs
+/// one GET per instance and second.
+#[derive(Debug, Clone)]
+pub struct RedisDecisionRelay<C: ConnectionLike, P: PubSubClient> {
+    client: P,
+    conn: C,
+    timeout: Duration,
+    keys: KeySpace,
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync + 'static, P: PubSubClient> RedisDecisionRelay<C, P> {
+    /// Publishes on `conn` and subscribes with `client`.
+    pub fn new(client: P, conn: C, timeout: Duration) -> Self {
+        RedisDecisionRelay {
+            client,
+            conn,
+            timeout,
+            keys: KeySpace::default(),
+        }
+    }
+
+    /// Relays decisions under `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+
+    /// Starts relaying and returns the feed to record decisions in.
+    pub fn spawn(self) -> DecisionFeed {
+        let (feed, outbox) = DecisionFeed::relayed(OUTBOX_CAPACITY);
+
+        tokio::spawn(self.clone().publish(feed.clone(), outbox));
+        tokio::spawn(self.clone().announce(feed.clone()));
+        tokio::spawn(self.subscribe(feed.clone()));
+
+        feed
+    }
+
+    async fn publish(self, feed: DecisionFeed, mut outbox: mpsc::Receiver<Decision>) {
+        let channel = self.keys.decision_channel();
+        let mut batch = Vec::with_capacity(PUBLISH_BATCH);
+
+        while outbox.recv_many(&mut batch, PUBLISH_BATCH).await > 0 {
+            let payload = serde_json::to_string(&batch).unwrap();
+            batch.clear();
+
+            let mut conn = self.conn.clone();
+            let published = tokio::time::timeout(
+                self.timeout,
+                redis::cmd("PUBLISH")
+                    .arg(&channel)
+                    .arg(payload)
+                    .query_async::<()>(&mut conn),
+            )
+            .await;
+            match published {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Failed to publish decisions: {}", e),
+                Err(_) => warn!("Failed to publish decisions: timeout"),
+            }
+
+            let dropped = feed.take_dropped();
+            if dropped > 0 {
+                warn!("Dropped {} decisions, publishing fell behind", dropped);
+            }
+        }
+    }
+
+    async fn announce(self, feed: DecisionFeed) {
+        let key = self.keys.decision_watchers();
+
+        loop {
+            let mut conn = self.conn.clone();
+            let announced = async {
+                if feed.has_watchers() {
+                    redis::cmd("SET")
+                        .arg(&key)
+                        .arg(1)
+                        .arg("PX")
+                        .arg(WATCHERS_TTL.as_millis() as u64)
+                        .query_async::<()>(&mut conn)
+                        .await?;
+                }
+                redis::cmd("EXISTS")
+                    .arg(&key)
+                    .query_async::<bool>(&mut conn)
+                    .await
+            };
+
+            match tokio::time::timeout(self.timeout, announced).await {
+                Ok(Ok(watched)) => feed.set_watched(watched),
+                Ok(Err(e)) => {
+                    debug!("Failed to check for decision watchers: {}", e);
+                    feed.set_watched(false);
+                }
+                Err(_) => feed.set_watched(false),
+            }
+
+            sleep(WATCHERS_INTERVAL).await;
+        }
+    }
+
+    async fn subscribe(self, feed: DecisionFeed) {
+        let channel = self.keys.decision_channel();
+
+        loop {
+            if let Some(mut messages) = subscribe(&self.client, &channel).await {
+                while let Some(message) = messages.next().await {
+                    let decisions = message
+                        .get_payload::<String>()
+                        .map_err(|e| e.to_string())
+                        .and_then(|payload| {
+                            serde_json::from_str::<Vec<Decision>>(&payload)
+                                .map_err(|e| e.to_string())
+                        });
+                    match decisions {
+                        Ok(decisions) => decisions
+                            .into_iter()
+                            .for_each(|decision| feed.deliver(decision)),
+                        Err(e) => warn!("Ignoring invalid decisions message: {}", e),
+                    }
+                }
+                warn!("Lost the decisions subscription, subscribing again");
+            }
+
+            sleep(RESUBSCRIBE_DELAY).await;
+        }
+    }
+}
+
+async fn subscribe(client: &impl PubSubClient, channel: &str) -> Option<PubSubStream> {
+    let result = async {
+        let mut pubsub = client.pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok::<_, redis::RedisError>(pubsub.into_on_message())
+    }
+    .await;
+
+    match result {
+        Ok(messages) => {
+            info!("Subscribed to decisions on '{}'", channel);
+            Some(messages)
+        }
+        Err(e) => {
+            warn!("Failed to subscribe to decisions: {}", e);
+            None
+        }
+    }
+}
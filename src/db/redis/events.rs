@@ -0,0 +1,104 @@
+use std::time::Duration;
+
+use log::warn;
+use redis::aio::ConnectionLike;
+use tokio::sync::mpsc;
+
+use crate::{
+    config::EventsConfig,
+    db::{DecisionEvent, EventPublisher},
+};
+
+use super::keys::KeySpace;
+
+/// Writes decision events to a Redis Stream, in pipelined batches of `XADD`s
+/// trimmed to about `max_len` entries. Consumers read the stream on their own,
+/// typically with consumer groups.
+#[derive(Debug, Clone)]
+pub struct RedisEventWriter<C: ConnectionLike> {
+    conn: C,
+    timeout: Duration,
+    keys: KeySpace,
+}
+
+impl<C: ConnectionLike + Clone + Send + Sync + 'static> RedisEventWriter<C> {
+    pub fn new(conn: C, timeout: Duration) -> Self {
+        RedisEventWriter {
+            conn,
+            timeout,
+            keys: KeySpace::default(),
+        }
+    }
+
+    /// Writes to the stream of `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+
+    /// Starts writing and returns the publisher to publish events with.
+    pub fn spawn(self, config: &EventsConfig) -> EventPublisher {
+        let (publisher, queued) = EventPublisher::queued(config.queue_size, config.defaults);
+        let stream = config.stream.clone().unwrap_or_else(|| self.keys.events());
+
+        tokio::spawn(self.write(
+            publisher.clone(),
+            queued,
+            stream,
+            config.max_len,
+            config.batch_size,
+        ));
+
+        publisher
+    }
+
+    async fn write(
+        self,
+        publisher: EventPublisher,
+        mut queued: mpsc::Receiver<DecisionEvent>,
+        stream: String,
+        max_len: u64,
+        batch_size: usize,
+    ) {
+        let mut batch = Vec::with_capacity(batch_size);
+
+        while queued.recv_many(&mut batch, batch_size).await > 0 {
+            let mut pipe = redis::pipe();
+            for event in batch.drain(..) {
+                pipe.cmd("XADD")
+                    .arg(&stream)
+                    .arg("MAXLEN")
+                    .arg("~")
+                    .arg(max_len)
+                    .arg("*")
+                    .arg("key")
+                    .arg(event.key)
+                    .arg("policy")
+                    .arg(event.policy)
+                    .arg("allowed")
+                    .arg(u8::from(event.allowed))
+                    .arg("remaining")
+                    .arg(event.remaining)
+                    .arg("source")
+                    .arg(event.source)
+                    .arg("time")
+                    .arg(event.time)
+                    .ignore();
+            }
+
+            let mut conn = self.conn.clone();
+            let written =
+                tokio::time::timeout(self.timeout, pipe.query_async::<()>(&mut conn)).await;
+            match written {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Failed to write decision events: {}", e),
+                Err(_) => warn!("Failed to write decision events: timeout"),
+            }
+
+            let dropped = publisher.take_dropped();
+            if dropped > 0 {
+                warn!("Dropped {} decision events, writing fell behind", dropped);
+            }
+        }
+    }
+}
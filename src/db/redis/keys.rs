@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use sha2::{Digest, Sha256};
+
+/// Parts longer than this are replaced by their SHA-256, to keep keys short.
+const MAX_RAW_LEN: usize = 128;
+
+/// Namespace used when none is configured.
+pub(super) const DEFAULT_NAMESPACE: &str = "break_check";
+
+/// Encodes a user-supplied part of a key so that no two parts encode the same
+/// and no part can be mistaken for the rest of a key: short parts are
+/// prefixed with their length, and long ones are hashed.
+fn encode(part: &str) -> String {
+    if part.len() <= MAX_RAW_LEN {
+        format!("{}:{}", part.len(), part)
+    } else {
+        format!("#{:x}", Sha256::digest(part))
+    }
+}
+
+/// Names of every Redis key and channel of one deployment, all starting with
+/// its namespace so that several deployments can share a Redis.
+///
+/// Keys of a counter key are hash-tagged with it, so that on Redis Cluster they
+/// all live in the same slot and can be used by one script.
+#[derive(Debug, Clone)]
+pub(super) struct KeySpace {
+    namespace: Arc<str>,
+}
+
+impl Default for KeySpace {
+    fn default() -> Self {
+        KeySpace::new(DEFAULT_NAMESPACE)
+    }
+}
+
+impl KeySpace {
+    pub(super) fn new(namespace: &str) -> Self {
+        KeySpace {
+            namespace: namespace.into(),
+        }
+    }
+
+    /// Counter of window `index` of a counter key. The policy and the window
+    /// size are part of the key, so that a changed policy never reads counters
+    /// written under another window size.
+    pub(super) fn window(
+        &self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+        index: u128,
+    ) -> String {
+        format!(
+            "{}:window:{{{}}}:{}:{}:{}",
+            self.namespace,
+            encode(counter_key),
+            encode(policy),
+            window_secs,
+            index
+        )
+    }
+
+    /// Penalty state `kind` ("denials", "strikes" or "ban") of a counter key.
+    pub(super) fn penalty(&self, counter_key: &str, policy: &str, kind: &str) -> String {
+        format!(
+            "{}:penalty:{{{}}}:{}:{}",
+            self.namespace,
+            encode(counter_key),
+            encode(policy),
+            kind
+        )
+    }
+
+    /// Override of a resource key.
+    pub(super) fn key_override(&self, key: &str) -> String {
+        format!("{}:override:{}", self.namespace, key)
+    }
+
+    /// Sorted set of the resource keys with an override, scored by expiry.
+    pub(super) fn override_index(&self) -> String {
+        format!("{}:overrides", self.namespace)
+    }
+
+    pub(super) fn policy_rules(&self) -> String {
+        format!("{}:{{policies}}:rules", self.namespace)
+    }
+
+    pub(super) fn policy_versions(&self) -> String {
+        format!("{}:{{policies}}:versions", self.namespace)
+    }
+
+    pub(super) fn policy_set_version(&self) -> String {
+        format!("{}:{{policies}}:version", self.namespace)
+    }
+
+    /// Sorted set of heavy hitter traffic `kind` ("keys", "key_denials",
+    /// "policies" or "policy_denials") in the bucket starting at `bucket`.
+    /// The sets of a bucket share a slot, so that one pipeline can update them.
+    pub(super) fn top_keys(&self, bucket: u64, kind: &str) -> String {
+        format!("{}:top:{{{}}}:{}", self.namespace, bucket, kind)
+    }
+
+    /// Channel decisions are published on while someone watches them.
+    pub(super) fn decision_channel(&self) -> String {
+        format!("{}:decisions", self.namespace)
+    }
+
+    /// Set while some instance has decision watchers.
+    pub(super) fn decision_watchers(&self) -> String {
+        format!("{}:decisions:watched", self.namespace)
+    }
+
+    /// Stream decision events are published to.
+    pub(super) fn events(&self) -> String {
+        format!("{}:events", self.namespace)
+    }
+
+    /// Channel policy changes are published on.
+    pub(super) fn policy_channel(&self) -> String {
+        format!("{}:policies:changed", self.namespace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    /// Part of a key Redis Cluster hashes, as described in the cluster spec.
+    fn hash_tag(key: &str) -> &str {
+        key.find('{')
+            .and_then(|open| {
+                let close = key[open + 1..].find('}')? + open + 1;
+                Some(&key[open + 1..close]).filter(|tag| !tag.is_empty())
+            })
+            .unwrap_or(key)
+    }
+
+    #[rstest]
+    #[case("user:1")]
+    #[case("tenant:{42}")]
+    #[case("a}b")]
+    #[case(&"x".repeat(500))]
+    fn test_keys_share_slot(#[case] counter_key: &str) {
+        let keys = KeySpace::default();
+        let current_key = keys.window(counter_key, "users", 60, 7);
+        let tag = hash_tag(&current_key);
+
+        assert_eq!(hash_tag(&keys.window(counter_key, "users", 60, 6)), tag);
+        for kind in ["denials", "strikes", "ban"] {
+            assert_eq!(hash_tag(&keys.penalty(counter_key, "users", kind)), tag);
+        }
+    }
+
+    #[rstest]
+    #[case(("a:1:b", "c"), ("a", "1:b:c"))]
+    #[case(("a.rate_limit.window.1", "p"), ("a", "p"))]
+    #[case(("x", "y:60"), ("x:y", "60"))]
+    fn test_no_collisions(#[case] first: (&str, &str), #[case] second: (&str, &str)) {
+        let keys = KeySpace::default();
+
+        assert_ne!(
+            keys.window(first.0, first.1, 60, 1),
+            keys.window(second.0, second.1, 60, 1)
+        );
+    }
+
+    #[test]
+    fn test_long_keys_are_hashed() {
+        let keys = KeySpace::new("tenant-a");
+        let long = "k".repeat(MAX_RAW_LEN + 1);
+
+        let key = keys.window(&long, "users", 60, 1);
+        assert!(key.starts_with("tenant-a:window:{#"));
+        assert!(key.len() < 128);
+        assert_ne!(
+            key,
+            keys.window(&"k".repeat(MAX_RAW_LEN + 2), "users", 60, 1)
+        );
+    }
+
+    #[test]
+    fn test_window_size_in_key() {
+        let keys = KeySpace::default();
+
+        assert_ne!(
+            keys.window("user:1", "users", 60, 1),
+            keys.window("user:1", "users", 120, 1)
+        );
+    }
+}
@@ -1,3 +1,20 @@
+mod decisions;
+mod events;
+mod keys;
+mod overrides;
+mod policies;
+mod pubsub;
+mod sentinel;
+mod settings;
 mod store;
+mod top_keys;
 
+pub use decisions::*;
+pub use events::*;
+pub use overrides::*;
+pub use policies::*;
+pub use pubsub::*;
+pub use sentinel::*;
+pub use settings::*;
 pub use store::*;
+pub use top_keys::*;
@@ -0,0 +1,203 @@
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use log::warn;
+use redis::{AsyncCommands, aio::ConnectionLike};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    common::{from_unix_millis, to_unix_millis},
+    db::{KeyOverride, OverrideStore, OverrideStoreErr},
+};
+
+use super::keys::KeySpace;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredOverride {
+    max_tokens: u32,
+    expires_at: u64,
+}
+
+/// Decodes a stored override, skipping it when it has expired or is corrupt.
+pub(super) fn decode_override(key: &str, value: &str, now: SystemTime) -> Option<KeyOverride> {
+    let stored: StoredOverride = match serde_json::from_str(value) {
+        Ok(stored) => stored,
+        Err(e) => {
+            warn!("Ignoring invalid override for key '{}': {}", key, e);
+            return None;
+        }
+    };
+
+    Some(KeyOverride {
+        key: key.to_string(),
+        max_tokens: stored.max_tokens,
+        expires_at: from_unix_millis(stored.expires_at),
+    })
+    .filter(|key_override| key_override.is_active(now))
+}
+
+#[derive(Debug, Clone)]
+pub struct RedisOverrideStore<C: ConnectionLike> {
+    conn: C,
+    timeout: Duration,
+    keys: KeySpace,
+}
+
+impl<C: ConnectionLike> RedisOverrideStore<C> {
+    pub fn new(conn: C, timeout: Duration) -> Self {
+        RedisOverrideStore {
+            conn,
+            timeout,
+            keys: KeySpace::default(),
+        }
+    }
+
+    /// Keeps overrides under `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+}
+
+macro_rules! timeout {
+    ($duration:expr, $fut:expr) => {{
+        match tokio::time::timeout($duration, $fut).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(e)) => Err(OverrideStoreErr::RedisError(e)),
+            _ => Err(OverrideStoreErr::Timeout),
+        }
+    }};
+}
+
+#[async_trait]
+impl<C: ConnectionLike + Clone + Send + Sync> OverrideStore for RedisOverrideStore<C> {
+    async fn set(&mut self, key_override: &KeyOverride) -> Result<(), OverrideStoreErr> {
+        let encoded = serde_json::to_string(&StoredOverride {
+            max_tokens: key_override.max_tokens,
+            expires_at: to_unix_millis(key_override.expires_at) as u64,
+        })?;
+
+        // Redis drops the override once it expires, so nothing has to clean up.
+        let ttl = key_override
+            .expires_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+            .max(Duration::from_millis(1));
+
+        let expires_at = to_unix_millis(key_override.expires_at) as u64;
+        let mut conn = self.conn.clone();
+        timeout!(
+            self.timeout,
+            redis::cmd("SET")
+                .arg(self.keys.key_override(&key_override.key))
+                .arg(encoded)
+                .arg("PX")
+                .arg(ttl.as_millis() as u64)
+                .query_async::<()>(&mut conn)
+        )?;
+
+        let mut conn = self.conn.clone();
+        timeout!(
+            self.timeout,
+            redis::pipe()
+                .zadd(self.keys.override_index(), &key_override.key, expires_at)
+                .zrembyscore(
+                    self.keys.override_index(),
+                    "-inf",
+                    to_unix_millis(SystemTime::now()) as u64
+                )
+                .query_async::<()>(&mut conn)
+        )
+    }
+
+    async fn get(&mut self, key: &str) -> Result<Option<KeyOverride>, OverrideStoreErr> {
+        let mut conn = self.conn.clone();
+        let value: Option<String> = timeout!(
+            self.timeout,
+            redis::cmd("GET")
+                .arg(self.keys.key_override(key))
+                .query_async(&mut conn)
+        )?;
+
+        Ok(value.and_then(|value| decode_override(key, &value, SystemTime::now())))
+    }
+
+    async fn list(&mut self) -> Result<Vec<KeyOverride>, OverrideStoreErr> {
+        let now = SystemTime::now();
+        let mut conn = self.conn.clone();
+        let keys: Vec<String> = timeout!(
+            self.timeout,
+            conn.zrangebyscore(
+                self.keys.override_index(),
+                to_unix_millis(now) as u64,
+                "+inf"
+            )
+        )?;
+
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let redis_keys: Vec<String> = keys.iter().map(|key| self.keys.key_override(key)).collect();
+        let mut conn = self.conn.clone();
+        let values: Vec<Option<String>> = timeout!(
+            self.timeout,
+            redis::cmd("MGET").arg(&redis_keys).query_async(&mut conn)
+        )?;
+
+        let mut overrides: Vec<_> = keys
+            .iter()
+            .zip(values)
+            .filter_map(|(key, value)| decode_override(key, &value?, now))
+            .collect();
+        overrides.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(overrides)
+    }
+
+    async fn delete(&mut self, key: &str) -> Result<bool, OverrideStoreErr> {
+        let mut conn = self.conn.clone();
+        let deleted: u32 = timeout!(
+            self.timeout,
+            redis::cmd("DEL")
+                .arg(self.keys.key_override(key))
+                .query_async(&mut conn)
+        )?;
+
+        let mut conn = self.conn.clone();
+        timeout!(
+            self.timeout,
+            redis::cmd("ZREM")
+                .arg(self.keys.override_index())
+                .arg(key)
+                .query_async::<()>(&mut conn)
+        )?;
+
+        Ok(deleted > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_override() {
+        let now = from_unix_millis(1_000);
+
+        let active = decode_override("customer", r#"{"max_tokens":50,"expires_at":2000}"#, now);
+        assert_eq!(
+            active,
+            Some(KeyOverride {
+                key: "customer".to_string(),
+                max_tokens: 50,
+                expires_at: from_unix_millis(2_000),
+            })
+        );
+
+        let expired = decode_override("customer", r#"{"max_tokens":50,"expires_at":1000}"#, now);
+        assert_eq!(expired, None);
+
+        assert_eq!(decode_override("customer", "not json", now), None);
+    }
+}
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use redis::aio::{ConnectionLike, PubSubStream};
+use tokio::time::sleep;
+use tokio_stream::StreamExt;
+
+use crate::{
+    config::PolicyRule,
+    db::{PolicyStore, PolicyStoreErr, reload_policies},
+    policy::{Policies, StoredPolicy},
+};
+
+use super::{PubSubClient, keys::KeySpace};
+
+#[derive(Debug, Clone)]
+pub struct RedisPolicyStore<C: ConnectionLike> {
+    conn: C,
+    timeout: Duration,
+    keys: KeySpace,
+}
+
+impl<C: ConnectionLike> RedisPolicyStore<C> {
+    pub fn new(conn: C, timeout: Duration) -> Self {
+        RedisPolicyStore {
+            conn,
+            timeout,
+            keys: KeySpace::default(),
+        }
+    }
+
+    /// Keeps policies under `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+}
+
+macro_rules! timeout {
+    ($duration:expr, $fut:expr) => {{
+        match tokio::time::timeout($duration, $fut).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(e)) => Err(PolicyStoreErr::RedisError(e)),
+            _ => Err(PolicyStoreErr::Timeout),
+        }
+    }};
+}
+
+/// Writes (or deletes, when the rule is empty) a rule if its version matches.
+/// Rule versions are taken from the set version, so a deleted and recreated
+/// rule never reuses a version an operator may still hold.
+static WRITE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+    local rules = KEYS[1]
+    local versions = KEYS[2]
+    local set_version = KEYS[3]
+    local name = ARGV[1]
+    local expected = tonumber(ARGV[2])
+    local rule = ARGV[3]
+    local channel = ARGV[4]
+
+    local current = tonumber(redis.call('HGET', versions, name) or '0')
+    if current ~= expected then
+        return {0, current}
+    end
+
+    local version = redis.call('INCR', set_version)
+    if rule == '' then
+        redis.call('HDEL', rules, name)
+        redis.call('HDEL', versions, name)
+    else
+        redis.call('HSET', rules, name, rule)
+        redis.call('HSET', versions, name, version)
+    end
+
+    redis.call('PUBLISH', channel, version)
+    return {1, version}
+"#,
+    )
+});
+
+impl<C: ConnectionLike + Clone + Send> RedisPolicyStore<C> {
+    async fn write(
+        &mut self,
+        name: &str,
+        expected_version: u64,
+        rule: String,
+    ) -> Result<u64, PolicyStoreErr> {
+        let mut conn = self.conn.clone();
+        let (written, version): (u64, u64) = timeout!(
+            self.timeout,
+            WRITE_SCRIPT
+                .key(self.keys.policy_rules())
+                .key(self.keys.policy_versions())
+                .key(self.keys.policy_set_version())
+                .arg(name)
+                .arg(expected_version)
+                .arg(rule)
+                .arg(self.keys.policy_channel())
+                .invoke_async(&mut conn)
+        )?;
+
+        if written == 0 {
+            return Err(PolicyStoreErr::Conflict {
+                name: name.to_string(),
+                current_version: version,
+            });
+        }
+
+        Ok(version)
+    }
+}
+
+#[async_trait]
+impl<C: ConnectionLike + Clone + Send> PolicyStore for RedisPolicyStore<C> {
+    async fn version(&mut self) -> Result<u64, PolicyStoreErr> {
+        let mut conn = self.conn.clone();
+        let version: Option<u64> = timeout!(
+            self.timeout,
+            redis::cmd("GET")
+                .arg(self.keys.policy_set_version())
+                .query_async(&mut conn)
+        )?;
+
+        Ok(version.unwrap_or(0))
+    }
+
+    async fn list(&mut self) -> Result<(u64, Vec<StoredPolicy>), PolicyStoreErr> {
+        let mut conn = self.conn.clone();
+        let (version, rules, versions): (
+            Option<u64>,
+            HashMap<String, String>,
+            HashMap<String, u64>,
+        ) = timeout!(
+            self.timeout,
+            redis::pipe()
+                .atomic()
+                .get(self.keys.policy_set_version())
+                .hgetall(self.keys.policy_rules())
+                .hgetall(self.keys.policy_versions())
+                .query_async(&mut conn)
+        )?;
+
+        let stored = rules
+            .into_iter()
+            .map(|(name, rule)| {
+                Ok(StoredPolicy {
+                    version: versions.get(&name).copied().unwrap_or(0),
+                    rule: serde_json::from_str(&rule)?,
+                })
+            })
+            .collect::<Result<_, PolicyStoreErr>>()?;
+
+        Ok((version.unwrap_or(0), stored))
+    }
+
+    async fn put(
+        &mut self,
+        rule: &PolicyRule,
+        expected_version: u64,
+    ) -> Result<u64, PolicyStoreErr> {
+        let encoded = serde_json::to_string(rule)?;
+        self.write(&rule.name, expected_version, encoded).await
+    }
+
+    async fn delete(&mut self, name: &str, expected_version: u64) -> Result<(), PolicyStoreErr> {
+        self.write(name, expected_version, String::new()).await?;
+        Ok(())
+    }
+}
+
+async fn subscribe(client: &impl PubSubClient, channel: &str) -> Option<PubSubStream> {
+    let result = async {
+        let mut pubsub = client.pubsub().await?;
+        pubsub.subscribe(channel).await?;
+        Ok::<_, redis::RedisError>(pubsub.into_on_message())
+    }
+    .await;
+
+    match result {
+        Ok(messages) => {
+            info!("Subscribed to policy changes on '{}'", channel);
+            Some(messages)
+        }
+        Err(e) => {
+            warn!("Failed to subscribe to policy changes, polling only: {}", e);
+            None
+        }
+    }
+}
+
+/// Keeps `policies` in sync with the rules stored in Redis. Changes are picked
+/// up as soon as they are published, and the store is also polled every
+/// `poll_interval` in case a notification is lost.
+pub async fn watch_policies<C: ConnectionLike + Clone + Send>(
+    client: impl PubSubClient,
+    mut store: RedisPolicyStore<C>,
+    policies: Arc<Policies>,
+    poll_interval: Duration,
+) {
+    let channel = store.keys.policy_channel();
+    let mut messages = subscribe(&client, &channel).await;
+
+    loop {
+        let subscribed = match messages.as_mut() {
+            Some(stream) => tokio::select! {
+                message = stream.next() => message.is_some(),
+                _ = sleep(poll_interval) => true,
+            },
+            None => {
+                sleep(poll_interval).await;
+                false
+            }
+        };
+
+        match reload_policies(&mut store, &policies).await {
+            Ok(true) => info!("Applied policies version {}", policies.version()),
+            Ok(false) => debug!("Policies are up to date"),
+            Err(e) => warn!("Failed to reload policies: {}", e),
+        }
+
+        if !subscribed {
+            messages = subscribe(&client, &channel).await;
+        }
+    }
+}
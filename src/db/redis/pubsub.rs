@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use redis::{RedisResult, aio::PubSub};
+
+use super::SentinelConnection;
+
+/// Opens pub/sub connections, which the multiplexed connections cannot
+/// serve. Subscribers open a new one every time they subscribe again, so a
+/// source that looks up its server each time follows it across failovers.
+#[async_trait]
+pub trait PubSubClient: Clone + Send + Sync + 'static {
+    async fn pubsub(&self) -> RedisResult<PubSub>;
+}
+
+#[async_trait]
+impl PubSubClient for redis::Client {
+    async fn pubsub(&self) -> RedisResult<PubSub> {
+        self.get_async_pubsub().await
+    }
+}
+
+/// Asks the sentinels for the current primary on every connection.
+#[async_trait]
+impl PubSubClient for SentinelConnection {
+    async fn pubsub(&self) -> RedisResult<PubSub> {
+        self.client().await?.get_async_pubsub().await
+    }
+}
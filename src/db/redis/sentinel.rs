@@ -0,0 +1,200 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
+use redis::{
+    Client, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value,
+    aio::{ConnectionLike, MultiplexedConnection},
+    sentinel::SentinelClient,
+};
+
+/// Connection to the primary of a Redis managed by Sentinel. The primary is
+/// looked up through the sentinels on first use and again after any error
+/// suggesting that it failed over, so requests only fail until the sentinels
+/// have promoted a replica. A primary that stops answering without closing
+/// its connections counts as failed over once a request takes longer than
+/// `timeout`.
+#[derive(Clone)]
+pub struct SentinelConnection {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    sentinel: tokio::sync::Mutex<SentinelClient>,
+    conn: Mutex<Option<MultiplexedConnection>>,
+    config: redis::AsyncConnectionConfig,
+    timeout: Duration,
+}
+
+impl SentinelConnection {
+    /// `timeout` should not be longer than the timeouts callers put on their
+    /// requests, which would otherwise cancel them before the primary is
+    /// forgotten.
+    pub fn new(
+        sentinel: SentinelClient,
+        config: redis::AsyncConnectionConfig,
+        timeout: Duration,
+    ) -> Self {
+        SentinelConnection {
+            inner: Arc::new(Inner {
+                sentinel: tokio::sync::Mutex::new(sentinel),
+                conn: Mutex::new(None),
+                config,
+                timeout,
+            }),
+        }
+    }
+
+    /// Client for the current primary, for connections the multiplexed
+    /// connection cannot serve, such as pub/sub.
+    pub async fn client(&self) -> RedisResult<Client> {
+        self.inner.sentinel.lock().await.async_get_client().await
+    }
+
+    fn current(&self) -> Option<MultiplexedConnection> {
+        self.inner.conn.lock().unwrap().clone()
+    }
+
+    async fn connection(&self) -> RedisResult<MultiplexedConnection> {
+        if let Some(conn) = self.current() {
+            return Ok(conn);
+        }
+
+        let mut sentinel = self.inner.sentinel.lock().await;
+        // Another request may have connected while this one waited.
+        if let Some(conn) = self.current() {
+            return Ok(conn);
+        }
+
+        let client = sentinel.async_get_client().await?;
+        let conn = client
+            .get_multiplexed_async_connection_with_config(&self.inner.config)
+            .await?;
+        info!(
+            "Connected to Redis primary at {}",
+            client.get_connection_info().addr
+        );
+
+        *self.inner.conn.lock().unwrap() = Some(conn.clone());
+        Ok(conn)
+    }
+
+    /// Runs a request on the primary, failing with a timeout error when it
+    /// takes longer than `timeout`.
+    async fn request<T>(&self, request: impl Future<Output = RedisResult<T>>) -> RedisResult<T> {
+        let result = match tokio::time::timeout(self.inner.timeout, request).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Redis primary did not answer in time",
+            )
+            .into()),
+        };
+        self.check(result)
+    }
+
+    /// Forgets the primary after errors that suggest it is gone or has been
+    /// demoted, so that the next request asks the sentinels again.
+    fn check<T>(&self, result: RedisResult<T>) -> RedisResult<T> {
+        if let Err(e) = &result
+            && is_failover(e)
+            && self.inner.conn.lock().unwrap().take().is_some()
+        {
+            warn!("Lost the Redis primary, asking the sentinels again: {}", e);
+        }
+        result
+    }
+}
+
+fn is_failover(e: &RedisError) -> bool {
+    e.is_io_error()
+        || e.is_connection_dropped()
+        || e.is_connection_refusal()
+        || e.is_timeout()
+        || e.kind() == ErrorKind::ReadOnly
+}
+
+impl ConnectionLike for SentinelConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        Box::pin(async move {
+            self.request(async { self.connection().await?.req_packed_command(cmd).await })
+                .await
+        })
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        Box::pin(async move {
+            self.request(async {
+                self.connection()
+                    .await?
+                    .req_packed_commands(cmd, offset, count)
+                    .await
+            })
+            .await
+        })
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::sentinel::SentinelServerType;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    /// A server that answers the connection handshake and then never again,
+    /// like a primary whose host went away.
+    async fn hanging_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = socket.read(&mut buf).await.unwrap();
+            socket.write_all(b"+OK\r\n+OK\r\n").await.unwrap();
+            while socket.read(&mut buf).await.is_ok_and(|read| read > 0) {}
+        });
+        format!("redis://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_forgets_hanging_primary() {
+        let sentinel = SentinelClient::build(
+            vec!["redis://127.0.0.1:1"],
+            "mymaster".to_string(),
+            None,
+            SentinelServerType::Master,
+        )
+        .unwrap();
+        let config = redis::AsyncConnectionConfig::new();
+        let conn = SentinelConnection::new(sentinel, config.clone(), Duration::from_millis(50));
+
+        let primary = Client::open(hanging_server().await)
+            .unwrap()
+            .get_multiplexed_async_connection_with_config(&config)
+            .await
+            .unwrap();
+        *conn.inner.conn.lock().unwrap() = Some(primary);
+
+        let result = redis::cmd("PING")
+            .query_async::<()>(&mut conn.clone())
+            .await;
+        assert!(result.unwrap_err().is_timeout());
+        assert!(conn.current().is_none());
+    }
+}
@@ -1,52 +1,70 @@
 use std::{
-    fmt::Write,
     sync::{Arc, LazyLock},
     time::{Duration, SystemTime},
 };
 
 use crate::{
-    common::{AcquireAttempt, RateLimitAlgorithm, RateLimitAlgorithmErr, to_unix_millis},
-    config::{PolicyDefinition, PolicyRule},
-    db::{AcquireErr, AcquireResult, RateLimitConfig, RateLimitStore, TokensRemaining},
+    common::{
+        AcquireAttempt, Clock, RateLimitAlgorithm, RateLimitAlgorithmErr, SystemClock,
+        from_unix_millis, to_unix_millis,
+    },
+    config::{RuleAction, TimeSource},
+    db::{
+        AcquireErr, AcquireResult, DecisionSource, RateLimitConfig, RateLimitStore,
+        TokensRemaining, WindowCounters, list_decision, window_index,
+    },
+    policy::Policies,
 };
 
+use super::{keys::KeySpace, overrides::decode_override};
+
 use async_trait::async_trait;
-use log::debug;
+use log::{debug, info};
 use redis::aio::ConnectionLike;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct RedisRateLimit<A: RateLimitAlgorithm, C: ConnectionLike> {
     conn: C,
     timeout: Duration,
-    default_policy: Arc<PolicyDefinition>,
-    policies: Arc<Vec<PolicyRule>>,
+    policies: Arc<Policies>,
     algorithm: A,
+    keys: KeySpace,
+    time_source: TimeSource,
+    clock: Arc<dyn Clock + Send + Sync>,
 }
 
 impl<A: RateLimitAlgorithm, C: ConnectionLike> RedisRateLimit<A, C> {
-    pub fn new(
-        conn: C,
-        timeout: Duration,
-        default_policy: Arc<PolicyDefinition>,
-        policies: Arc<Vec<PolicyRule>>,
-        algorithm: A,
-    ) -> Self {
+    pub fn new(conn: C, timeout: Duration, policies: Arc<Policies>, algorithm: A) -> Self {
         RedisRateLimit {
             conn,
             timeout,
-            default_policy,
             policies,
             algorithm,
+            keys: KeySpace::default(),
+            time_source: TimeSource::Local,
+            clock: Arc::new(SystemClock),
         }
     }
-}
 
-macro_rules! format_key {
-    ($key:expr, $window:expr) => {{
-        let mut result = String::new();
-        write!(&mut result, "{}.rate_limit.window.{}", $key, $window).unwrap();
-        result
-    }};
+    /// Keeps counters under `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+
+    /// Takes the time deciding the window from `time_source`.
+    pub fn with_time_source(mut self, time_source: TimeSource) -> Self {
+        self.time_source = time_source;
+        self
+    }
+
+    /// Reads the time from `clock` instead of the system clock. With the Redis
+    /// time source it only decides list rules and which windows are read.
+    #[cfg(any(test, feature = "test-support"))]
+    pub fn with_clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
 }
 
 macro_rules! join_and_unwrap {
@@ -66,17 +84,101 @@ macro_rules! timeout {
     }};
 }
 
+/// Counts the tokens in the current window and reads the previous window and
+/// the ban of the counter key. KEYS[1..4] are the windows two before to one
+/// after the caller's current one, so that the current window can be picked by
+/// the time of the Redis server when ARGV[3] is empty. Returns the current count
+/// before the increment, the previous count, when the ban ends (unix
+/// milliseconds, 0 for none) and the time the counters were read at.
 static SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
     redis::Script::new(
         r#"
-    local key = KEYS[1]
+    local ban_key = KEYS[5]
     local increment = tonumber(ARGV[1])
     local ttl = tonumber(ARGV[2])
+    local now = tonumber(ARGV[3])
+    local window = tonumber(ARGV[4])
+    local first_index = tonumber(ARGV[5])
+
+    if now == nil then
+        local time = redis.call('TIME')
+        now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    end
+
+    local offset = math.floor(now / window) - first_index
+    if offset < 1 or offset > 3 then
+        return redis.error_reply('clock skew between the instance and Redis exceeds the window')
+    end
+    local current_key = KEYS[offset + 1]
+    local previous_key = KEYS[offset]
+
+    local new_value = redis.call('INCRBY', current_key, increment)
+    redis.call('EXPIRE', current_key, ttl)
+
+    local previous = tonumber(redis.call('GET', previous_key) or '0')
+    local ban_until = tonumber(redis.call('GET', ban_key) or '0')
+
+    return {new_value - increment, previous, ban_until, now}
+"#,
+    )
+});
+
+/// Takes back up to ARGV[1] tokens from a window counter, never going below 0.
+static RELEASE_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+    local key = KEYS[1]
+    local tokens = tonumber(ARGV[1])
+
+    local current = tonumber(redis.call('GET', key) or '0')
+    local released = math.min(current, tokens)
+    if released > 0 then
+        redis.call('DECRBY', key, released)
+    end
+
+    return released
+"#,
+    )
+});
+
+/// Records a denial and bans the key once there were enough of them. Every ban
+/// adds a strike, and each strike makes the next ban `multiplier` times longer.
+/// Returns when the new ban ends (unix milliseconds), or 0 when there is none.
+static PENALTY_SCRIPT: LazyLock<redis::Script> = LazyLock::new(|| {
+    redis::Script::new(
+        r#"
+    local denials_key = KEYS[1]
+    local strikes_key = KEYS[2]
+    local ban_key = KEYS[3]
+    local now = tonumber(ARGV[1])
+    local threshold = tonumber(ARGV[2])
+    local within = tonumber(ARGV[3])
+    local ban = tonumber(ARGV[4])
+    local multiplier = tonumber(ARGV[5])
+    local max_ban = tonumber(ARGV[6])
 
-    local new_value = redis.call('INCRBY', key, increment)
-    redis.call('EXPIRE', key, ttl)
+    local denials = redis.call('INCR', denials_key)
+    if denials == 1 then
+        redis.call('PEXPIRE', denials_key, within)
+    end
+    if denials < threshold then
+        return 0
+    end
+    redis.call('DEL', denials_key)
 
-    return new_value - increment
+    local strikes = redis.call('INCR', strikes_key)
+    for _ = 2, strikes do
+        ban = ban * multiplier
+        if ban >= max_ban then
+            break
+        end
+    end
+    ban = math.min(ban, max_ban)
+    redis.call('PEXPIRE', strikes_key, ban + max_ban)
+
+    local ban_until = now + ban
+    redis.call('SET', ban_key, ban_until, 'PX', ban)
+    return ban_until
 "#,
     )
 });
@@ -86,86 +188,372 @@ impl<A: RateLimitAlgorithm + Send, C: ConnectionLike + Clone + Send> RateLimitSt
     for RedisRateLimit<A, C>
 {
     async fn acquire(&mut self, config: &RateLimitConfig) -> AcquireResult {
-        let policy = self
-            .policies
-            .iter()
-            .filter(|rule| match rule.pattern_type {
-                crate::config::PatternType::Exact => rule.pattern == config.resource_key,
-                crate::config::PatternType::Prefix => {
-                    config.resource_key.starts_with(&rule.pattern)
-                }
-            })
-            .max_by_key(|rule| rule.priority)
-            .map(|rule| &rule.policy)
-            .unwrap_or(&self.default_policy);
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(&config.resource_key);
+        let policy = resolved.policy;
 
         debug!(
-            "Using policy for key '{}': max_tokens={}, window_secs={}",
-            config.resource_key, policy.max_tokens, policy.window_secs
+            "Using policy '{}' for key '{}': max_tokens={}, window_secs={}, counter_key={}",
+            resolved.name,
+            config.resource_key,
+            policy.max_tokens,
+            policy.window_secs,
+            resolved.counter_key
         );
 
-        let now = to_unix_millis(SystemTime::now());
+        let now = self.clock.now();
+        if let Some(result) = list_decision(&resolved, now) {
+            return result;
+        }
 
         let window_duration = Duration::from_secs(policy.window_secs);
-        let window_ms = policy.window_secs as u128 * 1000;
-        let current_window = now / window_ms;
-        let previous_window = current_window - 1;
+        let first_window = window_index(now, policy.window_secs)? - 2;
 
-        let current_key = format_key!(config.resource_key, current_window);
-        let previous_key = format_key!(config.resource_key, previous_window);
+        let window_key = |index| {
+            self.keys.window(
+                &resolved.counter_key,
+                resolved.name,
+                policy.window_secs,
+                index,
+            )
+        };
+        let penalty_key = |kind| {
+            self.keys
+                .penalty(&resolved.counter_key, resolved.name, kind)
+        };
 
         let mut conn = self.conn.clone();
-        let fetch_current = async {
+        let fetch_counters = async {
+            let mut invocation = SCRIPT.prepare_invoke();
+            for index in first_window..first_window + 4 {
+                invocation.key(window_key(index));
+            }
+            invocation
+                .key(penalty_key("ban"))
+                .arg(config.tokens_to_acquire)
+                .arg(policy.window_secs * 2) // TTL should be at least double the window
+                .arg(match self.time_source {
+                    TimeSource::Local => (to_unix_millis(now) as u64).to_string(),
+                    TimeSource::Redis => String::new(),
+                })
+                .arg(policy.window_secs * 1000)
+                .arg(first_window as u64);
+
             timeout!(
                 self.timeout,
-                SCRIPT
-                    .key(&current_key)
-                    .arg(config.tokens_to_acquire)
-                    .arg(policy.window_secs * 2) // TTL should be at least double the window
-                    .invoke_async(&mut conn)
+                invocation.invoke_async::<(u32, u32, u64, u64)>(&mut conn)
             )
         };
 
+        // The override is keyed by the resource key, which may hash to another
+        // slot than the counter key, so it is read alongside the script.
         let mut conn = self.conn.clone();
-        let fetch_previous = async {
-            timeout!(self.timeout, async {
+        let fetch_override = async {
+            timeout!(
+                self.timeout,
                 redis::cmd("GET")
-                    .arg(previous_key)
-                    .query_async::<Option<u32>>(&mut conn)
-                    .await
-                    .map(|opt| opt.unwrap_or(0))
-            })
+                    .arg(self.keys.key_override(&config.resource_key))
+                    .query_async::<Option<String>>(&mut conn)
+            )
         };
 
-        let (current, previous) = join_and_unwrap!(fetch_current, fetch_previous);
+        let ((current, previous, ban_until, counted_at), key_override) =
+            join_and_unwrap!(fetch_counters, fetch_override);
         debug!(
             "Current window requests: {}, Previous window requests: {}",
             current, previous
         );
 
+        // With the Redis time source, everything below is decided as of the
+        // time the script read the counters at.
+        let now = from_unix_millis(counted_at);
+
+        let ban_until = Some(from_unix_millis(ban_until))
+            .filter(|ban_until| policy.penalty.is_some() && *ban_until > now);
+        if let Some(ban_until) = ban_until {
+            debug!(
+                "Key '{}' is banned by policy '{}' until {:?}",
+                config.resource_key, resolved.name, ban_until
+            );
+            return Err(AcquireErr::RateLimitExceeded {
+                reset_after: ban_until,
+                policy: resolved.name.clone(),
+                decided_by: DecisionSource::Penalty,
+                counted_at: now,
+            });
+        }
+
+        let key_override =
+            key_override.and_then(|value| decode_override(&config.resource_key, &value, now));
+        let (max_tokens, decided_by) = match key_override {
+            Some(key_override) => {
+                debug!(
+                    "Override for key '{}' changes max_tokens from {} to {}",
+                    config.resource_key, policy.max_tokens, key_override.max_tokens
+                );
+                (key_override.max_tokens, DecisionSource::Override)
+            }
+            None => (policy.max_tokens, DecisionSource::Policy),
+        };
+
         let attempt = AcquireAttempt::new(
             config.tokens_to_acquire,
-            policy.max_tokens,
+            max_tokens,
             window_duration,
             previous,
             current,
-        );
+        )
+        .at(now);
+
+        let result = match self.algorithm.try_acquire(&attempt) {
+            Ok((remaining, reset_after)) => Ok(TokensRemaining::new(
+                remaining,
+                reset_after,
+                resolved.name.clone(),
+                decided_by,
+                now,
+            )),
+            Err(RateLimitAlgorithmErr::RateLimitExceeded(reset_after)) => {
+                let mut denial = (reset_after, decided_by);
 
-        let result = self
-            .algorithm
-            .try_acquire(&attempt)
-            .map(|(remaining, reset_after)| TokensRemaining::new(remaining, reset_after))
-            .map_err(|e| match e {
-                RateLimitAlgorithmErr::RateLimitExceeded(reset_after) => {
-                    AcquireErr::RateLimitExceeded(reset_after)
+                if let Some(penalty) = &policy.penalty {
+                    let mut conn = self.conn.clone();
+                    let ban_until: u64 = timeout!(
+                        self.timeout,
+                        PENALTY_SCRIPT
+                            .key(penalty_key("denials"))
+                            .key(penalty_key("strikes"))
+                            .key(penalty_key("ban"))
+                            .arg(to_unix_millis(now) as u64)
+                            .arg(penalty.denials)
+                            .arg(penalty.within_secs * 1000)
+                            .arg(penalty.ban_secs * 1000)
+                            .arg(penalty.multiplier)
+                            .arg(penalty.max_ban_secs * 1000)
+                            .invoke_async(&mut conn)
+                    )?;
+
+                    if ban_until > 0 {
+                        info!(
+                            "Banned key '{}' under policy '{}' until {:?}",
+                            config.resource_key,
+                            resolved.name,
+                            from_unix_millis(ban_until)
+                        );
+                        denial = (from_unix_millis(ban_until), DecisionSource::Penalty);
+                    }
                 }
-            });
+
+                Err(AcquireErr::RateLimitExceeded {
+                    reset_after: denial.0,
+                    policy: resolved.name.clone(),
+                    decided_by: denial.1,
+                    counted_at: now,
+                })
+            }
+        };
 
         debug!(
-            "Acquire result for key '{}': {:?}",
-            config.resource_key, result
+            "Acquire result for key '{}' under policy '{}': {:?}",
+            config.resource_key, resolved.name, result
         );
 
         result
     }
+
+    async fn counters(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<WindowCounters, AcquireErr> {
+        let current_window = window_index(self.clock.now(), window_secs)?;
+
+        let mut conn = self.conn.clone();
+        let (current, previous) = timeout!(
+            self.timeout,
+            redis::cmd("MGET")
+                .arg(
+                    self.keys
+                        .window(counter_key, policy, window_secs, current_window)
+                )
+                .arg(
+                    self.keys
+                        .window(counter_key, policy, window_secs, current_window - 1)
+                )
+                .query_async::<(Option<u32>, Option<u32>)>(&mut conn)
+        )?;
+
+        Ok(WindowCounters {
+            window_start: SystemTime::UNIX_EPOCH
+                + Duration::from_millis((current_window * window_secs as u128 * 1000) as u64),
+            current: current.unwrap_or(0),
+            previous: previous.unwrap_or(0),
+        })
+    }
+
+    async fn reset(
+        &mut self,
+        counter_key: &str,
+        policy: &str,
+        window_secs: u64,
+    ) -> Result<(), AcquireErr> {
+        // The neighbours of the current window too, which the Redis time
+        // source may have counted in.
+        let current_window = window_index(self.clock.now(), window_secs)?;
+        let mut keys: Vec<String> = (current_window - 2..=current_window + 1)
+            .map(|index| self.keys.window(counter_key, policy, window_secs, index))
+            .collect();
+        keys.extend(
+            ["denials", "strikes", "ban"].map(|kind| self.keys.penalty(counter_key, policy, kind)),
+        );
+
+        let mut conn = self.conn.clone();
+        timeout!(
+            self.timeout,
+            redis::cmd("DEL").arg(&keys).query_async::<()>(&mut conn)
+        )
+    }
+
+    async fn release(
+        &mut self,
+        config: &RateLimitConfig,
+        counted_at: SystemTime,
+    ) -> Result<(), AcquireErr> {
+        let matcher = self.policies.matcher();
+        let resolved = matcher.resolve(&config.resource_key);
+        if resolved.action != RuleAction::Limit {
+            return Ok(());
+        }
+
+        let window = window_index(counted_at, resolved.policy.window_secs)?;
+        let mut conn = self.conn.clone();
+        let released: u32 = timeout!(
+            self.timeout,
+            RELEASE_SCRIPT
+                .key(self.keys.window(
+                    &resolved.counter_key,
+                    resolved.name,
+                    resolved.policy.window_secs,
+                    window
+                ))
+                .arg(config.tokens_to_acquire)
+                .invoke_async(&mut conn)
+        )?;
+
+        debug!(
+            "Released {} of {} tokens for key '{}'",
+            released, config.tokens_to_acquire, config.resource_key
+        );
+        Ok(())
+    }
+
+    async fn is_healthy(&mut self) -> bool {
+        let mut conn = self.conn.clone();
+        matches!(
+            tokio::time::timeout(
+                self.timeout,
+                redis::cmd("PING").query_async::<String>(&mut conn)
+            )
+            .await,
+            Ok(Ok(response)) if response == "PONG"
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::{Cmd, Pipeline, RedisFuture, Value};
+
+    use super::*;
+    use crate::{
+        common::{SlidingWindow, SystemClock},
+        config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction},
+        db::UNLIMITED_TOKENS,
+    };
+
+    /// Connection that fails the test if any command reaches it.
+    #[derive(Clone)]
+    struct NoRedis;
+
+    impl ConnectionLike for NoRedis {
+        fn req_packed_command<'a>(&'a mut self, _cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            panic!("list rules must not reach Redis")
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _cmd: &'a Pipeline,
+            _offset: usize,
+            _count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            panic!("list rules must not reach Redis")
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    fn list_rule(name: &str, pattern: &str, action: RuleAction) -> PolicyRule {
+        PolicyRule {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition::default(),
+            priority: 0,
+            action,
+        }
+    }
+
+    fn store() -> RedisRateLimit<SlidingWindow<SystemClock>, NoRedis> {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let rules = vec![
+            list_rule("monitors", "monitor.", RuleAction::Allow),
+            list_rule("abusers", "ip:10.", RuleAction::Deny),
+        ];
+        let policies = Policies::new(default_policy, rules).unwrap();
+
+        RedisRateLimit::new(
+            NoRedis,
+            Duration::from_millis(100),
+            Arc::new(policies),
+            SlidingWindow::new(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_allow_list_skips_redis() {
+        let result = store()
+            .acquire(&RateLimitConfig::new("monitor.synthetic".to_string(), 5))
+            .await
+            .unwrap();
+
+        assert_eq!(result.remaining, UNLIMITED_TOKENS);
+        assert_eq!(&*result.policy, "monitors");
+        assert_eq!(result.decided_by, DecisionSource::AllowList);
+    }
+
+    #[tokio::test]
+    async fn test_deny_list_skips_redis() {
+        let result = store()
+            .acquire(&RateLimitConfig::new("ip:10.0.0.1".to_string(), 1))
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(AcquireErr::RateLimitExceeded {
+                decided_by: DecisionSource::DenyList,
+                ..
+            })
+        ));
+    }
 }
@@ -0,0 +1,190 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::warn;
+use redis::aio::ConnectionLike;
+
+use crate::db::{KeyPolicy, TopKeysErr, TopKeysHistory, TrafficSummary};
+
+use super::keys::KeySpace;
+
+const KEYS: &str = "keys";
+const KEY_DENIALS: &str = "key_denials";
+const POLICIES: &str = "policies";
+const POLICY_DENIALS: &str = "policy_denials";
+
+/// Heavy hitter history shared by every instance. Each bucket is a handful of
+/// sorted sets, trimmed to the `capacity` busiest members and expired once
+/// they fall out of the retention. A trimmed member loses its score, so the
+/// counts of keys outside the busiest may be too low.
+#[derive(Debug, Clone)]
+pub struct RedisTopKeysHistory<C: ConnectionLike> {
+    conn: C,
+    timeout: Duration,
+    keys: KeySpace,
+    capacity: usize,
+    bucket_secs: u64,
+    retention_secs: u64,
+}
+
+impl<C: ConnectionLike> RedisTopKeysHistory<C> {
+    pub fn new(
+        conn: C,
+        timeout: Duration,
+        capacity: usize,
+        bucket_secs: u64,
+        retention_secs: u64,
+    ) -> Self {
+        RedisTopKeysHistory {
+            conn,
+            timeout,
+            keys: KeySpace::default(),
+            capacity,
+            bucket_secs,
+            retention_secs,
+        }
+    }
+
+    /// Keeps the history under `namespace` instead of the default one.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.keys = KeySpace::new(namespace);
+        self
+    }
+}
+
+macro_rules! timeout {
+    ($duration:expr, $fut:expr) => {{
+        match tokio::time::timeout($duration, $fut).await {
+            Ok(Ok(res)) => Ok(res),
+            Ok(Err(e)) => Err(TopKeysErr::RedisError(e)),
+            _ => Err(TopKeysErr::Timeout),
+        }
+    }};
+}
+
+/// Sorted set member of a key: the key and its policy as a JSON array, so
+/// that neither needs escaping.
+fn encode_member(key: &KeyPolicy) -> String {
+    serde_json::to_string(&(&key.key, &key.policy)).unwrap()
+}
+
+fn decode_member(member: &str) -> Option<KeyPolicy> {
+    match serde_json::from_str::<(String, String)>(member) {
+        Ok((key, policy)) => Some(KeyPolicy { key, policy }),
+        Err(e) => {
+            warn!("Ignoring invalid top keys member '{}': {}", member, e);
+            None
+        }
+    }
+}
+
+#[async_trait]
+impl<C: ConnectionLike + Clone + Send + Sync> TopKeysHistory for RedisTopKeysHistory<C> {
+    async fn add(&self, bucket: u64, summary: &TrafficSummary) -> Result<(), TopKeysErr> {
+        let mut pipe = redis::pipe();
+        for (key, traffic) in &summary.keys {
+            let member = encode_member(key);
+            pipe.zincr(self.keys.top_keys(bucket, KEYS), &member, traffic.requests)
+                .ignore();
+            if traffic.denials > 0 {
+                pipe.zincr(
+                    self.keys.top_keys(bucket, KEY_DENIALS),
+                    &member,
+                    traffic.denials,
+                )
+                .ignore();
+            }
+        }
+        for (policy, traffic) in &summary.policies {
+            pipe.zincr(
+                self.keys.top_keys(bucket, POLICIES),
+                policy,
+                traffic.requests,
+            )
+            .ignore();
+            pipe.zincr(
+                self.keys.top_keys(bucket, POLICY_DENIALS),
+                policy,
+                traffic.denials,
+            )
+            .ignore();
+        }
+
+        // Members below the `capacity` busiest are dropped, so a bucket stays
+        // small however many keys are seen in it.
+        let expiry = (self.retention_secs + self.bucket_secs) as i64;
+        for kind in [KEYS, KEY_DENIALS] {
+            pipe.zremrangebyrank(
+                self.keys.top_keys(bucket, kind),
+                0,
+                -(self.capacity as isize) - 1,
+            )
+            .ignore();
+        }
+        for kind in [KEYS, KEY_DENIALS, POLICIES, POLICY_DENIALS] {
+            pipe.expire(self.keys.top_keys(bucket, kind), expiry)
+                .ignore();
+        }
+
+        let mut conn = self.conn.clone();
+        timeout!(self.timeout, pipe.query_async::<()>(&mut conn))
+    }
+
+    async fn range(&self, from: u64, to: u64) -> Result<TrafficSummary, TopKeysErr> {
+        let mut summary = TrafficSummary::default();
+
+        // Every bucket is read in one round trip.
+        let first = from - from % self.bucket_secs;
+        let mut pipe = redis::pipe();
+        for bucket in (first..=to).step_by(self.bucket_secs as usize) {
+            for kind in [KEYS, KEY_DENIALS, POLICIES, POLICY_DENIALS] {
+                pipe.zrange_withscores(self.keys.top_keys(bucket, kind), 0, -1);
+            }
+        }
+
+        let mut conn = self.conn.clone();
+        let sets: Vec<Vec<(String, u64)>> = timeout!(self.timeout, pipe.query_async(&mut conn))?;
+
+        let mut sets = sets.into_iter();
+        while let (Some(keys), Some(key_denials), Some(policies), Some(policy_denials)) =
+            (sets.next(), sets.next(), sets.next(), sets.next())
+        {
+            let mut merged = TrafficSummary::default();
+            for (member, requests) in keys {
+                if let Some(key) = decode_member(&member) {
+                    merged.keys.entry(key).or_default().requests += requests;
+                }
+            }
+            for (member, denials) in key_denials {
+                if let Some(key) = decode_member(&member) {
+                    merged.keys.entry(key).or_default().denials += denials;
+                }
+            }
+            for (policy, requests) in policies {
+                merged.policies.entry(policy).or_default().requests += requests;
+            }
+            for (policy, denials) in policy_denials {
+                merged.policies.entry(policy).or_default().denials += denials;
+            }
+            summary.merge(merged);
+        }
+
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_round_trip() {
+        let key = KeyPolicy {
+            key: "tenant:\"1\"".to_string(),
+            policy: "a:b".to_string(),
+        };
+
+        assert_eq!(decode_member(&encode_member(&key)), Some(key));
+        assert_eq!(decode_member("not json"), None);
+    }
+}
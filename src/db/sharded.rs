@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    sync::Mutex,
+};
+
+/// Hash map split into independently locked shards, so that requests for
+/// different keys rarely wait on each other. Locks are never held across an
+/// `.await`.
+#[derive(Debug)]
+pub(in crate::db) struct ShardedMap<V> {
+    shards: Box<[Mutex<HashMap<String, V>>]>,
+    hasher: RandomState,
+}
+
+impl<V> ShardedMap<V> {
+    pub fn new(shards: usize) -> Self {
+        ShardedMap {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Four shards per available core.
+    pub fn with_default_shards() -> Self {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::new(cores * 4)
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<HashMap<String, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        &self.shards[index]
+    }
+
+    /// Runs `f` on the value of `key`, inserting `default()` first if needed.
+    pub fn update<R>(
+        &self,
+        key: &str,
+        default: impl FnOnce() -> V,
+        f: impl FnOnce(&mut V) -> R,
+    ) -> R {
+        let mut shard = self.shard(key).lock().unwrap();
+        let value = match shard.get_mut(key) {
+            Some(value) => value,
+            None => shard.entry(key.to_string()).or_insert_with(default),
+        };
+        f(value)
+    }
+
+    /// Runs `f` on the value of `key` if there is one.
+    pub fn modify<R>(&self, key: &str, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).lock().unwrap().get_mut(key).map(f)
+    }
+
+    pub fn get<R>(&self, key: &str, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.shard(key).lock().unwrap().get(key).map(f)
+    }
+
+    /// Inserts a value and returns the one it replaced.
+    pub fn insert(&self, key: String, value: V) -> Option<V> {
+        self.shard(&key).lock().unwrap().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<V> {
+        self.shard(key).lock().unwrap().remove(key)
+    }
+
+    /// Keeps only the entries for which `keep` returns true, one shard at a time.
+    pub fn retain(&self, mut keep: impl FnMut(&str, &mut V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().retain(|key, value| keep(key, value));
+        }
+    }
+
+    /// Removes and returns the entries for which `remove` returns true.
+    pub fn drain_where(&self, mut remove: impl FnMut(&str, &V) -> bool) -> Vec<(String, V)> {
+        let mut removed = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let keys: Vec<String> = shard
+                .iter()
+                .filter(|(key, value)| remove(key, value))
+                .map(|(key, _)| key.clone())
+                .collect();
+            removed.extend(keys.into_iter().filter_map(|key| shard.remove_entry(&key)));
+        }
+        removed
+    }
+
+    /// Collects `f` of every entry for which it returns `Some`.
+    pub fn collect<R>(&self, mut f: impl FnMut(&str, &V) -> Option<R>) -> Vec<R> {
+        let mut result = Vec::new();
+        for shard in self.shards.iter() {
+            result.extend(
+                shard
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter_map(|(key, value)| f(key, value)),
+            );
+        }
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_and_retain() {
+        let map = ShardedMap::new(4);
+        for key in ["a", "b", "c", "d", "e"] {
+            map.update(key, || 0, |value| *value += 1);
+        }
+        map.update("a", || 0, |value| *value += 1);
+
+        assert_eq!(map.get("a", |value| *value), Some(2));
+        assert_eq!(map.len(), 5);
+
+        map.retain(|key, _| key != "b");
+        assert_eq!(map.get("b", |value| *value), None);
+        assert_eq!(map.len(), 4);
+
+        let mut keys = map.collect(|key, _| Some(key.to_string()));
+        keys.sort();
+        assert_eq!(keys, vec!["a", "c", "d", "e"]);
+
+        let mut drained = map.drain_where(|_, value| *value == 1);
+        drained.sort();
+        assert_eq!(drained.len(), 3);
+        assert_eq!(map.len(), 1);
+    }
+}
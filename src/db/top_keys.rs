@@ -0,0 +1,498 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, hash_map::DefaultHasher},
+    fmt,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use async_trait::async_trait;
+use log::warn;
+use thiserror::Error;
+use tokio::{task::JoinHandle, time::sleep};
+
+use crate::{common::to_unix_millis, config::TopKeysConfig};
+
+#[derive(Error, Debug)]
+pub enum TopKeysErr {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] redis::RedisError),
+
+    #[error("Timeout error")]
+    Timeout,
+}
+
+/// Requests and denials counted for a key or a policy.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Traffic {
+    pub requests: u64,
+    pub denials: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: Traffic) {
+        self.requests += other.requests;
+        self.denials += other.denials;
+    }
+}
+
+/// A resource key and the policy that decided its requests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct KeyPolicy {
+    pub key: String,
+    pub policy: String,
+}
+
+/// Keys and their traffic, busiest first.
+pub type RankedKeys = Vec<(KeyPolicy, Traffic)>;
+
+/// Traffic per key and per policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficSummary {
+    pub keys: HashMap<KeyPolicy, Traffic>,
+    pub policies: HashMap<String, Traffic>,
+}
+
+impl TrafficSummary {
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty() && self.policies.is_empty()
+    }
+
+    pub fn merge(&mut self, other: TrafficSummary) {
+        for (key, traffic) in other.keys {
+            self.keys.entry(key).or_default().add(traffic);
+        }
+        for (policy, traffic) in other.policies {
+            self.policies.entry(policy).or_default().add(traffic);
+        }
+    }
+
+    /// Drops every key but the `capacity` with the most requests and the
+    /// `capacity` with the most denials. A dropped key loses what it counted
+    /// so far, and starts again from zero if it is seen later.
+    pub fn truncate(&mut self, capacity: usize) {
+        if self.keys.len() <= capacity {
+            return;
+        }
+
+        let mut keep: Vec<KeyPolicy> = top_by(&self.keys, capacity, |traffic| traffic.requests);
+        keep.extend(top_by(&self.keys, capacity, |traffic| traffic.denials));
+
+        let mut keys = std::mem::take(&mut self.keys);
+        for key in keep {
+            if let Some(traffic) = keys.remove(&key) {
+                self.keys.insert(key, traffic);
+            }
+        }
+    }
+
+    /// The `limit` keys with the most requests and the `limit` keys with the
+    /// most denials, busiest first. Keys without denials are left out of the
+    /// latter.
+    pub fn top(&self, limit: usize) -> (RankedKeys, RankedKeys) {
+        let ranked = |score: fn(&Traffic) -> u64| {
+            let mut keys: Vec<_> = self
+                .keys
+                .iter()
+                .filter(|(_, traffic)| score(traffic) > 0)
+                .map(|(key, traffic)| (key.clone(), *traffic))
+                .collect();
+            keys.sort_by(|(a_key, a), (b_key, b)| {
+                score(b).cmp(&score(a)).then_with(|| a_key.cmp(b_key))
+            });
+            keys.truncate(limit);
+            keys
+        };
+
+        (
+            ranked(|traffic| traffic.requests),
+            ranked(|traffic| traffic.denials),
+        )
+    }
+}
+
+fn top_by(
+    keys: &HashMap<KeyPolicy, Traffic>,
+    limit: usize,
+    score: impl Fn(&Traffic) -> u64,
+) -> Vec<KeyPolicy> {
+    let mut ranked: Vec<_> = keys.iter().collect();
+    ranked.sort_by_key(|(_, traffic)| Reverse(score(traffic)));
+    ranked
+        .into_iter()
+        .take(limit)
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+/// Traffic summaries of past buckets, shared by every instance.
+#[async_trait]
+pub trait TopKeysHistory {
+    /// Adds `summary` to the bucket starting at `bucket` (unix seconds).
+    async fn add(&self, bucket: u64, summary: &TrafficSummary) -> Result<(), TopKeysErr>;
+
+    /// Merges the buckets starting from `from` to `to` (unix seconds).
+    async fn range(&self, from: u64, to: u64) -> Result<TrafficSummary, TopKeysErr>;
+}
+
+/// History kept in process memory, for the in-memory store.
+#[derive(Debug, Default)]
+pub struct MemoryTopKeysHistory {
+    buckets: Mutex<BTreeMap<u64, TrafficSummary>>,
+    capacity: usize,
+    retention_secs: u64,
+}
+
+impl MemoryTopKeysHistory {
+    pub fn new(capacity: usize, retention_secs: u64) -> Self {
+        MemoryTopKeysHistory {
+            buckets: Mutex::default(),
+            capacity,
+            retention_secs,
+        }
+    }
+}
+
+#[async_trait]
+impl TopKeysHistory for MemoryTopKeysHistory {
+    async fn add(&self, bucket: u64, summary: &TrafficSummary) -> Result<(), TopKeysErr> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let merged = buckets.entry(bucket).or_default();
+        merged.merge(summary.clone());
+        merged.truncate(self.capacity);
+
+        let oldest = bucket.saturating_sub(self.retention_secs);
+        buckets.retain(|start, _| *start >= oldest);
+        Ok(())
+    }
+
+    async fn range(&self, from: u64, to: u64) -> Result<TrafficSummary, TopKeysErr> {
+        let buckets = self.buckets.lock().unwrap();
+
+        let mut merged = TrafficSummary::default();
+        for summary in buckets.range(from..=to).map(|(_, summary)| summary) {
+            merged.merge(summary.clone());
+        }
+        Ok(merged)
+    }
+}
+
+/// Counts the traffic of this instance and adds it to a shared history. Each
+/// shard keeps at most about `capacity` keys per shard count, so memory stays
+/// bounded however many keys there are.
+///
+/// This is a plain truncation, not a heavy hitter sketch, and gives no error
+/// bound: whenever a shard, a flush or a history bucket is trimmed, the keys
+/// outside the busiest are dropped with their counts. A key is never counted
+/// too high, but a steady key among bursts of other keys may be counted far
+/// too low or left out entirely. Policy totals are never trimmed and are
+/// exact.
+#[derive(Clone)]
+pub struct TopKeys {
+    shards: Arc<Vec<Mutex<TrafficSummary>>>,
+    history: Arc<dyn TopKeysHistory + Send + Sync>,
+    config: TopKeysConfig,
+}
+
+impl fmt::Debug for TopKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TopKeys")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Shards of the local summary, so that requests for different keys rarely
+/// wait for each other.
+const SHARDS: usize = 16;
+
+impl TopKeys {
+    pub fn new(
+        history: impl TopKeysHistory + Send + Sync + 'static,
+        config: TopKeysConfig,
+    ) -> Self {
+        TopKeys {
+            shards: Arc::new((0..SHARDS).map(|_| Mutex::default()).collect()),
+            history: Arc::new(history),
+            config,
+        }
+    }
+
+    fn shard_capacity(&self) -> usize {
+        self.config.capacity.div_ceil(SHARDS)
+    }
+
+    pub fn record(&self, key: &str, policy: &str, denied: bool) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let mut shard = self.shards[hasher.finish() as usize % SHARDS]
+            .lock()
+            .unwrap();
+
+        let traffic = Traffic {
+            requests: 1,
+            denials: denied as u64,
+        };
+        shard
+            .keys
+            .entry(KeyPolicy {
+                key: key.to_string(),
+                policy: policy.to_string(),
+            })
+            .or_default()
+            .add(traffic);
+        shard
+            .policies
+            .entry(policy.to_string())
+            .or_default()
+            .add(traffic);
+
+        // Trimming only once the shard holds a few times its capacity keeps
+        // the cost of sorting low on average.
+        let capacity = self.shard_capacity();
+        if shard.keys.len() >= capacity * 4 {
+            shard.truncate(capacity);
+        }
+    }
+
+    /// Takes the traffic counted since the last flush.
+    fn take(&self) -> TrafficSummary {
+        let mut summary = TrafficSummary::default();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            shard.truncate(self.shard_capacity());
+            summary.merge(std::mem::take(&mut *shard));
+        }
+        summary
+    }
+
+    fn bucket(&self, time: SystemTime) -> u64 {
+        let secs = (to_unix_millis(time) / 1000) as u64;
+        secs - secs % self.config.bucket_secs
+    }
+
+    /// Adds the traffic counted since the last flush to the history.
+    pub async fn flush(&self) -> Result<(), TopKeysErr> {
+        let summary = self.take();
+        if summary.is_empty() {
+            return Ok(());
+        }
+
+        self.history
+            .add(self.bucket(SystemTime::now()), &summary)
+            .await
+    }
+
+    /// Flushes every `flush_secs`, until the tracker is dropped.
+    pub fn spawn_flush(&self) -> JoinHandle<()> {
+        let top_keys = self.clone();
+        let interval = Duration::from_secs(self.config.flush_secs);
+
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+
+                if Arc::strong_count(&top_keys.shards) == 1 {
+                    break;
+                }
+                if let Err(e) = top_keys.flush().await {
+                    warn!("Failed to flush top keys: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Merges the traffic of every instance between `from` and `to`, at the
+    /// resolution of whole buckets. Only buckets within the retention are
+    /// read, since older ones have expired.
+    pub async fn range(
+        &self,
+        from: SystemTime,
+        to: SystemTime,
+    ) -> Result<TrafficSummary, TopKeysErr> {
+        let now = SystemTime::now();
+        let oldest = now
+            .checked_sub(Duration::from_secs(self.config.retention_secs))
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let (from, to) = (from.max(oldest), to.min(now));
+        if from > to {
+            return Ok(TrafficSummary::default());
+        }
+
+        self.history.range(self.bucket(from), self.bucket(to)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        common::SlidingWindow,
+        config::{DefaultPolicy, PolicyDefinition},
+        db::{MemoryRateLimit, ObservedRateLimit, RateLimitConfig, RateLimitStore},
+        policy::Policies,
+    };
+
+    fn key(key: &str, policy: &str) -> KeyPolicy {
+        KeyPolicy {
+            key: key.to_string(),
+            policy: policy.to_string(),
+        }
+    }
+
+    fn traffic(requests: u64, denials: u64) -> Traffic {
+        Traffic { requests, denials }
+    }
+
+    #[test]
+    fn test_truncate_keeps_busiest_and_most_denied() {
+        let mut summary = TrafficSummary::default();
+        summary.keys.insert(key("busy", "p"), traffic(100, 0));
+        summary.keys.insert(key("busier", "p"), traffic(200, 0));
+        summary.keys.insert(key("abusive", "p"), traffic(20, 19));
+        summary.keys.insert(key("quiet", "p"), traffic(1, 0));
+
+        summary.truncate(1);
+
+        let mut keys: Vec<_> = summary.keys.keys().map(|k| k.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["abusive", "busier"]);
+    }
+
+    #[test]
+    fn test_truncate_forgets_evicted_counts() {
+        let mut summary = TrafficSummary::default();
+        summary.keys.insert(key("steady", "p"), traffic(5, 0));
+        for burst in ["a", "b", "c"] {
+            summary.keys.insert(key(burst, "p"), traffic(10, 1));
+        }
+        summary.policies.insert("p".to_string(), traffic(35, 3));
+
+        summary.truncate(1);
+        assert!(!summary.keys.contains_key(&key("steady", "p")));
+
+        // Seen again after the eviction, the key only has its new requests.
+        let mut later = TrafficSummary::default();
+        later.keys.insert(key("steady", "p"), traffic(5, 0));
+        later.policies.insert("p".to_string(), traffic(5, 0));
+        summary.merge(later);
+
+        assert_eq!(summary.keys[&key("steady", "p")], traffic(5, 0));
+        assert_eq!(summary.policies["p"], traffic(40, 3));
+    }
+
+    #[test]
+    fn test_top() {
+        let mut summary = TrafficSummary::default();
+        summary.keys.insert(key("a", "p"), traffic(10, 0));
+        summary.keys.insert(key("b", "p"), traffic(30, 5));
+        summary.keys.insert(key("c", "q"), traffic(20, 15));
+
+        let (by_requests, by_denials) = summary.top(2);
+        assert_eq!(
+            by_requests,
+            vec![
+                (key("b", "p"), traffic(30, 5)),
+                (key("c", "q"), traffic(20, 15))
+            ]
+        );
+        assert_eq!(
+            by_denials,
+            vec![
+                (key("c", "q"), traffic(20, 15)),
+                (key("b", "p"), traffic(30, 5))
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_history_merges_buckets() {
+        let history = MemoryTopKeysHistory::new(10, 120);
+
+        let mut first = TrafficSummary::default();
+        first.keys.insert(key("a", "p"), traffic(3, 1));
+        first.policies.insert("p".to_string(), traffic(3, 1));
+        history.add(60, &first).await.unwrap();
+        history.add(120, &first).await.unwrap();
+        history.add(180, &first).await.unwrap();
+
+        let merged = history.range(120, 180).await.unwrap();
+        assert_eq!(merged.keys[&key("a", "p")], traffic(6, 2));
+        assert_eq!(merged.policies["p"], traffic(6, 2));
+
+        // Older than the retention once the bucket at 300 is added.
+        history.add(300, &first).await.unwrap();
+        let merged = history.range(0, 300).await.unwrap();
+        assert_eq!(merged.keys[&key("a", "p")], traffic(6, 2));
+    }
+
+    #[tokio::test]
+    async fn test_records_decisions() {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 2,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let policies = Arc::new(Policies::new(default_policy, vec![]).unwrap());
+        let top_keys = TopKeys::new(
+            MemoryTopKeysHistory::new(10, 3600),
+            TopKeysConfig::default(),
+        );
+        let mut store = ObservedRateLimit::new(
+            MemoryRateLimit::new(policies.clone(), SlidingWindow::new()),
+            policies,
+        )
+        .with_top_keys(top_keys.clone());
+
+        for _ in 0..3 {
+            let _ = store
+                .acquire(&RateLimitConfig::new("tenant:1".to_string(), 1))
+                .await;
+        }
+        top_keys.flush().await.unwrap();
+
+        let now = SystemTime::now();
+        let summary = top_keys.range(now, now).await.unwrap();
+        assert_eq!(summary.keys[&key("tenant:1", "default")], traffic(3, 1));
+        assert_eq!(summary.policies["default"], traffic(3, 1));
+    }
+
+    /// Remembers the buckets asked for.
+    #[derive(Default, Clone)]
+    struct Asked(Arc<Mutex<Vec<(u64, u64)>>>);
+
+    #[async_trait]
+    impl TopKeysHistory for Asked {
+        async fn add(&self, _bucket: u64, _summary: &TrafficSummary) -> Result<(), TopKeysErr> {
+            Ok(())
+        }
+
+        async fn range(&self, from: u64, to: u64) -> Result<TrafficSummary, TopKeysErr> {
+            self.0.lock().unwrap().push((from, to));
+            Ok(TrafficSummary::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_range_within_retention() {
+        let asked = Asked::default();
+        let config = TopKeysConfig::default();
+        let top_keys = TopKeys::new(asked.clone(), config);
+
+        let now = SystemTime::now();
+        let long_ago = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
+        top_keys.range(long_ago, now).await.unwrap();
+        top_keys.range(long_ago, long_ago).await.unwrap();
+
+        let asked = asked.0.lock().unwrap();
+        assert_eq!(asked.len(), 1);
+        let (from, to) = asked[0];
+        assert_eq!(to, top_keys.bucket(now));
+        assert!(to - from <= config.retention_secs);
+    }
+}
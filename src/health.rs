@@ -1,43 +1,43 @@
 use std::time::Duration;
 
+use crate::db::RateLimitStore;
 use crate::proto::health_server::Health;
 use crate::proto::{HealthCheckRequest, HealthCheckResponse};
-use redis::aio::ConnectionLike;
 use tokio::sync::mpsc;
 use tokio::time::sleep;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
 
 #[derive(Debug, Clone)]
-pub struct HealthCheckImpl<C: ConnectionLike> {
-    timeout: Duration,
-    conn: C,
+pub struct HealthCheckImpl<R: RateLimitStore> {
+    rate_limit: R,
 }
 
-impl<C: ConnectionLike> HealthCheckImpl<C> {
-    pub fn new(conn: C, timeout: Duration) -> Self {
-        HealthCheckImpl { conn, timeout }
+impl<R: RateLimitStore> HealthCheckImpl<R> {
+    pub fn new(rate_limit: R) -> Self {
+        HealthCheckImpl { rate_limit }
     }
 }
 
-impl<C: ConnectionLike> HealthCheckImpl<C> {
-    async fn check_health(conn: &mut C, timeout: Duration) -> HealthCheckResponse {
-        match tokio::time::timeout(timeout, redis::cmd("PING").query_async::<String>(conn)).await {
-            Ok(Ok(response)) if response == "PONG" => HealthCheckResponse { status: 1 }, // SERVING
-            _ => HealthCheckResponse { status: 2 }, // NOT_SERVING
+impl<R: RateLimitStore> HealthCheckImpl<R> {
+    async fn check_health(rate_limit: &mut R) -> HealthCheckResponse {
+        if rate_limit.is_healthy().await {
+            HealthCheckResponse { status: 1 } // SERVING
+        } else {
+            HealthCheckResponse { status: 2 } // NOT_SERVING
         }
     }
 }
 
 #[tonic::async_trait]
-impl<C: ConnectionLike + Send + Sync + 'static + Clone> Health for HealthCheckImpl<C> {
+impl<R: RateLimitStore + Send + Sync + 'static + Clone> Health for HealthCheckImpl<R> {
     async fn check(
         &self,
         _request: Request<HealthCheckRequest>,
     ) -> Result<Response<HealthCheckResponse>, Status> {
-        let mut conn = self.conn.clone();
+        let mut rate_limit = self.rate_limit.clone();
         Ok(Response::new(
-            HealthCheckImpl::check_health(&mut conn, self.timeout).await,
+            HealthCheckImpl::check_health(&mut rate_limit).await,
         ))
     }
 
@@ -49,12 +49,11 @@ impl<C: ConnectionLike + Send + Sync + 'static + Clone> Health for HealthCheckIm
     ) -> Result<Response<Self::WatchStream>, Status> {
         let (tx, rx) = mpsc::channel(4);
 
-        let mut conn = self.conn.clone();
-        let timeout = self.timeout;
+        let mut rate_limit = self.rate_limit.clone();
         tokio::spawn(async move {
             loop {
                 if tx
-                    .send(Ok(HealthCheckImpl::check_health(&mut conn, timeout).await))
+                    .send(Ok(HealthCheckImpl::check_health(&mut rate_limit).await))
                     .await
                     .is_err()
                 {
@@ -1,6 +1,8 @@
+pub mod admin;
 pub mod common;
 pub mod config;
 pub mod db;
 pub mod health;
+pub mod policy;
 pub mod proto;
 pub mod rate_limiter;
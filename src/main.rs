@@ -1,22 +1,44 @@
+mod admin;
 mod common;
 mod config;
 mod db;
 mod health;
+mod policy;
 mod proto;
 mod rate_limiter;
 
 use std::{sync::Arc, time::Duration};
 
-use log::{LevelFilter, debug};
-use proto::rate_limiter_server::RateLimiterServer;
+use log::{LevelFilter, debug, warn};
+use proto::{admin_server::AdminServer, rate_limiter_server::RateLimiterServer};
+use redis::aio::ConnectionLike;
 use simple_logger::SimpleLogger;
 use tokio::signal;
 
 use crate::{
-    common::SlidingWindow, config::load_config, db::RedisRateLimit, health::HealthCheckImpl,
-    proto::health_server::HealthServer, rate_limiter::RateLimiterImpl,
+    admin::AdminImpl,
+    common::SlidingWindow,
+    config::{ServerConfig, StoreKind, load_config},
+    db::{
+        AuditLog, CoalescingRateLimit, DecisionFeed, EventPublisher, FallbackRateLimit,
+        LeasedRateLimit, MemoryOverrideStore, MemoryPolicyStore, MemoryRateLimit,
+        MemoryTopKeysHistory, ObservedRateLimit, OverrideStore, PolicyStore, PubSubClient,
+        RateLimitStore, RedisDecisionRelay, RedisEventWriter, RedisOverrideStore, RedisPolicyStore,
+        RedisRateLimit, RedisSettings, RedisTopKeysHistory, SentinelConnection, TopKeys,
+        poll_overrides, reload_policies, watch_policies,
+    },
+    health::HealthCheckImpl,
+    policy::Policies,
+    proto::health_server::HealthServer,
+    rate_limiter::RateLimiterImpl,
 };
 
+/// How often in-memory windows that no longer count are dropped.
+const MEMORY_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often unused tokens of expired leases are given back to Redis.
+const LEASE_EXPIRY_INTERVAL: Duration = Duration::from_millis(250);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let _ = log::set_boxed_logger(Box::new(SimpleLogger::new()))
@@ -26,8 +48,111 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     debug!("Loaded config: {:?}", config);
 
     let addr = config.server.address.parse()?;
+    let admin_addr = config.server.admin_address.parse()?;
+    let policies = Arc::new(Policies::new(config.default_policy, config.policies)?);
+
+    let audit = match &config.server.audit {
+        Some(audit) => AuditLog::open(audit)
+            .map_err(|e| format!("Failed to open the audit log {:?}: {}", audit.path, e))?,
+        None => AuditLog::disabled(),
+    };
+
+    let result = match config.server.store {
+        StoreKind::Redis => {
+            run_with_redis(addr, admin_addr, &config.server, policies, audit.clone()).await
+        }
+        StoreKind::Memory => {
+            warn!("Using the in-memory store: limits are not shared between instances");
+
+            let rate_limit = MemoryRateLimit::new(policies.clone(), SlidingWindow::new());
+            rate_limit.spawn_expiry(MEMORY_EXPIRY_INTERVAL);
+
+            let override_store = rate_limit.override_store();
+            let rate_limit =
+                ObservedRateLimit::new(rate_limit, policies.clone()).with_audit(audit.clone());
+            let admin = AdminImpl::new(
+                rate_limit.clone(),
+                policies,
+                MemoryPolicyStore::default(),
+                override_store,
+            )
+            .with_audit(audit.clone());
+            let top_keys = config.server.top_keys.map(|top_keys| {
+                TopKeys::new(
+                    MemoryTopKeysHistory::new(top_keys.capacity, top_keys.retention_secs),
+                    top_keys,
+                )
+            });
+            serve(
+                addr,
+                admin_addr,
+                rate_limit,
+                admin,
+                top_keys,
+                DecisionFeed::local(),
+            )
+            .await
+        }
+    };
+
+    // Records still queued would be lost on exit.
+    audit.flush().await;
+    result
+}
+
+async fn run_with_redis(
+    addr: std::net::SocketAddr,
+    admin_addr: std::net::SocketAddr,
+    server: &ServerConfig,
+    policies: Arc<Policies>,
+    audit: AuditLog,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let timeout = Duration::from_millis(server.redis_timeout_ms);
+
+    let settings = RedisSettings::from_config(server)?;
+
+    if let Some(sentinel) = &server.sentinel {
+        let conn = SentinelConnection::new(
+            settings.sentinel_client(sentinel)?,
+            redis::AsyncConnectionConfig::new()
+                .set_response_timeout(timeout)
+                .set_connection_timeout(Duration::from_secs(1)),
+            timeout,
+        );
+
+        tokio::time::timeout(Duration::from_secs(10), conn.client())
+            .await
+            .map_err(|_| "Failed to find the Redis primary: timeout")?
+            .map_err(|e| format!("Failed to find the Redis primary: {}", e))?;
+
+        // Subscriptions ask the sentinels for the primary again every time
+        // they reconnect, so they follow it across failovers.
+        return serve_redis(
+            addr,
+            admin_addr,
+            server,
+            policies,
+            audit,
+            conn.clone(),
+            conn,
+        )
+        .await;
+    }
+
+    // Policy changes are also published on a plain connection in cluster mode,
+    // since Redis Cluster forwards published messages to every node.
+    let client = settings.client()?;
+
+    if server.redis_cluster {
+        let cluster = settings.cluster_client(timeout)?;
+        let conn = tokio::time::timeout(Duration::from_secs(10), cluster.get_async_connection())
+            .await
+            .map_err(|_| "Failed to connect to Redis Cluster: timeout")?
+            .map_err(|e| format!("Failed to connect to Redis Cluster: {}", e))?;
+
+        return serve_redis(addr, admin_addr, server, policies, audit, client, conn).await;
+    }
 
-    let timeout = Duration::from_millis(config.server.redis_timeout_ms);
     let redis_config = redis::aio::ConnectionManagerConfig::new()
         .set_response_timeout(timeout)
         .set_connection_timeout(Duration::from_secs(1))
@@ -36,33 +161,171 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .set_exponent_base(2)
         .set_number_of_retries(5);
 
-    let client = redis::Client::open(config.server.redis_url)?;
     let manager = tokio::time::timeout(
         Duration::from_secs(10),
-        redis::aio::ConnectionManager::new_with_config(client, redis_config),
+        redis::aio::ConnectionManager::new_with_config(client.clone(), redis_config),
     )
     .await
     .map_err(|_| "Failed to connect to Redis: timeout")?
     .map_err(|e| format!("Failed to connect to Redis: {}", e))?;
 
+    serve_redis(addr, admin_addr, server, policies, audit, client, manager).await
+}
+
+async fn serve_redis<C, P>(
+    addr: std::net::SocketAddr,
+    admin_addr: std::net::SocketAddr,
+    server: &ServerConfig,
+    policies: Arc<Policies>,
+    audit: AuditLog,
+    client: P,
+    conn: C,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    C: ConnectionLike + Send + Sync + Clone + 'static,
+    P: PubSubClient,
+{
+    let timeout = Duration::from_millis(server.redis_timeout_ms);
+
+    let namespace = server.key_namespace.as_str();
+
+    let mut policy_store = RedisPolicyStore::new(conn.clone(), timeout).with_namespace(namespace);
+    if let Err(e) = reload_policies(&mut policy_store, &policies).await {
+        warn!("Failed to load policies from Redis: {}", e);
+    }
+
+    let decisions = RedisDecisionRelay::new(client.clone(), conn.clone(), timeout)
+        .with_namespace(namespace)
+        .spawn();
+
+    tokio::spawn(watch_policies(
+        client,
+        policy_store.clone(),
+        policies.clone(),
+        Duration::from_secs(server.policy_poll_secs),
+    ));
+
     let rate_limit = RedisRateLimit::new(
-        manager.clone(),
+        conn.clone(),
         timeout,
-        Arc::new(config.default_policy),
-        Arc::new(config.policies),
+        policies.clone(),
         SlidingWindow::new(),
-    );
+    )
+    .with_namespace(namespace)
+    .with_time_source(server.time_source);
+    #[cfg(feature = "test-support")]
+    let clock = server
+        .virtual_clock
+        .then(|| common::VirtualClock::new(std::time::SystemTime::now()));
+    #[cfg(feature = "test-support")]
+    let rate_limit = match &clock {
+        Some(clock) => {
+            warn!("Using a virtual clock: time only moves through AdvanceClock");
+            rate_limit.with_clock(clock.clone())
+        }
+        None => rate_limit,
+    };
+    let override_store = RedisOverrideStore::new(conn.clone(), timeout).with_namespace(namespace);
+    let overrides = MemoryOverrideStore::default();
+    tokio::spawn(poll_overrides(
+        override_store.clone(),
+        overrides.clone(),
+        Duration::from_secs(server.policy_poll_secs),
+    ));
 
-    let rate_limiter = RateLimiterImpl::new(rate_limit);
-    let health = HealthCheckImpl::new(manager.clone(), timeout);
+    let rate_limit = FallbackRateLimit::new(
+        rate_limit,
+        policies.clone(),
+        server.failure_mode,
+        server.fallback_instances,
+    )
+    .with_overrides(overrides);
+    rate_limit.spawn_expiry(MEMORY_EXPIRY_INTERVAL);
+    let rate_limit = LeasedRateLimit::new(rate_limit, policies.clone());
+    rate_limit.spawn_expiry(LEASE_EXPIRY_INTERVAL);
+    let rate_limit = CoalescingRateLimit::new(rate_limit, policies.clone());
+    let events = match &server.events {
+        Some(events) => RedisEventWriter::new(conn.clone(), timeout)
+            .with_namespace(namespace)
+            .spawn(events),
+        None => EventPublisher::disabled(),
+    };
+    let rate_limit = ObservedRateLimit::new(rate_limit, policies.clone())
+        .with_audit(audit.clone())
+        .with_events(events);
 
-    println!("Server listening on {}", addr);
+    let top_keys = server.top_keys.map(|top_keys| {
+        let history = RedisTopKeysHistory::new(
+            conn.clone(),
+            timeout,
+            top_keys.capacity,
+            top_keys.bucket_secs,
+            top_keys.retention_secs,
+        )
+        .with_namespace(namespace);
+        TopKeys::new(history, top_keys)
+    });
 
-    tonic::transport::Server::builder()
-        .add_service(RateLimiterServer::new(rate_limiter))
+    let admin = AdminImpl::new(rate_limit.clone(), policies, policy_store, override_store)
+        .with_audit(audit);
+    #[cfg(feature = "test-support")]
+    let admin = match clock {
+        Some(clock) => admin.with_clock(clock),
+        None => admin,
+    };
+
+    serve(addr, admin_addr, rate_limit, admin, top_keys, decisions).await
+}
+
+async fn serve<R, P, O>(
+    addr: std::net::SocketAddr,
+    admin_addr: std::net::SocketAddr,
+    rate_limit: ObservedRateLimit<R>,
+    admin: AdminImpl<ObservedRateLimit<R>, P, O>,
+    top_keys: Option<TopKeys>,
+    decisions: DecisionFeed,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    R: RateLimitStore + Send + Sync + Clone + 'static,
+    P: PolicyStore + Send + Sync + Clone + 'static,
+    O: OverrideStore + Send + Sync + Clone + 'static,
+{
+    let health = HealthCheckImpl::new(rate_limit.clone());
+    let admin = admin.with_decisions(decisions.clone());
+    let rate_limit = rate_limit.with_decisions(decisions);
+    let (rate_limit, admin) = match &top_keys {
+        Some(top_keys) => {
+            top_keys.spawn_flush();
+            (
+                rate_limit.with_top_keys(top_keys.clone()),
+                admin.with_top_keys(top_keys.clone()),
+            )
+        }
+        None => (rate_limit, admin),
+    };
+
+    let router = tonic::transport::Server::builder()
         .add_service(HealthServer::new(health))
-        .serve_with_shutdown(addr, shutdown_signal())
-        .await?;
+        .add_service(RateLimiterServer::new(RateLimiterImpl::new(rate_limit)));
+
+    // The admin service has no authentication, so it is kept off the address
+    // clients use.
+    let admin_router = tonic::transport::Server::builder().add_service(AdminServer::new(admin));
+
+    println!("Server listening on {}", addr);
+    println!("Admin service listening on {}", admin_addr);
+
+    tokio::try_join!(
+        router.serve_with_shutdown(addr, shutdown_signal()),
+        admin_router.serve_with_shutdown(admin_addr, shutdown_signal()),
+    )?;
+
+    // Traffic counted since the last flush would be lost otherwise.
+    if let Some(top_keys) = top_keys
+        && let Err(e) = top_keys.flush().await
+    {
+        warn!("Failed to flush top keys: {}", e);
+    }
 
     println!("Server shutdown gracefully");
 
@@ -0,0 +1,427 @@
+use std::{borrow::Cow, cmp::Reverse, collections::HashMap, sync::Arc};
+
+use crate::{
+    config::{DefaultPolicy, PolicyDefinition, PolicyRule, RuleAction},
+    policy::{BucketTemplate, Pattern, PatternErr, PrefixTrie},
+};
+
+#[derive(Debug, Clone)]
+pub struct CompiledRule {
+    name: Arc<str>,
+    pattern: Pattern,
+    bucket: Option<BucketTemplate>,
+    rule: PolicyRule,
+}
+
+impl CompiledRule {
+    pub fn compile(rule: &PolicyRule) -> Result<Self, PatternErr> {
+        let pattern = Pattern::compile(&rule.pattern, rule.pattern_type)?;
+        let bucket = rule
+            .bucket
+            .as_deref()
+            .map(|template| BucketTemplate::parse(template, &pattern))
+            .transpose()?;
+
+        Ok(CompiledRule {
+            name: Arc::from(rule.name.as_str()),
+            pattern,
+            bucket,
+            rule: rule.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ResolvedPolicy<'a> {
+    pub name: &'a Arc<str>,
+    pub policy: &'a PolicyDefinition,
+    pub action: RuleAction,
+
+    /// Key the window counters are stored under. This is the resource key
+    /// itself unless the matched rule defines a bucket.
+    pub counter_key: Cow<'a, str>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Explanation<'a> {
+    /// Every rule matching the key, best first.
+    pub candidates: Vec<&'a PolicyRule>,
+
+    /// The winning rule, or `None` when the default policy applies.
+    pub selected: Option<&'a PolicyRule>,
+
+    /// Why the selected rule won over the other candidates.
+    pub reason: String,
+
+    pub resolved: ResolvedPolicy<'a>,
+}
+
+/// Rules compiled for lookup: exact rules in a hash map, prefix rules in a trie
+/// and glob/regex rules in a list that is scanned in full. See [`rank`] for how
+/// a rule is chosen among several matching ones.
+#[derive(Debug, Clone)]
+pub struct PolicyMatcher {
+    default_name: Arc<str>,
+    default_policy: DefaultPolicy,
+    rules: Vec<CompiledRule>,
+    exact: HashMap<String, usize>,
+    prefixes: PrefixTrie,
+    patterns: Vec<usize>,
+}
+
+impl PolicyMatcher {
+    pub fn new(default_policy: &DefaultPolicy, rules: &[PolicyRule]) -> Result<Self, PatternErr> {
+        let rules: Vec<CompiledRule> = rules
+            .iter()
+            .map(CompiledRule::compile)
+            .collect::<Result<_, _>>()?;
+
+        let better = |a: usize, b: usize| rank(&rules, a) > rank(&rules, b);
+
+        let mut exact = HashMap::new();
+        let mut prefixes = Vec::new();
+        let mut patterns = Vec::new();
+
+        for (index, rule) in rules.iter().enumerate() {
+            match &rule.pattern {
+                Pattern::Exact(pattern) => {
+                    exact
+                        .entry(pattern.clone())
+                        .and_modify(|best| {
+                            if better(index, *best) {
+                                *best = index;
+                            }
+                        })
+                        .or_insert(index);
+                }
+                Pattern::Prefix(pattern) => prefixes.push((pattern.as_str(), index)),
+                Pattern::Regex(_) => patterns.push(index),
+            }
+        }
+
+        let prefixes = PrefixTrie::new(prefixes, better);
+
+        Ok(PolicyMatcher {
+            default_name: Arc::from(default_policy.name.as_str()),
+            default_policy: default_policy.clone(),
+            rules,
+            exact,
+            prefixes,
+            patterns,
+        })
+    }
+
+    /// Returns the best policy matching the key, or the default policy.
+    pub fn resolve<'a>(&'a self, key: &'a str) -> ResolvedPolicy<'a> {
+        let rule = self
+            .exact
+            .get(key)
+            .copied()
+            .into_iter()
+            .chain(self.prefixes.get(key))
+            .chain(
+                self.patterns
+                    .iter()
+                    .copied()
+                    .filter(|index| self.rules[*index].pattern.matches(key)),
+            )
+            .max_by_key(|index| rank(&self.rules, *index))
+            .map(|index| &self.rules[index]);
+
+        let Some(rule) = rule else {
+            return ResolvedPolicy {
+                name: &self.default_name,
+                policy: &self.default_policy.policy,
+                action: RuleAction::Limit,
+                counter_key: Cow::Borrowed(key),
+            };
+        };
+
+        let counter_key = match &rule.bucket {
+            Some(bucket) => Cow::Owned(bucket.render(rule.pattern.captures(key).as_ref())),
+            None => Cow::Borrowed(key),
+        };
+
+        ResolvedPolicy {
+            name: &rule.name,
+            policy: &rule.rule.policy,
+            action: rule.rule.action,
+            counter_key,
+        }
+    }
+
+    pub fn default_policy(&self) -> &DefaultPolicy {
+        &self.default_policy
+    }
+
+    /// Returns the rules in the order they were defined.
+    pub fn rules(&self) -> impl Iterator<Item = &PolicyRule> {
+        self.rules.iter().map(|rule| &rule.rule)
+    }
+
+    /// Lists every rule matching the key and explains which one is selected.
+    /// This scans all rules and is meant for diagnostics, not the request path.
+    pub fn explain<'a>(&'a self, key: &'a str) -> Explanation<'a> {
+        let mut candidates: Vec<usize> = (0..self.rules.len())
+            .filter(|index| self.rules[*index].pattern.matches(key))
+            .collect();
+        candidates.sort_by_key(|index| Reverse(rank(&self.rules, *index)));
+
+        let reason = match candidates.as_slice() {
+            [] => "no rule matched, the default policy applies".to_string(),
+            [_] => "only matching rule".to_string(),
+            [winner, runner_up, ..] => {
+                let (priority, kind, length, _) = rank(&self.rules, *winner);
+                let (other_priority, other_kind, other_length, _) = rank(&self.rules, *runner_up);
+
+                if priority != other_priority {
+                    format!(
+                        "highest priority ({}) among {} matching rules",
+                        priority,
+                        candidates.len()
+                    )
+                } else if kind != other_kind {
+                    format!(
+                        "{} rule wins over {} rule at equal priority ({})",
+                        self.rules[*winner].rule.pattern_type,
+                        self.rules[*runner_up].rule.pattern_type,
+                        priority
+                    )
+                } else if length != other_length {
+                    format!("longest prefix at equal priority ({})", priority)
+                } else {
+                    format!(
+                        "defined first among {} rules at equal priority ({})",
+                        self.rules[*winner].rule.pattern_type, priority
+                    )
+                }
+            }
+        };
+
+        Explanation {
+            candidates: candidates
+                .iter()
+                .map(|index| &self.rules[*index].rule)
+                .collect(),
+            selected: candidates.first().map(|index| &self.rules[*index].rule),
+            reason,
+            resolved: self.resolve(key),
+        }
+    }
+}
+
+/// Ordering of rules matching the same key, greatest wins: higher priority,
+/// then exact before prefix before glob and regex, then the longer prefix,
+/// then the rule that comes first in the file.
+fn rank(rules: &[CompiledRule], index: usize) -> (u32, u8, usize, Reverse<usize>) {
+    let rule = &rules[index];
+    let (kind, length) = match &rule.pattern {
+        Pattern::Exact(_) => (2, 0),
+        Pattern::Prefix(prefix) => (1, prefix.len()),
+        Pattern::Regex(_) => (0, 0),
+    };
+
+    (rule.rule.priority, kind, length, Reverse(index))
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::config::PatternType;
+
+    fn rule(
+        pattern: &str,
+        pattern_type: PatternType,
+        max_tokens: u32,
+        priority: u32,
+    ) -> PolicyRule {
+        PolicyRule {
+            name: format!("rule-{}", max_tokens),
+            pattern: pattern.to_string(),
+            pattern_type,
+            policy: PolicyDefinition {
+                max_tokens,
+                window_secs: 60,
+                ..Default::default()
+            },
+            priority,
+            bucket: None,
+            action: RuleAction::Limit,
+        }
+    }
+
+    fn default_policy() -> DefaultPolicy {
+        DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 0,
+                window_secs: 60,
+                ..Default::default()
+            },
+        }
+    }
+
+    fn matcher() -> PolicyMatcher {
+        let rules = vec![
+            rule("tenant:42:api:export", PatternType::Exact, 1, 100),
+            rule("tenant:*:api:export", PatternType::Glob, 2, 90),
+            rule("tenant:", PatternType::Prefix, 3, 50),
+            rule(r"tenant:\d+:api:.+", PatternType::Regex, 4, 60),
+            rule("tenant:*:admin", PatternType::Glob, 5, 10),
+        ];
+
+        PolicyMatcher::new(&default_policy(), &rules).unwrap()
+    }
+
+    #[rstest]
+    #[case("tenant:42:api:export", 1)] // exact outranks glob
+    #[case("tenant:7:api:export", 2)] // glob outranks regex and prefix
+    #[case("tenant:7:api:import", 4)] // regex outranks prefix
+    #[case("tenant:x:api:import", 3)] // regex needs digits, prefix applies
+    #[case("tenant:7:admin", 3)] // low priority glob loses to prefix
+    #[case("user.login", 0)] // default policy
+    fn test_priority_across_pattern_types(#[case] key: &str, #[case] expected: u32) {
+        assert_eq!(matcher().resolve(key).policy.max_tokens, expected);
+    }
+
+    #[rstest]
+    #[case("user.login", "rule-1")] // exact before prefix
+    #[case("user.logout", "rule-3")] // longer prefix before shorter
+    #[case("user.register", "rule-2")] // prefix before glob
+    #[case("users", "rule-4")] // first in file among globs and regexes
+    #[case("other", "default")]
+    fn test_tie_breaking(#[case] key: &str, #[case] expected: &str) {
+        let rules = vec![
+            rule("user.login", PatternType::Exact, 1, 10),
+            rule("user.", PatternType::Prefix, 2, 10),
+            rule("user.log", PatternType::Prefix, 3, 10),
+            rule("user*", PatternType::Glob, 4, 10),
+            rule("users", PatternType::Regex, 5, 10),
+            rule("user?", PatternType::Glob, 6, 10),
+        ];
+
+        let matcher = PolicyMatcher::new(&default_policy(), &rules).unwrap();
+        assert_eq!(matcher.resolve(key).name.as_ref(), expected);
+    }
+
+    #[rstest]
+    #[case("user.login", &["rule-1", "rule-3", "rule-2", "rule-4"], "exact rule wins over prefix rule at equal priority (10)")]
+    #[case("user.logout", &["rule-3", "rule-2", "rule-4"], "longest prefix at equal priority (10)")]
+    #[case("users", &["rule-4", "rule-5"], "defined first among glob rules at equal priority (10)")]
+    #[case("userx", &["rule-4"], "only matching rule")]
+    #[case("user", &["rule-4", "rule-6"], "highest priority (10) among 2 matching rules")]
+    #[case("other", &[], "no rule matched, the default policy applies")]
+    fn test_explain(#[case] key: &str, #[case] candidates: &[&str], #[case] reason: &str) {
+        let rules = vec![
+            rule("user.login", PatternType::Exact, 1, 10),
+            rule("user.", PatternType::Prefix, 2, 10),
+            rule("user.log", PatternType::Prefix, 3, 10),
+            rule("user*", PatternType::Glob, 4, 10),
+            rule("users", PatternType::Glob, 5, 10),
+            rule("user", PatternType::Exact, 6, 1),
+        ];
+        let matcher = PolicyMatcher::new(&default_policy(), &rules).unwrap();
+        let explanation = matcher.explain(key);
+
+        let names: Vec<_> = explanation
+            .candidates
+            .iter()
+            .map(|rule| rule.name.as_str())
+            .collect();
+        assert_eq!(names, candidates);
+        assert_eq!(
+            explanation.selected.map(|rule| rule.name.as_str()),
+            candidates.first().copied()
+        );
+        assert_eq!(explanation.reason, reason);
+        assert_eq!(
+            explanation.resolved.name.as_ref(),
+            candidates.first().copied().unwrap_or("default")
+        );
+    }
+
+    #[rstest]
+    #[case("tenant:42:api:export", "tenant:42")]
+    #[case("tenant:42:api:import", "tenant:42")]
+    #[case("tenant:7:login", "tenant:7")]
+    #[case("user.login", "user.login")]
+    fn test_counter_key(#[case] key: &str, #[case] expected: &str) {
+        let mut tenant = rule("tenant:{tenant}:*", PatternType::Glob, 1, 10);
+        tenant.bucket = Some("tenant:{tenant}".to_string());
+
+        let matcher = PolicyMatcher::new(&default_policy(), &[tenant]).unwrap();
+
+        assert_eq!(matcher.resolve(key).counter_key, expected);
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let rules = vec![rule("tenant:(", PatternType::Regex, 1, 0)];
+
+        assert!(PolicyMatcher::new(&default_policy(), &rules).is_err());
+    }
+
+    /// Straightforward scan over all rules, kept as the reference semantics.
+    fn resolve_linear(rules: &[PolicyRule], key: &str) -> Option<u32> {
+        let specificity = |rule: &PolicyRule| match rule.pattern_type {
+            PatternType::Exact => (2, 0),
+            PatternType::Prefix => (1, rule.pattern.len()),
+            _ => (0, 0),
+        };
+
+        rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| {
+                Pattern::compile(&rule.pattern, rule.pattern_type)
+                    .unwrap()
+                    .matches(key)
+            })
+            .max_by(|(a_index, a), (b_index, b)| {
+                a.priority
+                    .cmp(&b.priority)
+                    .then(specificity(a).cmp(&specificity(b)))
+                    .then(b_index.cmp(a_index))
+            })
+            .map(|(_, rule)| rule.policy.max_tokens)
+    }
+
+    fn rule_strategy() -> impl Strategy<Value = (String, PatternType, u32)> {
+        (
+            "[ab.]{0,4}",
+            prop_oneof![
+                Just(PatternType::Exact),
+                Just(PatternType::Prefix),
+                Just(PatternType::Glob),
+            ],
+            0u32..4,
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_matches_linear_scan(
+            specs in prop::collection::vec(rule_strategy(), 0..12),
+            keys in prop::collection::vec("[ab.]{0,6}", 1..16),
+        ) {
+            // max_tokens doubles as the rule id, so each rule is distinguishable.
+            let rules: Vec<_> = specs
+                .iter()
+                .enumerate()
+                .map(|(index, (pattern, pattern_type, priority))| {
+                    rule(pattern, *pattern_type, index as u32 + 1, *priority)
+                })
+                .collect();
+
+            let matcher = PolicyMatcher::new(&default_policy(), &rules).unwrap();
+
+            for key in keys {
+                let expected = resolve_linear(&rules, &key).unwrap_or(0);
+                prop_assert_eq!(matcher.resolve(&key).policy.max_tokens, expected, "key {:?}", key);
+            }
+        }
+    }
+}
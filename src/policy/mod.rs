@@ -0,0 +1,9 @@
+mod matcher;
+mod pattern;
+mod registry;
+mod trie;
+
+pub use matcher::*;
+pub use pattern::*;
+pub use registry::*;
+pub use trie::*;
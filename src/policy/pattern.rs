@@ -0,0 +1,285 @@
+use regex::{Captures, Regex};
+use thiserror::Error;
+
+use crate::config::PatternType;
+
+#[derive(Debug, Error)]
+pub enum PatternErr {
+    #[error("{0}")]
+    Regex(#[from] regex::Error),
+
+    #[error(
+        "invalid capture {0:?}: expected `{{name}}` with a name of letters, digits and underscores"
+    )]
+    InvalidCapture(String),
+
+    #[error("bucket references capture {0:?} which the pattern does not define")]
+    UnknownCapture(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// Compiles a rule pattern. Glob and regex patterns must match the whole key.
+    pub fn compile(pattern: &str, pattern_type: PatternType) -> Result<Self, PatternErr> {
+        match pattern_type {
+            PatternType::Exact => Ok(Pattern::Exact(pattern.to_string())),
+            PatternType::Prefix => Ok(Pattern::Prefix(pattern.to_string())),
+            PatternType::Glob => Ok(Pattern::Regex(Regex::new(&glob_to_regex(pattern)?)?)),
+            PatternType::Regex => Ok(Pattern::Regex(Regex::new(&format!("^(?:{})$", pattern))?)),
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            Pattern::Exact(pattern) => pattern == key,
+            Pattern::Prefix(pattern) => key.starts_with(pattern.as_str()),
+            Pattern::Regex(regex) => regex.is_match(key),
+        }
+    }
+
+    pub fn captures<'k>(&self, key: &'k str) -> Option<Captures<'k>> {
+        match self {
+            Pattern::Regex(regex) => regex.captures(key),
+            _ => None,
+        }
+    }
+
+    pub fn capture_names(&self) -> Vec<&str> {
+        match self {
+            Pattern::Regex(regex) => regex.capture_names().flatten().collect(),
+            _ => vec![],
+        }
+    }
+}
+
+fn is_capture_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Splits `text` into literal runs and `{name}` captures.
+fn parse_captures(text: &str) -> Result<Vec<Segment>, PatternErr> {
+    let mut segments = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err(PatternErr::InvalidCapture(rest[start..].to_string()));
+        }
+
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| PatternErr::InvalidCapture(rest[start..].to_string()))?;
+
+        let name = &rest[start + 1..end];
+        if !is_capture_name(name) {
+            return Err(PatternErr::InvalidCapture(rest[start..=end].to_string()));
+        }
+
+        if start > 0 {
+            segments.push(Segment::Literal(rest[..start].to_string()));
+        }
+        segments.push(Segment::Capture(name.to_string()));
+        rest = &rest[end + 1..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Literal(rest.to_string()));
+    }
+
+    Ok(segments)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Capture(String),
+}
+
+/// Translates a glob into an anchored regex: `*` matches any run of characters,
+/// `?` matches exactly one and `{name}` captures a non-empty run up to the next
+/// literal character of the glob.
+fn glob_to_regex(glob: &str) -> Result<String, PatternErr> {
+    let segments = parse_captures(glob)?;
+
+    let mut regex = String::with_capacity(glob.len() + 8);
+    regex.push('^');
+
+    for (index, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Capture(name) => {
+                let stop = match segments.get(index + 1) {
+                    Some(Segment::Literal(next)) => {
+                        next.chars().next().filter(|c| *c != '*' && *c != '?')
+                    }
+                    _ => None,
+                };
+
+                match stop {
+                    Some(stop) => regex.push_str(&format!(
+                        "(?P<{}>[^{}]+)",
+                        name,
+                        regex::escape(stop.encode_utf8(&mut [0; 4]))
+                    )),
+                    None => regex.push_str(&format!("(?P<{}>.+?)", name)),
+                }
+            }
+            Segment::Literal(literal) => {
+                for c in literal.chars() {
+                    match c {
+                        '*' => regex.push_str(".*"),
+                        '?' => regex.push('.'),
+                        c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+                    }
+                }
+            }
+        }
+    }
+
+    regex.push('$');
+    Ok(regex)
+}
+
+/// Counter key template such as `tenant:{tenant}`, filled from pattern captures.
+#[derive(Debug, Clone)]
+pub struct BucketTemplate {
+    segments: Vec<Segment>,
+}
+
+impl BucketTemplate {
+    pub fn parse(template: &str, pattern: &Pattern) -> Result<Self, PatternErr> {
+        let segments = parse_captures(template)?;
+        let names = pattern.capture_names();
+
+        for segment in &segments {
+            if let Segment::Capture(name) = segment
+                && !names.contains(&name.as_str())
+            {
+                return Err(PatternErr::UnknownCapture(name.clone()));
+            }
+        }
+
+        Ok(BucketTemplate { segments })
+    }
+
+    pub fn render(&self, captures: Option<&Captures>) -> String {
+        let mut result = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => result.push_str(literal),
+                Segment::Capture(name) => {
+                    if let Some(value) = captures.and_then(|c| c.name(name)) {
+                        result.push_str(value.as_str());
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("tenant:*:api:export", "tenant:123:api:export", true)]
+    #[case("tenant:*:api:export", "tenant::api:export", true)]
+    #[case("tenant:*:api:export", "tenant:123:api:import", false)]
+    #[case("tenant:*:api:export", "xtenant:123:api:export", false)]
+    #[case("tenant:*:api:export", "tenant:123:api:export:all", false)]
+    #[case("user.?", "user.1", true)]
+    #[case("user.?", "user.12", false)]
+    #[case("api.*", "api.public.list", true)]
+    #[case("a+b(c)", "a+b(c)", true)]
+    #[case("tenant:{tenant}:*", "tenant:42:api:export", true)]
+    #[case("tenant:{tenant}:*", "tenant::api:export", false)]
+    #[case("tenant:{tenant}:export", "tenant:1:2:export", false)]
+    #[case("{tenant}", "acme", true)]
+    fn test_glob(#[case] glob: &str, #[case] key: &str, #[case] expected: bool) {
+        let pattern = Pattern::compile(glob, PatternType::Glob).unwrap();
+        assert_eq!(pattern.matches(key), expected);
+    }
+
+    #[rstest]
+    #[case(r"tenant:\d+:api:.+", "tenant:123:api:export", true)]
+    #[case(r"tenant:\d+:api:.+", "tenant:abc:api:export", false)]
+    #[case(r"tenant:\d+", "tenant:123:api:export", false)]
+    #[case(r"login|register", "register", true)]
+    #[case(r"login|register", "user.login", false)]
+    fn test_regex(#[case] regex: &str, #[case] key: &str, #[case] expected: bool) {
+        let pattern = Pattern::compile(regex, PatternType::Regex).unwrap();
+        assert_eq!(pattern.matches(key), expected);
+    }
+
+    #[rstest]
+    #[case("tenant:(", PatternType::Regex)]
+    #[case("tenant:{tenant", PatternType::Glob)]
+    #[case("tenant:tenant}", PatternType::Glob)]
+    #[case("tenant:{}", PatternType::Glob)]
+    #[case("tenant:{a-b}", PatternType::Glob)]
+    #[case("{tenant}:{tenant}", PatternType::Glob)]
+    fn test_invalid_pattern(#[case] pattern: &str, #[case] pattern_type: PatternType) {
+        assert!(Pattern::compile(pattern, pattern_type).is_err());
+    }
+
+    #[rstest]
+    #[case(
+        "tenant:{tenant}:*",
+        PatternType::Glob,
+        "tenant:{tenant}",
+        "tenant:42:api:export",
+        "tenant:42"
+    )]
+    #[case(
+        "{region}.{tenant}.*",
+        PatternType::Glob,
+        "{tenant}@{region}",
+        "eu.acme.login",
+        "acme@eu"
+    )]
+    #[case(
+        r"tenant:(?P<tenant>\d+):.*",
+        PatternType::Regex,
+        "tenant:{tenant}",
+        "tenant:7:x",
+        "tenant:7"
+    )]
+    #[case(
+        "api.public.",
+        PatternType::Prefix,
+        "api.public",
+        "api.public.list",
+        "api.public"
+    )]
+    fn test_bucket(
+        #[case] pattern: &str,
+        #[case] pattern_type: PatternType,
+        #[case] template: &str,
+        #[case] key: &str,
+        #[case] expected: &str,
+    ) {
+        let pattern = Pattern::compile(pattern, pattern_type).unwrap();
+        let bucket = BucketTemplate::parse(template, &pattern).unwrap();
+
+        assert_eq!(bucket.render(pattern.captures(key).as_ref()), expected);
+    }
+
+    #[test]
+    fn test_bucket_unknown_capture() {
+        let pattern = Pattern::compile("tenant:{tenant}:*", PatternType::Glob).unwrap();
+        let result = BucketTemplate::parse("user:{user}", &pattern);
+
+        assert!(matches!(result, Err(PatternErr::UnknownCapture(name)) if name == "user"));
+    }
+}
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use log::error;
+
+use crate::{
+    config::{DefaultPolicy, PolicyRule},
+    policy::{PatternErr, PolicyMatcher},
+};
+
+/// A rule managed at runtime, with the version it was last written at.
+#[derive(Debug, Clone)]
+pub struct StoredPolicy {
+    pub rule: PolicyRule,
+    pub version: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    matcher: Arc<PolicyMatcher>,
+    version: u64,
+    dynamic: HashMap<String, u64>,
+}
+
+/// Effective policies: rules from the config file merged with rules managed at
+/// runtime. A runtime rule replaces the file rule of the same name; other
+/// runtime rules are added after the file rules, ordered by name.
+#[derive(Debug)]
+pub struct Policies {
+    default_policy: DefaultPolicy,
+    file_rules: Vec<PolicyRule>,
+    state: RwLock<State>,
+}
+
+impl Policies {
+    pub fn new(
+        default_policy: DefaultPolicy,
+        file_rules: Vec<PolicyRule>,
+    ) -> Result<Self, PatternErr> {
+        let matcher = PolicyMatcher::new(&default_policy, &file_rules)?;
+
+        Ok(Policies {
+            default_policy,
+            file_rules,
+            state: RwLock::new(State {
+                matcher: Arc::new(matcher),
+                version: 0,
+                dynamic: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Returns the matcher for the current set of rules.
+    pub fn matcher(&self) -> Arc<PolicyMatcher> {
+        self.state.read().unwrap().matcher.clone()
+    }
+
+    /// Version of the runtime rules last applied.
+    pub fn version(&self) -> u64 {
+        self.state.read().unwrap().version
+    }
+
+    /// Version of the runtime rule with this name, or `None` for file rules.
+    pub fn dynamic_version(&self, name: &str) -> Option<u64> {
+        self.state.read().unwrap().dynamic.get(name).copied()
+    }
+
+    /// Replaces the runtime rules. Rules that fail to compile are logged and
+    /// skipped so one bad rule cannot stop the others from applying.
+    pub fn apply(&self, version: u64, mut stored: Vec<StoredPolicy>) {
+        stored.retain(|policy| match policy.rule.validate() {
+            Ok(()) => true,
+            Err(problems) => {
+                error!(
+                    "Skipping policy '{}' version {}: {}",
+                    policy.rule.name,
+                    policy.version,
+                    problems.join("; ")
+                );
+                false
+            }
+        });
+        stored.sort_by(|a, b| a.rule.name.cmp(&b.rule.name));
+
+        let mut by_name: HashMap<&str, &PolicyRule> = stored
+            .iter()
+            .map(|policy| (policy.rule.name.as_str(), &policy.rule))
+            .collect();
+
+        let mut rules: Vec<PolicyRule> = self
+            .file_rules
+            .iter()
+            .map(|rule| by_name.remove(rule.name.as_str()).unwrap_or(rule).clone())
+            .collect();
+
+        rules.extend(
+            stored
+                .iter()
+                .filter(|policy| by_name.contains_key(policy.rule.name.as_str()))
+                .map(|policy| policy.rule.clone()),
+        );
+
+        let matcher = match PolicyMatcher::new(&self.default_policy, &rules) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                error!("Failed to apply policies version {}: {}", version, e);
+                return;
+            }
+        };
+
+        let mut state = self.state.write().unwrap();
+        state.matcher = Arc::new(matcher);
+        state.version = version;
+        state.dynamic = stored
+            .into_iter()
+            .map(|policy| (policy.rule.name, policy.version))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PatternType, PolicyDefinition, RuleAction};
+
+    fn rule(name: &str, pattern: &str, max_tokens: u32) -> PolicyRule {
+        PolicyRule {
+            name: name.to_string(),
+            pattern: pattern.to_string(),
+            pattern_type: PatternType::Prefix,
+            bucket: None,
+            policy: PolicyDefinition {
+                max_tokens,
+                window_secs: 60,
+                ..Default::default()
+            },
+            priority: 0,
+            action: RuleAction::Limit,
+        }
+    }
+
+    fn policies() -> Policies {
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 1,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+
+        Policies::new(
+            default_policy,
+            vec![rule("users", "user.", 100), rule("api", "api.", 1000)],
+        )
+        .unwrap()
+    }
+
+    fn names(policies: &Policies) -> Vec<String> {
+        policies
+            .matcher()
+            .rules()
+            .map(|rule| rule.name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_runtime_rule_replaces_file_rule() {
+        let policies = policies();
+        policies.apply(
+            3,
+            vec![
+                StoredPolicy {
+                    rule: rule("users", "user.", 5),
+                    version: 2,
+                },
+                StoredPolicy {
+                    rule: rule("admins", "admin.", 10),
+                    version: 1,
+                },
+            ],
+        );
+
+        assert_eq!(names(&policies), vec!["users", "api", "admins"]);
+        assert_eq!(
+            policies.matcher().resolve("user.login").policy.max_tokens,
+            5
+        );
+        assert_eq!(policies.matcher().resolve("admin.x").policy.max_tokens, 10);
+        assert_eq!(policies.version(), 3);
+        assert_eq!(policies.dynamic_version("users"), Some(2));
+        assert_eq!(policies.dynamic_version("api"), None);
+    }
+
+    #[test]
+    fn test_removing_runtime_rule_restores_file_rule() {
+        let policies = policies();
+        policies.apply(
+            1,
+            vec![StoredPolicy {
+                rule: rule("users", "user.", 5),
+                version: 1,
+            }],
+        );
+        policies.apply(2, vec![]);
+
+        assert_eq!(names(&policies), vec!["users", "api"]);
+        assert_eq!(
+            policies.matcher().resolve("user.login").policy.max_tokens,
+            100
+        );
+    }
+
+    #[test]
+    fn test_invalid_runtime_rule_is_skipped() {
+        let policies = policies();
+        policies.apply(
+            1,
+            vec![
+                StoredPolicy {
+                    rule: rule("broken", "x.", 0),
+                    version: 1,
+                },
+                StoredPolicy {
+                    rule: rule("admins", "admin.", 10),
+                    version: 1,
+                },
+            ],
+        );
+
+        assert_eq!(names(&policies), vec!["users", "api", "admins"]);
+        assert_eq!(policies.dynamic_version("broken"), None);
+    }
+}
@@ -0,0 +1,133 @@
+/// Byte-wise prefix trie. Each node keeps the best value among all prefixes
+/// ending at or above it, so a lookup is a single walk down the key.
+#[derive(Debug, Clone)]
+pub struct PrefixTrie {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: Vec<(u8, usize)>,
+    value: Option<usize>,
+}
+
+impl PrefixTrie {
+    /// Builds the trie from `(prefix, value)` pairs. `better(a, b)` returns true
+    /// when value `a` should win over value `b` for keys matching both.
+    pub fn new<'a>(
+        entries: impl IntoIterator<Item = (&'a str, usize)>,
+        better: impl Fn(usize, usize) -> bool,
+    ) -> Self {
+        let mut trie = PrefixTrie {
+            nodes: vec![Node::default()],
+        };
+
+        for (prefix, value) in entries {
+            let node = trie.insert_path(prefix.as_bytes());
+            trie.nodes[node].value = pick(trie.nodes[node].value, Some(value), &better);
+        }
+
+        trie.propagate(0, None, &better);
+        trie
+    }
+
+    fn insert_path(&mut self, path: &[u8]) -> usize {
+        let mut node = 0;
+
+        for byte in path {
+            node = match self.child(node, *byte) {
+                Some(child) => child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node::default());
+
+                    let children = &mut self.nodes[node].children;
+                    let position = children.partition_point(|(b, _)| b < byte);
+                    children.insert(position, (*byte, child));
+                    child
+                }
+            };
+        }
+
+        node
+    }
+
+    fn propagate(
+        &mut self,
+        node: usize,
+        inherited: Option<usize>,
+        better: &impl Fn(usize, usize) -> bool,
+    ) {
+        let value = pick(inherited, self.nodes[node].value, better);
+        self.nodes[node].value = value;
+
+        for index in 0..self.nodes[node].children.len() {
+            let child = self.nodes[node].children[index].1;
+            self.propagate(child, value, better);
+        }
+    }
+
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        let children = &self.nodes[node].children;
+        children
+            .binary_search_by_key(&byte, |(b, _)| *b)
+            .ok()
+            .map(|position| children[position].1)
+    }
+
+    /// Returns the best value among all prefixes of `key`.
+    pub fn get(&self, key: &str) -> Option<usize> {
+        let mut node = 0;
+
+        for byte in key.as_bytes() {
+            match self.child(node, *byte) {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+
+        self.nodes[node].value
+    }
+}
+
+fn pick(
+    a: Option<usize>,
+    b: Option<usize>,
+    better: &impl Fn(usize, usize) -> bool,
+) -> Option<usize> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if better(b, a) { b } else { a }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("user.login", Some(2))]
+    #[case("user.", Some(1))]
+    #[case("user", Some(0))]
+    #[case("us", Some(0))]
+    #[case("api.public.list", Some(3))]
+    #[case("api.private", Some(0))]
+    fn test_get(#[case] key: &str, #[case] expected: Option<usize>) {
+        let entries = [("", 0), ("user.", 1), ("user.login", 2), ("api.public.", 3)];
+        let trie = PrefixTrie::new(entries, |a, b| a > b);
+
+        assert_eq!(trie.get(key), expected);
+    }
+
+    #[test]
+    fn test_ancestor_can_win() {
+        // A shorter prefix keeps winning below it when it is better.
+        let trie = PrefixTrie::new([("a", 5), ("ab", 1)], |a, b| a > b);
+
+        assert_eq!(trie.get("abc"), Some(5));
+        assert_eq!(trie.get("b"), None);
+    }
+}
@@ -1,10 +1,26 @@
 use crate::common::to_unix_millis;
-use crate::db::{AcquireErr, RateLimitConfig, RateLimitStore, TokensRemaining};
+use crate::db::{AcquireErr, DecisionSource, RateLimitConfig, RateLimitStore, TokensRemaining};
+use crate::proto::acquire_response::Source;
 use crate::proto::rate_limiter_server::RateLimiter;
 use crate::proto::{AcquireRequest, AcquireResponse};
 use log::error;
 use tonic::{Request, Response, Status};
 
+impl From<DecisionSource> for Source {
+    fn from(source: DecisionSource) -> Self {
+        match source {
+            DecisionSource::Policy => Source::Policy,
+            DecisionSource::Override => Source::Override,
+            DecisionSource::AllowList => Source::AllowList,
+            DecisionSource::DenyList => Source::DenyList,
+            DecisionSource::Penalty => Source::Penalty,
+            DecisionSource::FailOpen => Source::FailOpen,
+            DecisionSource::FailClosed => Source::FailClosed,
+            DecisionSource::LocalFallback => Source::LocalFallback,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct RateLimiterImpl<R: RateLimitStore> {
     rate_limit: R,
@@ -41,16 +57,30 @@ impl<R: RateLimitStore + 'static + Send + Sync + Clone> RateLimiter for RateLimi
             Ok(TokensRemaining {
                 remaining,
                 reset_after,
+                policy,
+                decided_by,
+                ..
             }) => Ok(Response::new(AcquireResponse {
                 remaining: remaining as i32,
                 reset_after: to_unix_millis(reset_after) as i64,
                 allowed: true,
+                policy: policy.to_string(),
+                source: Source::from(decided_by).into(),
+                degraded: decided_by.is_degraded(),
             })),
             Err(e) => match e {
-                AcquireErr::RateLimitExceeded(reset_after) => Ok(Response::new(AcquireResponse {
+                AcquireErr::RateLimitExceeded {
+                    reset_after,
+                    policy,
+                    decided_by,
+                    ..
+                } => Ok(Response::new(AcquireResponse {
                     remaining: 0,
                     reset_after: to_unix_millis(reset_after) as i64,
                     allowed: false,
+                    policy: policy.to_string(),
+                    source: Source::from(decided_by).into(),
+                    degraded: decided_by.is_degraded(),
                 })),
                 AcquireErr::Timeout => {
                     error!("Rate limit acquisition timed out");
@@ -63,6 +93,10 @@ impl<R: RateLimitStore + 'static + Send + Sync + Clone> RateLimiter for RateLimi
                     error!("Redis error: {:?}", e);
                     Err(Status::unavailable("Failed to acquire rate limit"))
                 }
+                AcquireErr::ZeroWindow => {
+                    error!("Rate limit acquisition for a policy without a window");
+                    Err(Status::internal("Failed to acquire rate limit"))
+                }
             },
         }
     }
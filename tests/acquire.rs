@@ -1,8 +1,15 @@
 use break_check::proto::AcquireRequest;
+use break_check::proto::acquire_response::Source;
 use break_check::proto::rate_limiter_client::RateLimiterClient;
 use break_check::proto::rate_limiter_server::RateLimiterServer;
 use break_check::{
-    common::SlidingWindow, config::PolicyDefinition, db::RedisRateLimit,
+    common::{SlidingWindow, VirtualClock},
+    config::{
+        DefaultPolicy, PatternType, PenaltyConfig, PolicyDefinition, PolicyRule, RuleAction,
+        TimeSource,
+    },
+    db::{RateLimitConfig, RateLimitStore, RedisRateLimit},
+    policy::Policies,
     rate_limiter::RateLimiterImpl,
 };
 use redis::AsyncConnectionConfig;
@@ -27,17 +34,42 @@ async fn setup_test_server() -> (String, tokio::task::JoinHandle<()>) {
         .expect("Failed to get Redis connection");
 
     // Setup test policies
-    let default_policy = PolicyDefinition {
-        max_tokens: 10,
-        window_secs: 60,
+    let default_policy = DefaultPolicy {
+        name: "default".to_string(),
+        policy: PolicyDefinition {
+            max_tokens: 10,
+            window_secs: 60,
+            ..Default::default()
+        },
     };
 
-    let policies = vec![];
+    // Two denials within a minute ban a "penalty:" key for 10 minutes
+    let penalty_rule = PolicyRule {
+        name: "penalty".to_string(),
+        pattern: "penalty:".to_string(),
+        pattern_type: PatternType::Prefix,
+        bucket: None,
+        policy: PolicyDefinition {
+            max_tokens: 1,
+            window_secs: 60,
+            penalty: Some(PenaltyConfig {
+                denials: 2,
+                within_secs: 60,
+                ban_secs: 600,
+                multiplier: 2,
+                max_ban_secs: 3600,
+            }),
+            ..Default::default()
+        },
+        priority: 0,
+        action: RuleAction::Limit,
+    };
+
+    let policies = Policies::new(default_policy, vec![penalty_rule]).unwrap();
 
     let rate_limit = RedisRateLimit::new(
         conn,
         Duration::from_millis(200),
-        Arc::new(default_policy),
         Arc::new(policies),
         SlidingWindow::new(),
     );
@@ -105,8 +137,9 @@ mod tests {
         let response = client.acquire(request).await.unwrap();
         let response = response.into_inner();
 
-        assert_eq!(response.allowed, true);
+        assert!(response.allowed);
         assert_eq!(response.remaining, 9); // 10 max tokens - 1 acquired
+        assert_eq!(response.policy, "default");
         assert!(response.reset_after > now as i64);
         assert!(response.reset_after <= (now + 60000) as i64); // within window
 
@@ -129,7 +162,7 @@ mod tests {
         let response = client.acquire(request).await.unwrap();
         let response = response.into_inner();
 
-        assert_eq!(response.allowed, true);
+        assert!(response.allowed);
         assert_eq!(response.remaining, 5); // 10 max tokens - 5 acquired
         assert!(response.reset_after > now as i64);
         assert!(response.reset_after <= (now + 60000) as i64); // within window
@@ -151,7 +184,7 @@ mod tests {
             tokens: 10,
         };
         let response = client.acquire(request).await.unwrap();
-        assert_eq!(response.into_inner().allowed, true);
+        assert!(response.into_inner().allowed);
 
         // Second request: try to acquire 1 more token - should fail
         let request = AcquireRequest {
@@ -161,8 +194,9 @@ mod tests {
         let response = client.acquire(request).await.unwrap();
         let response = response.into_inner();
 
-        assert_eq!(response.allowed, false);
+        assert!(!response.allowed);
         assert_eq!(response.remaining, 0);
+        assert_eq!(response.policy, "default");
         assert!(response.reset_after > now as i64);
         assert!(response.reset_after <= (now + 60000) as i64); // within window
 
@@ -186,7 +220,7 @@ mod tests {
             let response = client.acquire(request).await.unwrap();
             let response = response.into_inner();
 
-            assert_eq!(response.allowed, true);
+            assert!(response.allowed);
             assert_eq!(response.remaining, 9 - i); // Should decrease by 1 each time
             assert!(response.reset_after > now as i64);
             assert!(response.reset_after <= (now + 60000) as i64); // within window
@@ -273,7 +307,7 @@ mod tests {
             tokens: 10,
         };
         let response = client.acquire(request).await.unwrap();
-        assert_eq!(response.into_inner().allowed, true);
+        assert!(response.into_inner().allowed);
 
         // key2 should still have full capacity
         let request = AcquireRequest {
@@ -283,7 +317,7 @@ mod tests {
         let response = client.acquire(request).await.unwrap();
         let response = response.into_inner();
 
-        assert_eq!(response.allowed, true);
+        assert!(response.allowed);
         assert_eq!(response.remaining, 5);
 
         cleanup_redis_key(&key1).await;
@@ -326,4 +360,113 @@ mod tests {
 
         cleanup_redis_key(&key).await;
     }
+
+    #[tokio::test]
+    async fn test_penalty_ban() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_client(server_url).await;
+        let now = unix_now_millis();
+
+        let key = format!("penalty:{}", uuid::Uuid::new_v4());
+        let acquire = |client: &mut RateLimiterClient<Channel>| {
+            let request = AcquireRequest {
+                key: key.clone(),
+                tokens: 1,
+            };
+            let mut client = client.clone();
+            async move { client.acquire(request).await.unwrap().into_inner() }
+        };
+
+        assert!(acquire(&mut client).await.allowed);
+
+        // The first denial only counts against the window
+        let response = acquire(&mut client).await;
+        assert!(!response.allowed);
+        assert_eq!(response.source(), Source::Policy);
+        assert!(response.reset_after <= (now + 60_000) as i64);
+
+        // The second denial bans the key for ten minutes
+        let response = acquire(&mut client).await;
+        assert!(!response.allowed);
+        assert_eq!(response.source(), Source::Penalty);
+        assert!(response.reset_after >= (now + 600_000) as i64);
+
+        let banned = acquire(&mut client).await;
+        assert!(!banned.allowed);
+        assert_eq!(banned.source(), Source::Penalty);
+        assert_eq!(banned.reset_after, response.reset_after);
+    }
+
+    #[tokio::test]
+    async fn test_redis_time_source() {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let conn = client.get_multiplexed_async_connection().await.unwrap();
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 3,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let policies = Policies::new(default_policy, vec![]).unwrap();
+        let mut rate_limit = RedisRateLimit::new(
+            conn,
+            Duration::from_millis(200),
+            Arc::new(policies),
+            SlidingWindow::new(),
+        )
+        .with_time_source(TimeSource::Redis);
+
+        let config = RateLimitConfig::new(format!("time:{}", uuid::Uuid::new_v4()), 1);
+        for remaining in (0..3).rev() {
+            let result = rate_limit.acquire(&config).await.unwrap();
+            assert_eq!(result.remaining, remaining);
+        }
+
+        assert!(rate_limit.acquire(&config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_window_rollover_with_virtual_clock() {
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let conn = client.get_multiplexed_async_connection().await.unwrap();
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 4,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let policies = Policies::new(default_policy, vec![]).unwrap();
+
+        // Start right at a window boundary so that the weights are exact
+        let start = (unix_now_millis() / 60_000 + 1) * 60_000;
+        let clock = VirtualClock::new(std::time::UNIX_EPOCH + Duration::from_millis(start as u64));
+        let mut rate_limit = RedisRateLimit::new(
+            conn,
+            Duration::from_millis(200),
+            Arc::new(policies),
+            SlidingWindow::new(),
+        )
+        .with_clock(clock.clone());
+
+        let config = RateLimitConfig::new(format!("rollover:{}", uuid::Uuid::new_v4()), 1);
+        for _ in 0..4 {
+            rate_limit.acquire(&config).await.unwrap();
+        }
+        assert!(rate_limit.acquire(&config).await.is_err());
+
+        // Three quarters into the next window a quarter of the previous one
+        // still counts, including the denied request: round(5 * 0.25) = 1
+        clock.advance(Duration::from_secs(105));
+        let result = rate_limit.acquire(&config).await.unwrap();
+        assert_eq!(result.remaining, 2);
+
+        // Two windows later nothing counts any more
+        clock.advance(Duration::from_secs(120));
+        let result = rate_limit.acquire(&config).await.unwrap();
+        assert_eq!(result.remaining, 3);
+    }
 }
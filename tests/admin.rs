@@ -0,0 +1,466 @@
+use break_check::proto::acquire_response::Source;
+use break_check::proto::admin_client::AdminClient;
+use break_check::proto::admin_server::AdminServer;
+use break_check::proto::rate_limiter_client::RateLimiterClient;
+use break_check::proto::rate_limiter_server::RateLimiterServer;
+use break_check::proto::{
+    AcquireRequest, CreatePolicyRequest, DeleteOverrideRequest, DeletePolicyRequest,
+    ExplainKeyRequest, GetOverrideRequest, KeyOverride, ListOverridesRequest, ListPoliciesRequest,
+    Policy, ResetKeyRequest, SetOverrideRequest, UpdatePolicyRequest,
+};
+use break_check::{
+    admin::AdminImpl,
+    common::SlidingWindow,
+    config::{DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction},
+    db::{RedisOverrideStore, RedisPolicyStore, RedisRateLimit},
+    policy::Policies,
+    rate_limiter::RateLimiterImpl,
+};
+use redis::AsyncConnectionConfig;
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tonic::transport::{Channel, Server};
+
+fn rule(name: &str, pattern: &str, pattern_type: PatternType, priority: u32) -> PolicyRule {
+    PolicyRule {
+        name: name.to_string(),
+        pattern: pattern.to_string(),
+        pattern_type,
+        bucket: None,
+        policy: PolicyDefinition {
+            max_tokens: 5,
+            window_secs: 60,
+            ..Default::default()
+        },
+        priority,
+        action: RuleAction::Limit,
+    }
+}
+
+/// Helper function to setup a test gRPC server with the rate limiter and admin services
+async fn setup_test_server() -> (String, tokio::task::JoinHandle<()>) {
+    let addr: std::net::SocketAddr = "[::1]:0".parse().unwrap();
+
+    let redis_config = AsyncConnectionConfig::default();
+    let client = redis::Client::open("redis://127.0.0.1/")
+        .expect("Failed to connect to Redis. Ensure Redis is running on localhost:6379");
+
+    let conn = client
+        .get_multiplexed_async_connection_with_config(&redis_config)
+        .await
+        .expect("Failed to get Redis connection");
+
+    let default_policy = DefaultPolicy {
+        name: "default".to_string(),
+        policy: PolicyDefinition {
+            max_tokens: 10,
+            window_secs: 60,
+            ..Default::default()
+        },
+    };
+
+    let rules = vec![
+        rule("admin-login", "admin:login:", PatternType::Prefix, 10),
+        rule("admin", "admin:", PatternType::Prefix, 10),
+        rule("admin-glob", "admin:*", PatternType::Glob, 1),
+        PolicyRule {
+            action: RuleAction::Allow,
+            policy: PolicyDefinition::default(),
+            ..rule("monitors", "monitor.", PatternType::Prefix, 0)
+        },
+    ];
+
+    let policies = Arc::new(Policies::new(default_policy, rules).unwrap());
+    let policy_store = RedisPolicyStore::new(conn.clone(), Duration::from_millis(200));
+    let override_store = RedisOverrideStore::new(conn.clone(), Duration::from_millis(200));
+
+    let rate_limit = RedisRateLimit::new(
+        conn,
+        Duration::from_millis(200),
+        policies.clone(),
+        SlidingWindow::new(),
+    );
+
+    let admin = AdminImpl::new(rate_limit.clone(), policies, policy_store, override_store);
+    let rate_limiter = RateLimiterImpl::new(rate_limit);
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        Server::builder()
+            .add_service(RateLimiterServer::new(rate_limiter))
+            .add_service(AdminServer::new(admin))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    (format!("http://{}", local_addr), server_handle)
+}
+
+async fn create_admin_client(server_url: String) -> AdminClient<Channel> {
+    AdminClient::connect(server_url)
+        .await
+        .expect("Failed to connect to gRPC server")
+}
+
+fn unix_millis_in(duration: Duration) -> i64 {
+    (std::time::SystemTime::now() + duration)
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+fn dynamic_policy(name: &str, max_tokens: i32) -> Policy {
+    Policy {
+        name: name.to_string(),
+        pattern: format!("{}:", name),
+        r#type: "prefix".to_string(),
+        max_tokens,
+        window_secs: 60,
+        priority: 5,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_list_policies() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let response = client
+            .list_policies(ListPoliciesRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+
+        let default_policy = response.default_policy.unwrap();
+        assert_eq!(default_policy.name, "default");
+        assert_eq!(default_policy.max_tokens, 10);
+
+        let names: Vec<_> = response.policies.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["admin-login", "admin", "admin-glob"]);
+        assert_eq!(response.policies[0].r#type, "prefix");
+        assert_eq!(response.policies[0].pattern, "admin:login:");
+    }
+
+    #[tokio::test]
+    async fn test_explain_key_with_counters() {
+        let (server_url, _handle) = setup_test_server().await;
+        let key = format!("admin:login:{}", uuid::Uuid::new_v4());
+
+        let mut rate_limiter = RateLimiterClient::connect(server_url.clone())
+            .await
+            .unwrap();
+        rate_limiter
+            .acquire(AcquireRequest {
+                key: key.clone(),
+                tokens: 3,
+            })
+            .await
+            .unwrap();
+
+        let mut client = create_admin_client(server_url).await;
+        let response = client
+            .explain_key(ExplainKeyRequest { key: key.clone() })
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.selected.unwrap().name, "admin-login");
+
+        let names: Vec<_> = response
+            .candidates
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["admin-login", "admin", "admin-glob"]);
+        assert_eq!(response.reason, "longest prefix at equal priority (10)");
+        assert_eq!(response.counter_key, key);
+
+        let counters = response.counters.unwrap();
+        assert_eq!(counters.current, 3);
+        assert_eq!(counters.previous, 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_key() {
+        let (server_url, _handle) = setup_test_server().await;
+        let key = format!("admin:login:{}", uuid::Uuid::new_v4());
+
+        let mut rate_limiter = RateLimiterClient::connect(server_url.clone())
+            .await
+            .unwrap();
+        rate_limiter
+            .acquire(AcquireRequest {
+                key: key.clone(),
+                tokens: 5,
+            })
+            .await
+            .unwrap();
+
+        let mut client = create_admin_client(server_url).await;
+        let response = client
+            .reset_key(ResetKeyRequest { key: key.clone() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.policy, "admin-login");
+        assert_eq!(response.counter_key, key);
+
+        let response = client
+            .explain_key(ExplainKeyRequest { key })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.counters.unwrap().current, 0);
+    }
+
+    #[tokio::test]
+    async fn test_explain_key_default_policy() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let key = format!("test:explain:{}", uuid::Uuid::new_v4());
+        let response = client
+            .explain_key(ExplainKeyRequest { key })
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.selected.unwrap().name, "default");
+        assert!(response.candidates.is_empty());
+        assert_eq!(response.counters.unwrap().current, 0);
+    }
+
+    #[tokio::test]
+    async fn test_explain_key_allow_rule() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let response = client
+            .explain_key(ExplainKeyRequest {
+                key: "monitor.x".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(response.selected.unwrap().name, "monitors");
+        assert_eq!(response.counters.unwrap().current, 0);
+    }
+
+    #[tokio::test]
+    async fn test_explain_empty_key() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let status = client
+            .explain_key(ExplainKeyRequest { key: String::new() })
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "Key must not be empty");
+    }
+
+    #[tokio::test]
+    async fn test_policy_lifecycle() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+        let name = format!("dynamic-{}", uuid::Uuid::new_v4());
+
+        let created = client
+            .create_policy(CreatePolicyRequest {
+                policy: Some(dynamic_policy(&name, 3)),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(created.version > 0);
+
+        let status = client
+            .create_policy(CreatePolicyRequest {
+                policy: Some(dynamic_policy(&name, 3)),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::AlreadyExists);
+
+        let response = client
+            .explain_key(ExplainKeyRequest {
+                key: format!("{}:key", name),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let selected = response.selected.unwrap();
+        assert_eq!(selected.name, name);
+        assert_eq!(selected.source, "redis");
+        assert_eq!(selected.version, created.version);
+
+        let updated = client
+            .update_policy(UpdatePolicyRequest {
+                policy: Some(dynamic_policy(&name, 7)),
+                expected_version: created.version,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(updated.version > created.version);
+
+        // A writer holding the old version loses.
+        let status = client
+            .update_policy(UpdatePolicyRequest {
+                policy: Some(dynamic_policy(&name, 9)),
+                expected_version: created.version,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Aborted);
+
+        client
+            .delete_policy(DeletePolicyRequest {
+                name: name.clone(),
+                expected_version: updated.version,
+            })
+            .await
+            .unwrap();
+
+        let response = client
+            .list_policies(ListPoliciesRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.policies.iter().all(|p| p.name != name));
+
+        let status = client
+            .delete_policy(DeletePolicyRequest {
+                name,
+                expected_version: updated.version,
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_create_invalid_policy() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let status = client
+            .create_policy(CreatePolicyRequest {
+                policy: Some(dynamic_policy("broken", 0)),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let status = client
+            .create_policy(CreatePolicyRequest {
+                policy: Some(dynamic_policy("default", 5)),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_key_override() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url.clone()).await;
+        let mut rate_limiter = RateLimiterClient::connect(server_url).await.unwrap();
+        let key = format!("admin:{}", uuid::Uuid::new_v4());
+
+        let key_override = KeyOverride {
+            key: key.clone(),
+            max_tokens: 50,
+            expires_at: unix_millis_in(Duration::from_secs(60)),
+        };
+        client
+            .set_override(SetOverrideRequest {
+                key_override: Some(key_override.clone()),
+            })
+            .await
+            .unwrap();
+
+        // The "admin" policy allows 5 tokens, the override 50.
+        let response = rate_limiter
+            .acquire(AcquireRequest {
+                key: key.clone(),
+                tokens: 20,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.allowed);
+        assert_eq!(response.policy, "admin");
+        assert_eq!(response.source(), Source::Override);
+
+        let fetched = client
+            .get_override(GetOverrideRequest { key: key.clone() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, key_override);
+
+        let listed = client
+            .list_overrides(ListOverridesRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(listed.overrides.contains(&key_override));
+
+        let explained = client
+            .explain_key(ExplainKeyRequest { key: key.clone() })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(explained.active_override, Some(key_override));
+
+        client
+            .delete_override(DeleteOverrideRequest { key: key.clone() })
+            .await
+            .unwrap();
+
+        let status = client
+            .get_override(GetOverrideRequest { key: key.clone() })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::NotFound);
+
+        let response = rate_limiter
+            .acquire(AcquireRequest { key, tokens: 1 })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.allowed);
+        assert_eq!(response.source(), Source::Policy);
+    }
+
+    #[tokio::test]
+    async fn test_set_expired_override() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = create_admin_client(server_url).await;
+
+        let status = client
+            .set_override(SetOverrideRequest {
+                key_override: Some(KeyOverride {
+                    key: "admin:expired".to_string(),
+                    max_tokens: 50,
+                    expires_at: unix_millis_in(Duration::ZERO) - 1000,
+                }),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    }
+}
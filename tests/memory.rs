@@ -0,0 +1,316 @@
+use break_check::proto::AcquireRequest;
+use break_check::proto::admin_client::AdminClient;
+use break_check::proto::admin_server::{Admin, AdminServer};
+use break_check::proto::rate_limiter_client::RateLimiterClient;
+use break_check::proto::rate_limiter_server::RateLimiterServer;
+use break_check::proto::{
+    CreatePolicyRequest, ExplainKeyRequest, Policy, ResetKeyRequest, TopKeysRequest,
+    WatchDecisionsRequest, watch_decisions_request::Outcome,
+};
+use break_check::{
+    admin::AdminImpl,
+    common::SlidingWindow,
+    config::{
+        AuditConfig, DefaultPolicy, PatternType, PolicyDefinition, PolicyRule, RuleAction,
+        TopKeysConfig,
+    },
+    db::{
+        AuditLog, DecisionFeed, MemoryPolicyStore, MemoryRateLimit, MemoryTopKeysHistory,
+        ObservedRateLimit, TopKeys,
+    },
+    policy::Policies,
+    rate_limiter::RateLimiterImpl,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, timeout};
+use tokio_stream::StreamExt;
+use tonic::transport::Server;
+
+/// Helper function to setup a test gRPC server with the in-memory backend
+async fn setup_test_server() -> (String, tokio::task::JoinHandle<()>) {
+    let addr: std::net::SocketAddr = "[::1]:0".parse().unwrap();
+
+    let default_policy = DefaultPolicy {
+        name: "default".to_string(),
+        policy: PolicyDefinition {
+            max_tokens: 10,
+            window_secs: 60,
+            ..Default::default()
+        },
+    };
+
+    let monitors = PolicyRule {
+        name: "monitors".to_string(),
+        pattern: "monitor.".to_string(),
+        pattern_type: PatternType::Prefix,
+        bucket: None,
+        policy: PolicyDefinition::default(),
+        priority: 0,
+        action: RuleAction::Allow,
+    };
+
+    let policies = Arc::new(Policies::new(default_policy, vec![monitors]).unwrap());
+    let rate_limit = MemoryRateLimit::new(policies.clone(), SlidingWindow::new());
+
+    let top_keys_config = TopKeysConfig {
+        flush_secs: 1,
+        ..Default::default()
+    };
+    let top_keys = TopKeys::new(
+        MemoryTopKeysHistory::new(top_keys_config.capacity, top_keys_config.retention_secs),
+        top_keys_config,
+    );
+    top_keys.spawn_flush();
+
+    let admin = AdminImpl::new(
+        rate_limit.clone(),
+        policies.clone(),
+        MemoryPolicyStore::default(),
+        rate_limit.override_store(),
+    )
+    .with_top_keys(top_keys.clone());
+
+    let decisions = DecisionFeed::local();
+    let admin = admin.with_decisions(decisions.clone());
+    let rate_limiter = RateLimiterImpl::new(
+        ObservedRateLimit::new(rate_limit, policies)
+            .with_top_keys(top_keys)
+            .with_decisions(decisions),
+    );
+
+    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+    let local_addr = listener.local_addr().unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        Server::builder()
+            .add_service(RateLimiterServer::new(rate_limiter))
+            .add_service(AdminServer::new(admin))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener))
+            .await
+            .unwrap();
+    });
+
+    sleep(Duration::from_millis(100)).await;
+
+    (format!("http://{}", local_addr), server_handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rate_limit_exceeded() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut client = RateLimiterClient::connect(server_url).await.unwrap();
+
+        let response = client
+            .acquire(AcquireRequest {
+                key: "test:memory".to_string(),
+                tokens: 10,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(response.allowed);
+        assert_eq!(response.remaining, 0);
+
+        let response = client
+            .acquire(AcquireRequest {
+                key: "test:memory".to_string(),
+                tokens: 1,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.allowed);
+        assert_eq!(response.policy, "default");
+    }
+
+    #[tokio::test]
+    async fn test_runtime_policy() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut admin = AdminClient::connect(server_url.clone()).await.unwrap();
+        let mut client = RateLimiterClient::connect(server_url).await.unwrap();
+
+        admin
+            .create_policy(CreatePolicyRequest {
+                policy: Some(Policy {
+                    name: "reports".to_string(),
+                    pattern: "reports.".to_string(),
+                    r#type: "prefix".to_string(),
+                    max_tokens: 2,
+                    window_secs: 60,
+                    ..Default::default()
+                }),
+            })
+            .await
+            .unwrap();
+
+        let response = client
+            .acquire(AcquireRequest {
+                key: "reports.daily".to_string(),
+                tokens: 3,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(!response.allowed);
+        assert_eq!(response.policy, "reports");
+
+        let explained = admin
+            .explain_key(ExplainKeyRequest {
+                key: "reports.daily".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(explained.counters.unwrap().current, 3);
+    }
+
+    #[tokio::test]
+    async fn test_explain_allow_rule() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut admin = AdminClient::connect(server_url).await.unwrap();
+
+        let explained = admin
+            .explain_key(ExplainKeyRequest {
+                key: "monitor.x".to_string(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(explained.selected.unwrap().name, "monitors");
+        assert_eq!(explained.counters.unwrap().current, 0);
+    }
+
+    #[tokio::test]
+    async fn test_top_keys() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut admin = AdminClient::connect(server_url.clone()).await.unwrap();
+        let mut client = RateLimiterClient::connect(server_url).await.unwrap();
+
+        for (key, tokens) in [("tenant:1", 4), ("tenant:1", 8), ("tenant:2", 1)] {
+            client
+                .acquire(AcquireRequest {
+                    key: key.to_string(),
+                    tokens,
+                })
+                .await
+                .unwrap();
+        }
+
+        // Counts reach the history with the next flush.
+        sleep(Duration::from_millis(1500)).await;
+
+        let response = admin
+            .top_keys(TopKeysRequest::default())
+            .await
+            .unwrap()
+            .into_inner();
+
+        let by_requests: Vec<_> = response
+            .by_requests
+            .iter()
+            .map(|key| (key.key.as_str(), key.requests, key.denials))
+            .collect();
+        assert_eq!(by_requests, vec![("tenant:1", 2, 1), ("tenant:2", 1, 0)]);
+        assert_eq!(response.by_denials.len(), 1);
+        assert_eq!(response.policies[0].policy, "default");
+        assert_eq!(response.policies[0].requests, 3);
+    }
+
+    #[tokio::test]
+    async fn test_watch_decisions() {
+        let (server_url, _handle) = setup_test_server().await;
+        let mut admin = AdminClient::connect(server_url.clone()).await.unwrap();
+        let mut client = RateLimiterClient::connect(server_url).await.unwrap();
+
+        let mut decisions = admin
+            .watch_decisions(WatchDecisionsRequest {
+                key_prefix: "watched:".to_string(),
+                outcome: Outcome::Denied.into(),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+
+        for (key, tokens) in [("watched:1", 10), ("other:1", 11), ("watched:1", 1)] {
+            client
+                .acquire(AcquireRequest {
+                    key: key.to_string(),
+                    tokens,
+                })
+                .await
+                .unwrap();
+        }
+
+        let decision = timeout(Duration::from_secs(1), decisions.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(decision.key, "watched:1");
+        assert!(!decision.allowed);
+        assert_eq!(decision.policy, "default");
+        assert_eq!(decision.missed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_audit_admin_changes() {
+        let path = std::env::temp_dir()
+            .join(format!("break-check-{}", uuid::Uuid::new_v4()))
+            .join("audit.log");
+        let audit = AuditLog::open(&AuditConfig {
+            path: path.clone(),
+            max_file_bytes: AuditConfig::DEFAULT_MAX_FILE_BYTES,
+            max_files: 1,
+            queue_size: 16,
+        })
+        .unwrap();
+
+        let default_policy = DefaultPolicy {
+            name: "default".to_string(),
+            policy: PolicyDefinition {
+                max_tokens: 10,
+                window_secs: 60,
+                ..Default::default()
+            },
+        };
+        let policies = Arc::new(Policies::new(default_policy, vec![]).unwrap());
+        let rate_limit = MemoryRateLimit::new(policies.clone(), SlidingWindow::new());
+        let admin = AdminImpl::new(
+            rate_limit.clone(),
+            policies,
+            MemoryPolicyStore::default(),
+            rate_limit.override_store(),
+        )
+        .with_audit(audit.clone());
+
+        admin
+            .reset_key(tonic::Request::new(ResetKeyRequest {
+                key: "user:1".to_string(),
+            }))
+            .await
+            .unwrap();
+        admin
+            .reset_key(tonic::Request::new(ResetKeyRequest { key: String::new() }))
+            .await
+            .unwrap_err();
+
+        audit.flush().await;
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["type"], "admin");
+        assert_eq!(records[0]["method"], "ResetKey");
+        assert_eq!(records[0]["request"]["key"], "user:1");
+        assert_eq!(records[0]["status"], "Ok");
+        assert_eq!(records[1]["status"], "InvalidArgument");
+    }
+}